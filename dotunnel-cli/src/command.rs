@@ -19,5 +19,5 @@ pub enum Command {
     Setup(setup::Args),
 
     /// Start a tunnel to expose a local server
-    Tunnel(tunnel::Args),
+    Tunnel(Box<tunnel::Args>),
 }
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
-use crate::config::{Config, Credentials};
+use crate::config::{Config, Credentials, TunnelStatus};
 
 #[derive(Debug, clap::Args)]
 pub struct Args {}
@@ -42,6 +42,8 @@ pub fn execute(_args: &Args, profile: &str) -> Result<()> {
     println!("Service: {}", profile_config.service_url);
     println!();
 
+    print_running_tunnels(profile);
+
     // Fetch user info from server
     let agent = crate::http_client::agent();
     let user_url = format!("{}/_api/user", profile_config.service_url);
@@ -79,3 +81,28 @@ pub fn execute(_args: &Args, profile: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Print tunnels currently running under this profile.
+fn print_running_tunnels(profile: &str) {
+    let tunnels: Vec<TunnelStatus> = TunnelStatus::list()
+        .into_iter()
+        .filter(|t| t.profile == profile)
+        .collect();
+    if tunnels.is_empty() {
+        return;
+    }
+
+    println!("Running tunnels:");
+    for tunnel in &tunnels {
+        let state = match (tunnel.connected, tunnel.rtt_ms) {
+            (true, Some(rtt)) => format!("connected, RTT {} ms", rtt),
+            (true, None) => "connected".to_string(),
+            (false, _) => "reconnecting".to_string(),
+        };
+        println!(
+            "  {} -> {} ({}, pid {})",
+            tunnel.tunnel_url, tunnel.local_addr, state, tunnel.pid
+        );
+    }
+    println!();
+}
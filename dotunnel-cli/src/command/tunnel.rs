@@ -1,40 +1,98 @@
 //! Tunnel command - establishes a tunnel to expose a local server.
 
-use anyhow::{bail, Context, Result};
-use bytes::Bytes;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
 use clap::Parser;
 use serde::Deserialize;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{HashMap, VecDeque};
 use std::io::Read;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
-use tungstenite::protocol::CloseFrame;
-use tungstenite::stream::MaybeTlsStream;
-use tungstenite::{connect, Message as WsMessage, WebSocket};
+use tungstenite::protocol::frame::coding::{Control as WsControl, Data as WsData, OpCode};
+use tungstenite::protocol::frame::{Frame, FrameHeader, FrameSocket};
+use tungstenite::{connect, Message as WsMessage};
 use url::Url;
 
-use crate::config::{Config, Credentials};
+use crate::config::{Config, Credentials, TunnelStatus};
+use chunking::ChunkSizer;
+use deflate::Inflater;
+use dotunnel::transport::Encoder;
 use dotunnel::transport::message::{
-    Control, Envelope, Header, HttpBodyChunk, HttpMessage, HttpResponseEnd, HttpResponseInit,
-    Payload, Pong, WebSocketFrame, WebSocketOpcode,
+    AbortReason, Ack, Control, Envelope, GoAway, GoAwayCode, Header, HttpBodyChunk,
+    HttpInterimResponse, HttpMessage, HttpResponseAbort, HttpResponseEnd, HttpResponseInit,
+    HttpTrailers, HttpVersion, Payload, Ping, Pong, WebSocketFrame, WebSocketOpcode,
 };
+use link::{HttpLink, RelayLink, Transport};
+use liveness::{Liveness, LivenessConfig, Probe};
+use pool::WorkerPool;
+use reconnect::{classify, ReconnectPolicy};
+use request_body::{BodyEvent, BodySender, RequestBody};
+use request_target::origin_form;
+use spool::ResponseSpool;
+use upgrade::{handle_upgrade, UpgradeRequest};
+use upstream::{
+    Closer, HeaderCase, InterimResponse, Target, TlsOptions, Upgrade, Upstream, UpstreamBody,
+    UpstreamProtocol, UpstreamResponse,
+};
+use writer::{PrioritizedMsg, PriorityWriter, Scheduler, WriteError};
+
+mod chunking;
+mod deflate;
+mod link;
+mod liveness;
+mod pool;
+mod reconnect;
+mod request_body;
+mod request_target;
+mod spool;
+mod upgrade;
+mod upstream;
+mod writer;
+
+pub use reconnect::FatalError;
 
 /// Expose a local server through a tunnel
 #[derive(Debug, Parser)]
 pub struct Args {
     /// Local port to forward to
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, required_unless_present = "upstream")]
+    port: Option<u16>,
 
     /// Local host (default: 127.0.0.1)
     #[arg(long, default_value = "127.0.0.1")]
     host: String,
 
+    /// Local server URL, e.g. `https://localhost:5173`, or `unix:` and a
+    /// socket path, e.g. `unix:/run/app.sock`, instead of a port
+    #[arg(long, value_name = "URL", conflicts_with_all = ["port", "host"])]
+    upstream: Option<String>,
+
+    /// Host header (HTTP/2 `:authority`) sent to the local server, instead
+    /// of the host and port it is reached at
+    #[arg(long, value_name = "HOST")]
+    upstream_host_header: Option<String>,
+
+    /// Server name to send in SNI and expect in an https local server's
+    /// certificate
+    #[arg(long, value_name = "NAME")]
+    upstream_sni: Option<String>,
+
+    /// PEM file of CA certificates to trust for an https local server, e.g.
+    /// mkcert's rootCA.pem
+    #[arg(long, value_name = "FILE")]
+    upstream_ca: Option<PathBuf>,
+
+    /// Accept any certificate from an https local server, e.g. a
+    /// self-signed one
+    #[arg(long)]
+    upstream_insecure: bool,
+
     /// Use named tunnel (subdomain)
     #[arg(short, long)]
     subdomain: Option<String>,
@@ -42,14 +100,92 @@ pub struct Args {
     /// Service URL override
     #[arg(long, env = "DOTUNNEL_SERVICE_URL")]
     service_url: Option<String>,
+
+    /// Share of bandwidth a WebSocket stream gets relative to an HTTP body
+    #[arg(long, value_name = "WEIGHT", default_value_t = writer::DEFAULT_WEBSOCKET_WEIGHT)]
+    websocket_weight: u32,
+
+    /// Streams forwarded to the local server at once; as many again may
+    /// queue, and any beyond that are refused with 503
+    #[arg(long, value_name = "N", default_value_t = pool::DEFAULT_MAX_CONCURRENT_STREAMS)]
+    max_concurrent_streams: usize,
+
+    /// WebSockets and other upgraded connections open at once, on top of
+    /// --max-concurrent-streams; any beyond that are refused with 503
+    #[arg(long, value_name = "N", default_value_t = pool::DEFAULT_MAX_WEBSOCKETS)]
+    max_websockets: usize,
+
+    /// Largest response body chunk, in bytes; chunks grow toward this while
+    /// a download keeps the tunnel busy
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = chunking::DEFAULT_MAX_CHUNK_SIZE,
+        value_parser = chunking::parse_max_chunk_size
+    )]
+    max_chunk_size: usize,
+
+    /// Relay connections to open; new streams are spread across them, so a
+    /// slow or lost connection only holds up its own share
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_CONNECTIONS)
+    )]
+    connections: u32,
+
+    /// Protocol to speak to the local server; `auto` uses HTTP/2 if the
+    /// server answers an HTTP/2 preface, or picks it in TLS ALPN
+    #[arg(long, value_enum, default_value_t = UpstreamProtocol::Http1)]
+    upstream_protocol: UpstreamProtocol,
+
+    /// How header names are written to an HTTP/1.1 local server
+    #[arg(long, value_enum, default_value_t = HeaderCase::Preserve)]
+    upstream_header_case: HeaderCase,
+
+    /// How to reach the relay; `auto` falls back to HTTP streaming when a
+    /// network blocks WebSocket upgrades
+    #[arg(long, value_enum, default_value_t = Transport::Auto)]
+    transport: Transport,
+
+    /// Seconds between the pings that check the relay connection is alive
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = liveness::DEFAULT_PING_INTERVAL_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    ping_interval: u64,
+
+    /// Pings that may go unanswered before the relay connection is dropped
+    /// and reconnected
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = liveness::DEFAULT_MAX_MISSED_PONGS,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_missed_pongs: u32,
+
+    /// Give up after this many consecutive failed connection attempts
+    #[arg(long, value_name = "N")]
+    max_reconnect_attempts: Option<u32>,
+
+    /// Give up after failing to connect for this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    max_reconnect_duration: Option<u64>,
 }
 
+/// Most relay connections a tunnel may have; the relay refuses more.
+const MAX_CONNECTIONS: i64 = 8;
+
 // =============================================================================
 // Protocol Types
 // =============================================================================
 
 /// Connect response from POST /_api/tunnel/connect
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct ConnectResponse {
     #[serde(rename = "tunnelId")]
     tunnel_id: String,
@@ -63,135 +199,270 @@ struct ConnectResponse {
 #[derive(Debug, Deserialize)]
 struct ErrorResponse {
     error: String,
-    #[allow(dead_code)]
     code: Option<String>,
 }
 
+/// Handshake the relay sends as the first (text) message on a connection
+#[derive(Debug, Deserialize)]
+struct TunnelReady {
+    #[serde(rename = "connectionId")]
+    connection_id: String,
+    /// Whether the relay picked up the session named in the resume headers
+    #[serde(default)]
+    resumed: bool,
+    /// Last envelope the relay received from us, when resumed
+    #[serde(rename = "lastMsgSeq", default)]
+    last_msg_seq: u32,
+}
+
 // =============================================================================
-// Reconnection Constants
+// Session Resumption Constants
 // =============================================================================
 
-const INITIAL_BACKOFF_MS: u64 = 1000;
-const MAX_BACKOFF_MS: u64 = 60000;
-const BACKOFF_MULTIPLIER: f64 = 2.0;
+/// How long a dropped session is offered for resumption. The relay holds its
+/// side for about as long before failing the streams.
+const RESUME_WINDOW: Duration = Duration::from_secs(15);
+
+/// Unacknowledged outbound bytes kept for replay. A session that outgrows it
+/// stops being resumable rather than buffering without limit.
+const MAX_REPLAY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Acknowledge after this many inbound envelopes...
+const ACK_EVERY: u32 = 32;
+
+/// ...or once the oldest unacknowledged one is this old.
+const ACK_INTERVAL: Duration = Duration::from_millis(200);
 
 // =============================================================================
-// Priority Write Channel
+// Stream State
 // =============================================================================
 
-/// Priority levels for outbound messages.
-/// Lower numeric value = higher priority = sent first.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum WritePriority {
-    /// Control messages (pong, goaway, close) — highest priority
-    Control = 0,
-    /// Response headers and end markers — must not be delayed by body chunks
-    Meta = 1,
-    /// Response body chunks — bulk data, lowest priority
-    Body = 2,
-}
-
-/// A message tagged with priority for the write channel.
-struct PrioritizedMsg {
-    priority: WritePriority,
-    /// Tie-breaker: lower seq = sent first among same priority
-    seq: u64,
-    msg: WsMessage,
+/// Active WebSocket connection to local server
+struct LocalWebSocket {
+    write_tx: mpsc::Sender<Frame>,
+    #[allow(dead_code)]
+    stream_id: u32,
 }
 
-impl PartialEq for PrioritizedMsg {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority && self.seq == other.seq
-    }
+/// Active stream state - can be HTTP request, WebSocket or another upgrade
+enum StreamType {
+    Http {
+        /// Feeds the request body to the worker forwarding it; None once
+        /// the body has ended, or the worker stopped reading it
+        body_tx: Option<BodySender>,
+    },
+    WebSocket {
+        local_ws: LocalWebSocket,
+    },
+    /// Switching (or switched) to a protocol the CLI doesn't understand
+    Upgraded {
+        /// The request body, which carries the client's bytes for the local
+        /// server; dropped to half-close. None for a bodiless request, whose
+        /// client never sends anything.
+        write_tx: Option<mpsc::SyncSender<Bytes>>,
+        /// The local connection once switched, to close on abort
+        socket: Option<Closer>,
+    },
 }
 
-impl Eq for PrioritizedMsg {}
-
-impl PartialOrd for PrioritizedMsg {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+/// Active stream state
+struct StreamState {
+    stream_type: StreamType,
 }
 
-impl Ord for PrioritizedMsg {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // BinaryHeap is a max-heap; we want min-priority first, so reverse.
-        (other.priority as u8, other.seq).cmp(&(self.priority as u8, self.seq))
-    }
-}
+// =============================================================================
+// Session
+// =============================================================================
 
-/// Sender handle for priority-tagged writes.
+/// Settings a session is created with.
 #[derive(Clone)]
-struct PriorityWriter {
-    tx: mpsc::Sender<PrioritizedMsg>,
-    seq: Arc<AtomicU64>,
+struct SessionConfig {
+    upstream: Upstream,
+    websocket_weight: u32,
+    max_chunk_size: usize,
+    liveness: LivenessConfig,
+    /// Shared by all sessions, so the limit holds while an old connection
+    /// drains next to its replacement
+    workers: WorkerPool,
+    /// Runs WebSockets and other upgraded connections, which hold a worker
+    /// for as long as they stay open
+    upgraded: WorkerPool,
 }
 
-use std::sync::atomic::AtomicU64;
+/// Tunnel state that outlives a single relay WebSocket.
+///
+/// Worker threads only hold the writer and the stream map, so they keep
+/// running while the connection underneath is replaced. Outbound envelopes
+/// stay in `replay` until the relay acknowledges them; after a reconnect
+/// whatever it missed is sent again, and it does the same for us.
+struct Session {
+    /// Assigned by the relay in `tunnel_ready`; 0 until the first handshake.
+    connection_id: u64,
+    /// The tunnel's public URL, the only origin an absolute-form request
+    /// may name
+    tunnel_url: Url,
+    upstream: Upstream,
+    max_chunk_size: usize,
+    /// Each connection carrying the session probes with this
+    liveness_config: LivenessConfig,
+    workers: WorkerPool,
+    upgraded: WorkerPool,
+    write_rx: mpsc::Receiver<PrioritizedMsg>,
+    /// Messages drained from the channel, waiting for their turn
+    scheduler: Scheduler,
+    writer: PriorityWriter,
+    /// Reused across envelopes
+    encoder: Encoder,
+    /// Stream state map: streamId -> StreamState
+    streams: Arc<Mutex<HashMap<u32, StreamState>>>,
+    /// Last outbound msg_seq assigned
+    last_sent_seq: u32,
+    /// Last inbound msg_seq received from the relay
+    last_received_seq: u32,
+    /// Inbound envelopes not yet acknowledged, and when the oldest arrived
+    unacked_inbound: u32,
+    unacked_since: Option<Instant>,
+    /// Sent but unacknowledged envelopes, oldest first
+    replay: VecDeque<(u32, Bytes)>,
+    replay_bytes: usize,
+    /// Cleared once `replay` overflows; such a session can only start over.
+    resumable: bool,
+    /// When the connection carrying this session was lost
+    lost_at: Instant,
+    /// Set while another link is resuming this session
+    hold_acks: bool,
+}
+
+impl Session {
+    fn new(config: &SessionConfig, tunnel_url: Url) -> Self {
+        // Priority write channel
+        let (write_tx, write_rx) = mpsc::channel::<PrioritizedMsg>();
 
-impl PriorityWriter {
-    fn new(tx: mpsc::Sender<PrioritizedMsg>) -> Self {
         Self {
-            tx,
-            seq: Arc::new(AtomicU64::new(0)),
+            connection_id: 0,
+            tunnel_url,
+            upstream: config.upstream.clone(),
+            max_chunk_size: config.max_chunk_size,
+            liveness_config: config.liveness,
+            workers: config.workers.clone(),
+            upgraded: config.upgraded.clone(),
+            write_rx,
+            scheduler: Scheduler::new(config.websocket_weight),
+            writer: PriorityWriter::new(write_tx),
+            encoder: Encoder::new(),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            last_sent_seq: 0,
+            last_received_seq: 0,
+            unacked_inbound: 0,
+            unacked_since: None,
+            replay: VecDeque::new(),
+            replay_bytes: 0,
+            resumable: true,
+            lost_at: Instant::now(),
+            hold_acks: false,
         }
     }
 
-    fn send(&self, priority: WritePriority, msg: WsMessage) -> Result<(), mpsc::SendError<PrioritizedMsg>> {
-        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
-        self.tx.send(PrioritizedMsg { priority, seq, msg })
-    }
+    /// Assign the wire sequence number and encode a queued message, keeping
+    /// a copy for replay unless it is a control message.
+    fn seal(&mut self, pm: PrioritizedMsg) -> Bytes {
+        let sequenced = !matches!(pm.payload, Payload::Control(_));
+        let msg_seq = if sequenced {
+            self.last_sent_seq += 1;
+            self.last_sent_seq
+        } else {
+            0
+        };
 
-    /// Send a control message (highest priority).
-    fn send_control(&self, msg: WsMessage) -> Result<(), mpsc::SendError<PrioritizedMsg>> {
-        self.send(WritePriority::Control, msg)
-    }
+        let envelope = Envelope {
+            timestamp_ms: now_ms(),
+            connection_id: self.connection_id,
+            stream_id: pm.stream_id,
+            msg_seq,
+            payload: pm.payload,
+        };
+        let data = self
+            .encoder
+            .encode(&envelope)
+            .expect("failed to encode envelope");
+
+        if sequenced && self.resumable {
+            self.replay_bytes += data.len();
+            self.replay.push_back((msg_seq, data.clone()));
+            if self.replay_bytes > MAX_REPLAY_BYTES {
+                warn!("Replay buffer exceeded, this session can no longer be resumed");
+                self.resumable = false;
+                self.replay.clear();
+                self.replay_bytes = 0;
+            }
+        }
 
-    /// Send a response header or end marker.
-    fn send_meta(&self, msg: WsMessage) -> Result<(), mpsc::SendError<PrioritizedMsg>> {
-        self.send(WritePriority::Meta, msg)
+        data
     }
 
-    /// Send a response body chunk (lowest priority).
-    fn send_body(&self, msg: WsMessage) -> Result<(), mpsc::SendError<PrioritizedMsg>> {
-        self.send(WritePriority::Body, msg)
+    /// Adopt a new relay-side connection ID, forgetting sequence state the
+    /// relay no longer has.
+    fn restart(&mut self, connection_id: u64) {
+        self.connection_id = connection_id;
+        self.last_received_seq = 0;
+        self.unacked_inbound = 0;
+        self.unacked_since = None;
+        self.replay.clear();
+        self.replay_bytes = 0;
+        self.resumable = true;
     }
-}
-
-// =============================================================================
-// Stream State
-// =============================================================================
 
-/// Pending HTTP request being assembled
-struct PendingRequest {
-    method: String,
-    uri: String,
-    headers: Vec<(String, String)>,
-    body_chunks: Vec<Bytes>,
-    #[allow(dead_code)]
-    has_body: bool,
-}
+    /// Record an inbound envelope. Returns false for a replayed duplicate.
+    fn accept_inbound(&mut self, msg_seq: u32) -> bool {
+        if msg_seq == 0 {
+            return true;
+        }
+        if msg_seq <= self.last_received_seq {
+            return false;
+        }
+        self.last_received_seq = msg_seq;
+        self.unacked_inbound += 1;
+        self.unacked_since.get_or_insert_with(Instant::now);
+        true
+    }
 
-/// Active WebSocket connection to local server
-struct LocalWebSocket {
-    write_tx: mpsc::Sender<WsMessage>,
-    #[allow(dead_code)]
-    stream_id: u32,
-}
+    /// Drop everything the relay has confirmed from the replay buffer.
+    fn acknowledge(&mut self, last_msg_seq: u32) {
+        while let Some((msg_seq, data)) = self.replay.front() {
+            if *msg_seq > last_msg_seq {
+                break;
+            }
+            self.replay_bytes -= data.len();
+            self.replay.pop_front();
+        }
+    }
 
-/// Active stream state - can be HTTP request or WebSocket
-enum StreamType {
-    Http {
-        pending_request: Option<PendingRequest>,
-    },
-    WebSocket {
-        local_ws: LocalWebSocket,
-    },
+    /// Queue an Ack if enough inbound envelopes have piled up.
+    fn maybe_ack(&mut self) {
+        if self.hold_acks {
+            return;
+        }
+        let due = self.unacked_inbound >= ACK_EVERY
+            || self
+                .unacked_since
+                .is_some_and(|since| since.elapsed() >= ACK_INTERVAL);
+        if !due {
+            return;
+        }
+        self.unacked_inbound = 0;
+        self.unacked_since = None;
+        let _ = self
+            .writer
+            .send_control(control_ack(self.last_received_seq));
+    }
 }
 
-/// Active stream state
-struct StreamState {
-    stream_type: StreamType,
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Wake producers blocked on the outbound budget; nothing will ever
+        // drain it now
+        self.writer.close();
+    }
 }
 
 // =============================================================================
@@ -216,96 +487,492 @@ pub fn execute(args: &Args, profile: &str) -> Result<()> {
         .context("Not logged in. Run 'dotunnel login' first.")?;
     let token = creds.token.clone();
 
-    // Resolve hostname to socket address
-    let local_addr: SocketAddr = format!("{}:{}", args.host, args.port)
-        .to_socket_addrs()
-        .context("Failed to resolve local address")?
-        .next()
-        .context("No addresses found for local host")?;
+    let url = match (&args.upstream, args.port) {
+        (Some(url), _) => url.clone(),
+        (None, Some(port)) if args.host.contains(':') => format!("http://[{}]:{}", args.host, port),
+        (None, Some(port)) => format!("http://{}:{}", args.host, port),
+        (None, None) => bail!("Either --port or --upstream is required"),
+    };
+    let tls = TlsOptions {
+        server_name: args.upstream_sni.clone(),
+        ca_file: args.upstream_ca.clone(),
+        insecure: args.upstream_insecure,
+    };
+    let upstream = Upstream::new(
+        Target::parse(&url, args.upstream_host_header.clone(), &tls)?,
+        args.upstream_protocol,
+        args.upstream_header_case,
+    );
+
+    // Ctrl+C is process-wide and can only be registered once, so it lives
+    // here rather than in each connection.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = shutdown.clone();
+        ctrlc::set_handler(move || {
+            info!("Shutting down tunnel...");
+            shutdown.store(true, Ordering::SeqCst);
+        })
+        .context("Failed to set Ctrl+C handler")?;
+    }
+
+    let tunnel = Tunnel {
+        service_url,
+        token,
+        subdomain: args.subdomain.clone(),
+        connections: args.connections,
+        transport: args.transport,
+        websocket_blocked_at: Mutex::new(None),
+        session_config: SessionConfig {
+            upstream: upstream.clone(),
+            websocket_weight: args.websocket_weight,
+            max_chunk_size: args.max_chunk_size,
+            liveness: LivenessConfig {
+                ping_interval: Duration::from_secs(args.ping_interval),
+                max_missed_pongs: args.max_missed_pongs,
+            },
+            workers: WorkerPool::new(args.max_concurrent_streams, args.max_concurrent_streams),
+            upgraded: WorkerPool::new(args.max_websockets, 0),
+        },
+        max_reconnect_attempts: args.max_reconnect_attempts,
+        max_reconnect_duration: args.max_reconnect_duration.map(Duration::from_secs),
+        tunnel_info: Mutex::new(None),
+        announced: AtomicBool::new(false),
+        status: StatusBoard::new(TunnelStatus {
+            pid: std::process::id(),
+            profile: profile.to_string(),
+            tunnel_url: String::new(),
+            local_addr: upstream.to_string(),
+            connected: false,
+            rtt_ms: None,
+            updated_at: 0,
+        }),
+        shutdown,
+    };
+
+    // Every lane is its own relay connection with its own session and
+    // reconnect loop; the relay spreads new streams across them.
+    let result = thread::scope(|scope| {
+        let lanes: Vec<_> = (0..tunnel.connections)
+            .map(|lane| {
+                let tunnel = &tunnel;
+                scope.spawn(move || {
+                    // A lane that dies takes the tunnel down with it rather
+                    // than leaving the others to wait on it forever
+                    panic::catch_unwind(AssertUnwindSafe(|| run_lane(lane, tunnel))).unwrap_or_else(
+                        |_| {
+                            tunnel.stop();
+                            Err(anyhow!("Tunnel lane {} panicked", lane))
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        let mut result = Ok(());
+        for handle in lanes {
+            let lane_result = handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Tunnel lane panicked")));
+            if result.is_ok() {
+                result = lane_result;
+            }
+        }
+        result
+    });
+
+    tunnel.status.remove();
+    result
+}
+
+/// Everything the lanes of one tunnel share.
+struct Tunnel {
+    service_url: String,
+    token: String,
+    subdomain: Option<String>,
+    connections: u32,
+    transport: Transport,
+    /// When auto mode last found WebSocket upgrades blocked
+    websocket_blocked_at: Mutex<Option<Instant>>,
+    session_config: SessionConfig,
+    max_reconnect_attempts: Option<u32>,
+    max_reconnect_duration: Option<Duration>,
+    /// The tunnel is selected once and reused by every lane and reconnect,
+    /// so an ephemeral tunnel keeps its public URL across connections.
+    tunnel_info: Mutex<Option<ConnectResponse>>,
+    /// Set once the public URL has been printed
+    announced: AtomicBool,
+    status: StatusBoard,
+    /// Set by Ctrl+C, and by a lane that ends the whole tunnel
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Tunnel {
+    /// The selected tunnel. The first lane to ask selects it while the
+    /// others wait.
+    fn tunnel_info(&self) -> Result<ConnectResponse> {
+        let mut info = self.tunnel_info.lock().unwrap();
+        if info.is_none() {
+            *info = Some(select_tunnel(
+                &self.service_url,
+                &self.token,
+                &self.subdomain,
+            )?);
+        }
+        Ok(info.clone().expect("tunnel selected above"))
+    }
+
+    /// Stop every lane.
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether auto mode should go straight to HTTP streaming: upgrades
+    /// were found blocked less than [`WEBSOCKET_RETRY_INTERVAL`] ago.
+    fn websocket_blocked(&self) -> bool {
+        self.websocket_blocked_at
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < WEBSOCKET_RETRY_INTERVAL)
+    }
+
+    /// Record whether a WebSocket upgrade got through.
+    fn record_websocket(&self, blocked: bool) {
+        let mut blocked_at = self.websocket_blocked_at.lock().unwrap();
+        match (blocked_at.is_some(), blocked) {
+            (false, true) => info!("WebSocket upgrades appear blocked, using HTTP streaming"),
+            (true, false) => info!("WebSocket upgrades work again"),
+            _ => {}
+        }
+        *blocked_at = blocked.then(Instant::now);
+    }
+}
 
-    // Run with reconnection
-    let mut backoff_ms = INITIAL_BACKOFF_MS;
-    let mut first_connect = true;
+/// Keep one lane connected until Ctrl+C or an error that ends the tunnel.
+fn run_lane(lane: u32, tunnel: &Tunnel) -> Result<()> {
+    let mut state = LaneState::default();
+    let mut policy =
+        ReconnectPolicy::new(tunnel.max_reconnect_attempts, tunnel.max_reconnect_duration);
 
     loop {
-        if !first_connect {
-            info!("Reconnecting in {} ms...", backoff_ms);
-            thread::sleep(Duration::from_millis(backoff_ms));
+        if tunnel.shutdown.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        if state
+            .session
+            .as_ref()
+            .is_some_and(|s| s.lost_at.elapsed() > RESUME_WINDOW)
+        {
+            info!("Session expired, starting over");
+            state.session = None;
         }
-        first_connect = false;
 
-        match connect_and_run(&service_url, &token, &args.subdomain, local_addr) {
-            Ok(()) => {
-                // Graceful shutdown
+        state.connected_at = None;
+        let result = connect_and_run(lane, tunnel, &mut state);
+        policy.connection_ended(state.connected_at);
+
+        // Tell `dotunnel status` we're between connections
+        tunnel.status.disconnected(lane);
+
+        match result {
+            Ok(ConnectionExit::Shutdown) => {
                 info!("Tunnel closed gracefully");
-                break;
+                return Ok(());
+            }
+            Ok(ConnectionExit::GoAway) => {
+                // The old connection drains in the background; replace it
+                // right away so new visitors never see the tunnel offline.
+                info!("Opening replacement connection");
+                policy.reset();
+                continue;
+            }
+            // Taking the lane back would only evict the other process in
+            // turn
+            Ok(ConnectionExit::Replaced) => {
+                tunnel.stop();
+                return Err(FatalError::Replaced.into());
+            }
+            // Only this lane is gone; the others keep serving while it
+            // reconnects
+            Ok(ConnectionExit::Closed) => {
+                warn!("Relay closed lane {}", lane);
             }
             Err(e) => {
-                error!("Tunnel error: {}", e);
-                // Increase backoff
-                backoff_ms = ((backoff_ms as f64) * BACKOFF_MULTIPLIER) as u64;
-                if backoff_ms > MAX_BACKOFF_MS {
-                    backoff_ms = MAX_BACKOFF_MS;
+                if tunnel.shutdown.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                if e.is::<FatalError>() {
+                    tunnel.stop();
+                    return Err(e);
                 }
+                error!("Tunnel error: {:#}", e);
             }
         }
+
+        let delay = match policy.next_delay() {
+            Ok(delay) => delay,
+            Err(fatal) => {
+                tunnel.stop();
+                return Err(fatal.into());
+            }
+        };
+        info!("Reconnecting in {} ms...", delay.as_millis());
+        sleep_unless_shutdown(delay, &tunnel.shutdown, &tunnel.status);
     }
+}
 
-    Ok(())
+/// State a lane carries across reconnects.
+#[derive(Default)]
+struct LaneState {
+    /// A session left behind by a lost connection, offered for resumption
+    /// until RESUME_WINDOW has passed.
+    session: Option<Session>,
+    /// When the current connection completed its handshake
+    connected_at: Option<Instant>,
 }
 
-fn connect_and_run(
-    service_url: &str,
-    token: &str,
-    subdomain: &Option<String>,
-    local_addr: SocketAddr,
-) -> Result<()> {
-    info!("Connecting to {}...", service_url);
+/// How often the status file is rewritten while nothing else changes it,
+/// well inside the TTL after which `dotunnel status` drops it.
+const STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Merges the lanes' connection state into the tunnel's status file.
+struct StatusBoard {
+    inner: Mutex<StatusInner>,
+}
+
+struct StatusInner {
+    status: TunnelStatus,
+    /// Connected lanes and their smoothed RTT in milliseconds
+    lanes: HashMap<u32, Option<u64>>,
+    /// When the status file was last written
+    saved_at: Option<Instant>,
+}
+
+impl StatusBoard {
+    fn new(status: TunnelStatus) -> Self {
+        Self {
+            inner: Mutex::new(StatusInner {
+                status,
+                lanes: HashMap::new(),
+                saved_at: None,
+            }),
+        }
+    }
+
+    fn connected(&self, lane: u32, tunnel_url: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.status.tunnel_url = tunnel_url.to_string();
+        inner.lanes.insert(lane, None);
+        inner.save();
+    }
+
+    fn disconnected(&self, lane: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.lanes.remove(&lane).is_some() {
+            inner.save();
+        }
+    }
+
+    /// Record a lane's RTT. Returns true for the lane's first sample.
+    fn record_rtt(&self, lane: u32, rtt_ms: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.lanes.get_mut(&lane) else {
+            return false;
+        };
+        let first = entry.replace(rtt_ms).is_none();
+        inner.save();
+        first
+    }
+
+    /// Rewrite the status file if it is getting old, so `dotunnel status`
+    /// still lists a tunnel that is between connections for a while.
+    fn refresh(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .saved_at
+            .is_none_or(|at| at.elapsed() >= STATUS_REFRESH_INTERVAL)
+        {
+            inner.save();
+        }
+    }
+
+    fn remove(&self) {
+        self.inner.lock().unwrap().status.remove();
+    }
+}
+
+impl StatusInner {
+    /// Write the status file; the reported RTT is the best lane's.
+    fn save(&mut self) {
+        self.status.connected = !self.lanes.is_empty();
+        self.status.rtt_ms = self.lanes.values().flatten().min().copied();
+        if let Err(e) = self.status.save() {
+            debug!("Failed to write tunnel status: {:#}", e);
+        }
+        self.saved_at = Some(Instant::now());
+    }
+}
+
+/// Sleep for `delay`, waking early if Ctrl+C is pressed. Keeps the status
+/// file fresh meanwhile, however long the outage.
+fn sleep_unless_shutdown(delay: Duration, shutdown: &AtomicBool, status: &StatusBoard) {
+    let deadline = Instant::now() + delay;
+    while !shutdown.load(Ordering::SeqCst) {
+        status.refresh();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+}
+
+fn connect_and_run(lane: u32, tunnel: &Tunnel, state: &mut LaneState) -> Result<ConnectionExit> {
+    let service_url = &tunnel.service_url;
+    let session_config = &tunnel.session_config;
+    info!("Connecting lane {} to {}...", lane, service_url);
 
     // Step 1: POST to get/create tunnel
-    let agent = crate::http_client::agent();
-    let connect_url = format!("{}/_api/tunnel/connect", service_url);
+    let info = tunnel.tunnel_info()?;
 
-    let body = if let Some(subdomain) = subdomain {
-        serde_json::json!({ "subdomain": subdomain })
-    } else {
-        serde_json::json!({})
+    // Step 2: Connect to the tunnel's DO
+    let mut link = open_link(lane, tunnel, &info, state.session.as_ref())?;
+
+    let ready = read_tunnel_ready(&mut link)?;
+    let connection_id: u64 = ready
+        .connection_id
+        .parse()
+        .context("Invalid connection ID in handshake")?;
+
+    state.connected_at = Some(Instant::now());
+
+    let session_slot = &mut state.session;
+    let mut session = match session_slot.take() {
+        Some(mut session) if ready.resumed => {
+            session.acknowledge(ready.last_msg_seq);
+            info!(
+                "Resumed session, replaying {} envelope(s)",
+                session.replay.len()
+            );
+            for (_, data) in &session.replay {
+                if let Err(e) = link.send(WsMessage::Binary(data.clone())) {
+                    session.lost_at = Instant::now();
+                    *session_slot = Some(session);
+                    return Err(e).context("Failed to replay unacknowledged envelopes");
+                }
+            }
+            session
+        }
+        previous => {
+            if previous.is_some() {
+                warn!("Relay could not resume the session, in-flight streams are lost");
+            }
+            let tunnel_url = Url::parse(&info.tunnel_url).context("Invalid tunnel URL")?;
+            Session::new(session_config, tunnel_url)
+        }
     };
+    session.connection_id = connection_id;
+
+    if !tunnel.announced.swap(true, Ordering::SeqCst) {
+        println!("\n✓ Tunnel established!");
+        println!("  Public URL: {}", info.tunnel_url);
+        println!("  Forwarding: {}", session_config.upstream);
+        if tunnel.connections > 1 {
+            println!("  Connections: {}", tunnel.connections);
+        }
+        println!("\nPress Ctrl+C to stop the tunnel.\n");
+    } else {
+        info!("Lane {} connected: {}", lane, info.tunnel_url);
+    }
 
-    let resp = agent
-        .post(&connect_url)
-        .header("Authorization", &format!("Bearer {}", token))
-        .send_json(&body)
-        .context("Failed to connect to tunnel service")?;
+    tunnel.status.connected(lane, &info.tunnel_url);
 
-    if resp.status() != 200 {
-        let error: ErrorResponse = resp.into_body().read_json().unwrap_or(ErrorResponse {
-            error: "Unknown error".to_string(),
-            code: None,
-        });
-        bail!("Failed to create tunnel: {}", error.error);
+    // Run the tunnel
+    run_tunnel(link, session, session_slot, lane, tunnel)
+}
+
+/// Open the relay connection over the configured transport.
+///
+/// In auto mode a WebSocket upgrade that something on the way refuses falls
+/// back to HTTP streaming. Later connections go straight to HTTP until
+/// [`WEBSOCKET_RETRY_INTERVAL`] has passed, then try a WebSocket again.
+/// Failures that would hit HTTP streaming just the same, such as the relay
+/// being unreachable, don't count.
+fn open_link(
+    lane: u32,
+    tunnel: &Tunnel,
+    info: &ConnectResponse,
+    session: Option<&Session>,
+) -> Result<RelayLink> {
+    let headers = link_headers(lane, tunnel, session);
+
+    match tunnel.transport {
+        Transport::Websocket => open_websocket(&tunnel.service_url, info, headers),
+        Transport::Http => open_http_stream(tunnel, info, headers),
+        Transport::Auto if tunnel.websocket_blocked() => open_http_stream(tunnel, info, headers),
+        Transport::Auto => {
+            let error = match open_websocket(&tunnel.service_url, info, headers.clone()) {
+                Ok(link) => {
+                    tunnel.record_websocket(false);
+                    return Ok(link);
+                }
+                Err(e) if e.is::<UpgradeBlocked>() => e,
+                Err(e) => return Err(e),
+            };
+            warn!("{:#}, trying HTTP streaming", error);
+            let link = open_http_stream(tunnel, info, headers)?;
+            tunnel.record_websocket(true);
+            Ok(link)
+        }
+    }
+}
+
+/// Headers naming the lane and, if there is a session to resume, where it
+/// left off.
+fn link_headers(
+    lane: u32,
+    tunnel: &Tunnel,
+    session: Option<&Session>,
+) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("Authorization", format!("Bearer {}", tunnel.token)),
+        ("X-Dotunnel-Lane", lane.to_string()),
+    ];
+    // Ask the relay to pick up where the lost connection left off
+    if let Some(session) = session.filter(|s| s.connection_id != 0) {
+        headers.push(("X-Dotunnel-Resume", session.connection_id.to_string()));
+        headers.push((
+            "X-Dotunnel-Last-Msg-Seq",
+            session.last_received_seq.to_string(),
+        ));
     }
+    headers
+}
 
-    let tunnel_info: ConnectResponse = resp
-        .into_body()
-        .read_json()
-        .context("Failed to parse tunnel response")?;
-    info!("Tunnel created: {}", tunnel_info.tunnel_url);
+/// A WebSocket failure that points at something between the CLI and the
+/// relay refusing the upgrade, rather than at the relay or the network.
+#[derive(Debug, thiserror::Error)]
+#[error("WebSocket upgrade appears blocked")]
+struct UpgradeBlocked;
 
-    // Step 2: Connect WebSocket to DO
+fn open_websocket(
+    service_url: &str,
+    info: &ConnectResponse,
+    headers: Vec<(&'static str, String)>,
+) -> Result<RelayLink> {
     let ws_url = format!(
         "{}/_api/tunnel/connect?tunnelId={}",
         service_url
             .replace("http://", "ws://")
             .replace("https://", "wss://"),
-        tunnel_info.tunnel_id
+        info.tunnel_id
     );
 
     let parsed_url = Url::parse(&ws_url)?;
 
-    let ws_request = http::Request::builder()
+    let mut ws_request = http::Request::builder()
         .uri(&ws_url)
-        .header("Authorization", format!("Bearer {}", token))
         .header(
             "Sec-WebSocket-Key",
             tungstenite::handshake::client::generate_key(),
@@ -313,84 +980,370 @@ fn connect_and_run(
         .header("Sec-WebSocket-Version", "13")
         .header("Connection", "Upgrade")
         .header("Upgrade", "websocket")
-        .header("Host", parsed_url.host_str().unwrap_or("localhost"))
+        .header("Host", parsed_url.host_str().unwrap_or("localhost"));
+    for (name, value) in headers {
+        ws_request = ws_request.header(name, value);
+    }
+
+    let ws_request = ws_request
         .body(())
         .context("Failed to build WebSocket request")?;
 
-    let (ws_stream, _) = connect(ws_request).context("Failed to establish WebSocket connection")?;
+    match connect(ws_request) {
+        Ok((ws_stream, _)) => Ok(RelayLink::WebSocket(Box::new(ws_stream))),
+        Err(tungstenite::Error::Http(resp)) => {
+            let status = resp.status().as_u16();
+            let relay_error: Option<ErrorResponse> = resp
+                .body()
+                .as_deref()
+                .and_then(|body| serde_json::from_slice(body).ok());
+            // The relay explains its refusals in JSON, so a bare 4xx came
+            // from something in between; and the relay only answers 405
+            // when the Upgrade header never reached it
+            let blocked = match &relay_error {
+                Some(error) => error.code.as_deref() == Some("method_not_allowed"),
+                None => (400..500).contains(&status),
+            };
+            let error = relay_error.unwrap_or_else(|| ErrorResponse {
+                error: format!("WebSocket upgrade rejected with status {}", status),
+                code: None,
+            });
+            let result =
+                Err(classify(status, error)).context("Failed to establish WebSocket connection");
+            if blocked {
+                result.context(UpgradeBlocked)
+            } else {
+                result
+            }
+        }
+        // Something answered, but not with a working upgrade
+        Err(e @ tungstenite::Error::Protocol(_)) => Err(e)
+            .context("Failed to establish WebSocket connection")
+            .context(UpgradeBlocked),
+        Err(e) => Err(e).context("Failed to establish WebSocket connection"),
+    }
+}
 
-    println!("\n✓ Tunnel established!");
-    println!("  Public URL: {}", tunnel_info.tunnel_url);
-    println!("  Forwarding: http://{}", local_addr);
-    println!("\nPress Ctrl+C to stop the tunnel.\n");
+/// Open the HTTP streaming transport: GET the downstream, whose response
+/// names the stream the upstream POST then feeds.
+fn open_http_stream(
+    tunnel: &Tunnel,
+    info: &ConnectResponse,
+    mut headers: Vec<(&'static str, String)>,
+) -> Result<RelayLink> {
+    let agent = crate::http_client::agent();
+    let stream_url = format!(
+        "{}/_api/tunnel/stream?tunnelId={}",
+        tunnel.service_url, info.tunnel_id
+    );
 
-    // Run the tunnel
-    run_tunnel(ws_stream, local_addr)
+    let mut request = agent
+        .get(&stream_url)
+        .config()
+        .http_status_as_error(false)
+        .build();
+    for (name, value) in &headers {
+        request = request.header(*name, value);
+    }
+    let resp = request.call().context("Failed to open HTTP stream")?;
+
+    if resp.status() != 200 {
+        let status = resp.status().as_u16();
+        let error: ErrorResponse = resp
+            .into_body()
+            .read_json()
+            .unwrap_or_else(|_| ErrorResponse {
+                error: format!("HTTP stream rejected with status {}", status),
+                code: None,
+            });
+        return Err(classify(status, error)).context("Failed to open HTTP stream");
+    }
+
+    let stream_id = resp
+        .headers()
+        .get("X-Dotunnel-Stream")
+        .and_then(|v| v.to_str().ok())
+        .context("Relay did not name the HTTP stream")?
+        .to_string();
+    headers.push(("X-Dotunnel-Stream", stream_id));
+
+    let link = HttpLink::start(agent, stream_url, headers, resp.into_body())
+        .context("Failed to start HTTP stream")?;
+    Ok(RelayLink::Http(link))
 }
 
-// =============================================================================
-// Tunnel Runtime
-// =============================================================================
+/// Get or create the tunnel via POST /_api/tunnel/connect.
+fn select_tunnel(
+    service_url: &str,
+    token: &str,
+    subdomain: &Option<String>,
+) -> Result<ConnectResponse> {
+    let agent = crate::http_client::agent();
+    let connect_url = format!("{}/_api/tunnel/connect", service_url);
 
-/// Run the tunnel IO loop.
-///
-/// Architecture:
+    let body = if let Some(subdomain) = subdomain {
+        serde_json::json!({ "subdomain": subdomain })
+    } else {
+        serde_json::json!({})
+    };
+
+    let resp = agent
+        .post(&connect_url)
+        .config()
+        .http_status_as_error(false)
+        .build()
+        .header("Authorization", &format!("Bearer {}", token))
+        .send_json(&body)
+        .context("Failed to connect to tunnel service")?;
+
+    if resp.status() != 200 {
+        let status = resp.status().as_u16();
+        let error: ErrorResponse = resp.into_body().read_json().unwrap_or(ErrorResponse {
+            error: "Unknown error".to_string(),
+            code: None,
+        });
+        return Err(classify(status, error)).context("Failed to create tunnel");
+    }
+
+    let tunnel_info: ConnectResponse = resp
+        .into_body()
+        .read_json()
+        .context("Failed to parse tunnel response")?;
+    info!("Tunnel created: {}", tunnel_info.tunnel_url);
+
+    Ok(tunnel_info)
+}
+
+/// Wait for the relay's `tunnel_ready` handshake.
+fn read_tunnel_ready(link: &mut RelayLink) -> Result<TunnelReady> {
+    link.set_read_timeout(Some(Duration::from_secs(10)))?;
+    loop {
+        match link.read().context("Failed to read tunnel handshake")? {
+            WsMessage::Text(text) => {
+                return serde_json::from_str(&text).context("Invalid tunnel handshake");
+            }
+            WsMessage::Ping(_) | WsMessage::Pong(_) => continue,
+            other => bail!("Unexpected message before tunnel handshake: {:?}", other),
+        }
+    }
+}
+
+// =============================================================================
+// Tunnel Runtime
+// =============================================================================
+
+/// Bytes written per IO loop iteration before checking for new messages
+/// and inbound data again.
+const FLUSH_BUDGET: usize = 256 * 1024;
+
+/// How long a connection that received GoAway may keep serving its
+/// in-flight streams before it is closed regardless.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long auto mode stays on HTTP streaming before trying a WebSocket
+/// again.
+const WEBSOCKET_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a connection whose link was closed waits for the WebSocket it is
+/// moving to.
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read timeout while a connection is running, short so the IO loop stays
+/// responsive for writes.
+///
+/// We cannot use non-blocking because tungstenite's read() may internally
+/// write (auto-pong) and a non-blocking write returning WouldBlock breaks it.
+/// Note: very short timeouts (< ~10ms) cause data corruption on macOS with
+/// native-tls because the TLS layer may return TimedOut mid-record.
+const ACTIVE_READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Why a relay connection ended without an error.
+enum ConnectionExit {
+    /// Ctrl+C, or another lane ended the tunnel.
+    Shutdown,
+    /// The relay closed the connection.
+    Closed,
+    /// The relay sent GoAway. The connection keeps draining on a background
+    /// thread, and a replacement should be opened immediately.
+    GoAway,
+    /// Another process connected to the lane; the connection has drained.
+    Replaced,
+}
+
+/// Outcome of a single IO loop iteration.
+enum PollOutcome {
+    Continue,
+    GoAway(GoAway),
+    Closed,
+}
+
+/// Control messages the IO loop handles itself rather than inline.
+enum ConnectionEvent {
+    GoAway(GoAway),
+    Ack(u32),
+    Pong(Bytes),
+}
+
+/// One relay connection and the session it is carrying.
+struct Connection {
+    link: RelayLink,
+    session: Session,
+    liveness: Liveness,
+}
+
+/// Run the tunnel IO loop.
+///
+/// Architecture:
 ///   - Single thread owns the WebSocket (required by tungstenite — `read()` may write internally).
 ///   - Worker threads send outbound messages through a priority channel.
 ///   - The IO loop alternates between draining writes (priority-ordered, batched) and reading.
 ///   - Read timeout is 50ms so writes are never stalled for long.
 ///   - When truly idle (no writes, no reads), we block on the channel for up to 5ms.
+///   - On GoAway, the connection is handed to a drain thread and this returns
+///     so the caller can open a replacement in parallel.
+///   - On a connection error the session is put back into `session_slot`
+///     so the caller can try to resume it.
+///   - We ping the relay ourselves; too many missed pongs count as a
+///     connection error, which is how half-open connections are detected.
+///   - In auto mode, a lane on HTTP streaming tries a WebSocket again every
+///     [`WEBSOCKET_RETRY_INTERVAL`], moving over only if the relay resumes
+///     the session on it.
 fn run_tunnel(
-    mut ws_stream: WebSocket<MaybeTlsStream<TcpStream>>,
-    local_addr: SocketAddr,
-) -> Result<()> {
-    // Use a short read timeout so the IO loop stays responsive for writes.
-    // We cannot use non-blocking because tungstenite's read() may internally write
-    // (auto-pong) and a non-blocking write returning WouldBlock breaks it.
-    // Note: very short timeouts (< ~10ms) cause data corruption on macOS with
-    // native-tls because the TLS layer may return TimedOut mid-record.
-    let active_timeout = Some(Duration::from_millis(50));
-    set_read_timeout(&ws_stream, active_timeout)?;
-
-    // Priority write channel
-    let (write_tx, write_rx) = mpsc::channel::<PrioritizedMsg>();
-    let writer = PriorityWriter::new(write_tx);
-
-    // Stream state map: streamId -> StreamState
-    let streams: Arc<Mutex<HashMap<u32, StreamState>>> = Arc::new(Mutex::new(HashMap::new()));
-    let msg_seq_counter = Arc::new(AtomicU32::new(1));
-
-    // Set up Ctrl+C handler
-    let shutdown = Arc::new(AtomicBool::new(false));
-    {
-        let shutdown = shutdown.clone();
-        let writer = writer.clone();
-        ctrlc::set_handler(move || {
-            info!("Shutting down tunnel...");
-            shutdown.store(true, Ordering::SeqCst);
-            let _ = writer.send_control(WsMessage::Close(None));
-        })
-        .context("Failed to set Ctrl+C handler")?;
-    }
-
-    // Re-usable priority heap — avoids per-iteration allocation
-    let mut heap: BinaryHeap<PrioritizedMsg> = BinaryHeap::with_capacity(64);
+    link: RelayLink,
+    session: Session,
+    session_slot: &mut Option<Session>,
+    lane: u32,
+    tunnel: &Tunnel,
+) -> Result<ConnectionExit> {
+    let shutdown = &tunnel.shutdown;
+    let mut conn = Connection::new(link, session)?;
+    let mut switch = WebSocketSwitch::new();
 
     loop {
         if shutdown.load(Ordering::SeqCst) {
-            drain_and_flush(&write_rx, &mut heap, &mut ws_stream);
-            return Ok(());
+            conn.close();
+            return Ok(ConnectionExit::Shutdown);
+        }
+
+        let mut outcome = switch
+            .poll(&mut conn, lane, tunnel)
+            .and_then(|()| conn.poll(None));
+
+        // The relay closes the old link as it resumes the session on the
+        // new one, which may well be noticed first
+        if matches!(outcome, Ok(PollOutcome::Closed) | Err(_)) {
+            match switch.finish(&mut conn, lane, tunnel) {
+                Ok(true) => outcome = Ok(PollOutcome::Continue),
+                Ok(false) => {}
+                Err(e) => outcome = Err(e),
+            }
+        }
+
+        if let Some(rtt) = conn.liveness.take_rtt_update()
+            && tunnel.status.record_rtt(lane, rtt.as_millis() as u64)
+        {
+            info!("Relay RTT: {} ms", rtt.as_millis());
+        }
+
+        match outcome {
+            Ok(PollOutcome::Continue) => {}
+            Ok(PollOutcome::GoAway(go_away)) => match go_away.code {
+                GoAwayCode::Replaced => {
+                    warn!("Another connection took over lane {}, draining", lane);
+                    conn.drain(go_away, shutdown);
+                    return Ok(ConnectionExit::Replaced);
+                }
+                GoAwayCode::Restart => {
+                    warn!("Received GoAway: {}", go_away.reason);
+                    let shutdown = shutdown.clone();
+                    thread::spawn(move || conn.drain(go_away, &shutdown));
+                    return Ok(ConnectionExit::GoAway);
+                }
+            },
+            Ok(PollOutcome::Closed) if shutdown.load(Ordering::SeqCst) => {
+                return Ok(ConnectionExit::Shutdown);
+            }
+            Ok(PollOutcome::Closed) => return Ok(ConnectionExit::Closed),
+            Err(e) => {
+                if shutdown.load(Ordering::SeqCst) {
+                    return Ok(ConnectionExit::Shutdown);
+                }
+                if conn.session.resumable {
+                    conn.session.lost_at = Instant::now();
+                    *session_slot = Some(conn.session);
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn new(mut link: RelayLink, session: Session) -> Result<Self> {
+        link.set_read_timeout(Some(ACTIVE_READ_TIMEOUT))?;
+
+        Ok(Self {
+            link,
+            liveness: Liveness::new(session.liveness_config),
+            session,
+        })
+    }
+
+    /// Move onto a link the relay resumed this session on. The relay has
+    /// already let go of the old link; whatever it didn't get from there is
+    /// sent again.
+    fn switch_link(&mut self, mut link: RelayLink, ready: &TunnelReady) -> Result<()> {
+        link.set_read_timeout(Some(ACTIVE_READ_TIMEOUT))?;
+        self.session.acknowledge(ready.last_msg_seq);
+        for (_, data) in &self.session.replay {
+            link.send(WsMessage::Binary(data.clone()))
+                .context("Failed to replay unacknowledged envelopes")?;
         }
+        std::mem::replace(&mut self.link, link).close();
+        // Pings sent on the old link are never answered
+        self.liveness = Liveness::new(self.session.liveness_config);
+        Ok(())
+    }
 
-        // ── Phase 1: Drain channel into priority heap, write in priority order ──
-        let wrote = drain_and_flush(&write_rx, &mut heap, &mut ws_stream);
+    /// Run one iteration of the IO loop.
+    ///
+    /// `draining` carries the `last_msg_seq` of a GoAway already received;
+    /// streams the relay opens after it are refused.
+    fn poll(&mut self, draining: Option<u32>) -> Result<PollOutcome> {
+        // ── Phase 1: Drain channel into scheduler, write in scheduled order ──
+        match self.liveness.tick() {
+            Probe::Idle => {}
+            Probe::Send(data) => {
+                let _ = self.session.writer.send_control(control_ping(data));
+            }
+            Probe::Dead { missed } => {
+                bail!("Relay did not answer {} pings, connection is dead", missed);
+            }
+        }
+        self.session.maybe_ack();
+        // Upgraded connections never queue
+        self.session.workers.expire_queued();
+        let wrote = drain_and_flush(&mut self.session, &mut self.link)?;
 
         // ── Phase 2: Try to read one inbound message (times out after ~50ms) ──
-        match ws_stream.read() {
+        match self.link.read() {
             Ok(msg) => {
-                handle_inbound(msg, local_addr, &writer, &streams, &msg_seq_counter, &mut ws_stream)?;
+                let event = handle_inbound(msg, &mut self.session, draining, &mut self.link)?;
                 // After reading, immediately loop back to drain writes.
-                continue;
+                return Ok(match event {
+                    Some(ConnectionEvent::GoAway(go_away)) => PollOutcome::GoAway(go_away),
+                    Some(ConnectionEvent::Ack(last_msg_seq)) => {
+                        self.session.acknowledge(last_msg_seq);
+                        PollOutcome::Continue
+                    }
+                    Some(ConnectionEvent::Pong(data)) => {
+                        if let Some(sample) = self.liveness.on_pong(&data) {
+                            debug!("Relay RTT: {} ms", sample.as_millis());
+                        }
+                        PollOutcome::Continue
+                    }
+                    None => PollOutcome::Continue,
+                });
             }
             Err(tungstenite::Error::Io(ref e))
                 if e.kind() == std::io::ErrorKind::WouldBlock
@@ -400,15 +1353,12 @@ fn run_tunnel(
             }
             Err(tungstenite::Error::ConnectionClosed) => {
                 info!("Server closed connection");
-                return Ok(());
+                return Ok(PollOutcome::Closed);
             }
             Err(tungstenite::Error::AlreadyClosed) => {
-                return Ok(());
+                return Ok(PollOutcome::Closed);
             }
             Err(e) => {
-                if shutdown.load(Ordering::SeqCst) {
-                    return Ok(());
-                }
                 return Err(anyhow::anyhow!("WebSocket error: {}", e));
             }
         }
@@ -416,84 +1366,269 @@ fn run_tunnel(
         // ── Phase 3: Nothing to read and nothing was written — idle wait ──
         // Block on the channel briefly to avoid busy-spinning at 50ms granularity.
         if !wrote {
-            match write_rx.recv_timeout(Duration::from_millis(5)) {
+            match self.session.write_rx.recv_timeout(Duration::from_millis(5)) {
                 Ok(msg) => {
-                    heap.push(msg);
+                    self.session.scheduler.push(msg);
                     // Drain any additional messages that arrived while we waited
-                    while let Ok(m) = write_rx.try_recv() {
-                        heap.push(m);
-                    }
-                    flush_heap(&mut heap, &mut ws_stream);
+                    drain_and_flush(&mut self.session, &mut self.link)?;
                 }
                 Err(mpsc::RecvTimeoutError::Timeout) => {
                     // Idle — loop back and check for inbound data
                 }
                 Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    return Ok(());
+                    return Ok(PollOutcome::Closed);
+                }
+            }
+        }
+
+        Ok(PollOutcome::Continue)
+    }
+
+    /// Keep serving in-flight streams after GoAway, then close.
+    ///
+    /// Streams opened up to `last_msg_seq` run to completion (bounded by
+    /// [`DRAIN_TIMEOUT`]); anything newer is refused in `dispatch_message`.
+    fn drain(mut self, go_away: GoAway, shutdown: &AtomicBool) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+
+        loop {
+            let in_flight = self.session.streams.lock().unwrap().len();
+            if in_flight == 0 {
+                debug!("Drained all streams");
+                break;
+            }
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "Drain deadline reached with {} stream(s) still in flight",
+                    in_flight
+                );
+                break;
+            }
+
+            match self.poll(Some(go_away.last_msg_seq)) {
+                Ok(PollOutcome::Continue | PollOutcome::GoAway(_)) => {}
+                Ok(PollOutcome::Closed) => return,
+                Err(e) => {
+                    debug!("Draining connection failed: {}", e);
+                    return;
                 }
             }
         }
+
+        self.close();
+    }
+
+    /// Flush pending writes and close the relay connection.
+    fn close(&mut self) {
+        while let Ok(true) = drain_and_flush(&mut self.session, &mut self.link) {}
+        self.link.close();
     }
 }
 
-/// Set the read timeout on the underlying TCP stream.
-fn set_read_timeout(ws: &WebSocket<MaybeTlsStream<TcpStream>>, timeout: Option<Duration>) -> Result<()> {
-    match ws.get_ref() {
-        MaybeTlsStream::Plain(tcp) => tcp.set_read_timeout(timeout)?,
-        MaybeTlsStream::NativeTls(tls) => tls.get_ref().set_read_timeout(timeout)?,
-        _ => {}
+/// Auto mode's attempts to move a lane from HTTP streaming back to a
+/// WebSocket.
+///
+/// The WebSocket asks to resume the lane's session, and the relay refuses
+/// rather than replace the running connection if it can't, so a failed
+/// attempt leaves the lane as it was.
+struct WebSocketSwitch {
+    /// When the next attempt is due; while one is in flight, when it
+    /// started
+    next_attempt: Instant,
+    pending: Option<mpsc::Receiver<Result<(RelayLink, TunnelReady)>>>,
+}
+
+impl WebSocketSwitch {
+    fn new() -> Self {
+        Self {
+            next_attempt: Instant::now() + WEBSOCKET_RETRY_INTERVAL,
+            pending: None,
+        }
+    }
+
+    /// Start an attempt once one is due, and move the connection over when
+    /// one gets through.
+    fn poll(&mut self, conn: &mut Connection, lane: u32, tunnel: &Tunnel) -> Result<()> {
+        if let Some(pending) = &self.pending {
+            match pending.try_recv() {
+                Ok(result) => {
+                    self.pending = None;
+                    self.next_attempt = Instant::now() + WEBSOCKET_RETRY_INTERVAL;
+                    self.take_over(conn, result, lane, tunnel)?;
+                }
+                // Don't hold acks back for a handshake that hangs
+                Err(mpsc::TryRecvError::Empty) if self.next_attempt.elapsed() < SWITCH_TIMEOUT => {}
+                Err(_) => {
+                    self.pending = None;
+                    self.next_attempt = Instant::now() + WEBSOCKET_RETRY_INTERVAL;
+                    conn.session.hold_acks = false;
+                }
+            }
+            return Ok(());
+        }
+
+        let due = tunnel.transport == Transport::Auto
+            && matches!(conn.link, RelayLink::Http(_))
+            && conn.session.resumable
+            && Instant::now() >= self.next_attempt;
+        if !due {
+            return Ok(());
+        }
+        match self.start(lane, tunnel, &conn.session) {
+            Ok(pending) => {
+                // An ack now could trim what the relay must replay on the
+                // new link
+                conn.session.hold_acks = true;
+                self.pending = Some(pending);
+                self.next_attempt = Instant::now();
+            }
+            Err(e) => {
+                debug!("Could not try a WebSocket: {:#}", e);
+                self.next_attempt = Instant::now() + WEBSOCKET_RETRY_INTERVAL;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for an attempt in flight. Returns whether the connection moved.
+    fn finish(&mut self, conn: &mut Connection, lane: u32, tunnel: &Tunnel) -> Result<bool> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(false);
+        };
+        self.next_attempt = Instant::now() + WEBSOCKET_RETRY_INTERVAL;
+        match pending.recv_timeout(SWITCH_TIMEOUT) {
+            Ok(result) => self.take_over(conn, result, lane, tunnel),
+            Err(_) => {
+                conn.session.hold_acks = false;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Open the WebSocket on a background thread.
+    fn start(
+        &self,
+        lane: u32,
+        tunnel: &Tunnel,
+        session: &Session,
+    ) -> Result<mpsc::Receiver<Result<(RelayLink, TunnelReady)>>> {
+        let info = tunnel.tunnel_info()?;
+        let service_url = tunnel.service_url.clone();
+        let mut headers = link_headers(lane, tunnel, Some(session));
+        headers.push(("X-Dotunnel-Switch", "1".to_string()));
+
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("dotunnel-switch".to_string())
+            .spawn(move || {
+                let result = open_websocket(&service_url, &info, headers).and_then(|mut link| {
+                    let ready = read_tunnel_ready(&mut link)?;
+                    Ok((link, ready))
+                });
+                let _ = tx.send(result);
+            })?;
+        Ok(rx)
+    }
+
+    fn take_over(
+        &self,
+        conn: &mut Connection,
+        result: Result<(RelayLink, TunnelReady)>,
+        lane: u32,
+        tunnel: &Tunnel,
+    ) -> Result<bool> {
+        conn.session.hold_acks = false;
+        match result {
+            Ok((link, ready)) if ready.resumed => {
+                tunnel.record_websocket(false);
+                conn.switch_link(link, &ready)?;
+                info!("Lane {} moved to a WebSocket", lane);
+                Ok(true)
+            }
+            Ok((mut link, _)) => {
+                link.close();
+                debug!("Relay did not resume the session on the WebSocket");
+                Ok(false)
+            }
+            Err(e) => {
+                if e.is::<UpgradeBlocked>() {
+                    tunnel.record_websocket(true);
+                }
+                debug!("Staying on HTTP streaming: {:#}", e);
+                Ok(false)
+            }
+        }
     }
-    Ok(())
 }
 
-/// Drain all pending messages from the channel into the priority heap,
-/// then send them to the WebSocket in priority order.
+/// Drain all pending messages from the channel into the scheduler,
+/// then send up to [`FLUSH_BUDGET`] bytes to the WebSocket in scheduled order.
 /// Returns true if any messages were written.
-fn drain_and_flush(
-    rx: &mpsc::Receiver<PrioritizedMsg>,
-    heap: &mut BinaryHeap<PrioritizedMsg>,
-    ws: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-) -> bool {
-    while let Ok(m) = rx.try_recv() {
-        heap.push(m);
+fn drain_and_flush(session: &mut Session, link: &mut RelayLink) -> Result<bool> {
+    while let Ok(m) = session.write_rx.try_recv() {
+        session.scheduler.push(m);
     }
-    if heap.is_empty() {
-        return false;
+    if session.scheduler.is_empty() {
+        return Ok(false);
     }
-    flush_heap(heap, ws);
-    true
+    flush_scheduled(session, link)?;
+    Ok(true)
 }
 
-/// Send all messages from the heap to the WebSocket in priority order.
-fn flush_heap(
-    heap: &mut BinaryHeap<PrioritizedMsg>,
-    ws: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-) {
-    while let Some(pm) = heap.pop() {
-        if let Err(e) = ws.send(pm.msg) {
-            error!("WebSocket write error: {}", e);
-            heap.clear();
-            return;
-        }
+/// Send queued messages to the WebSocket in scheduled order, stopping once
+/// [`FLUSH_BUDGET`] bytes are written so that messages arriving meanwhile get
+/// scheduled against what is still queued.
+///
+/// A message's budget is returned only once the link has taken its bytes,
+/// so producers are paced by the relay connection rather than by the
+/// scheduler.
+///
+/// A failed write leaves the rest queued; sequenced envelopes already sealed
+/// are in the replay buffer, so a resumed session loses nothing.
+fn flush_scheduled(session: &mut Session, link: &mut RelayLink) -> Result<()> {
+    let mut written = 0;
+    while written < FLUSH_BUDGET
+        && let Some(pm) = session.scheduler.pop()
+    {
+        let charge = pm.charge();
+        let data = session.seal(pm);
+        written += data.len();
+        let sent = link.send(WsMessage::Binary(data));
+        session.writer.release(charge);
+        sent.context("Relay write error")?;
     }
+    Ok(())
 }
 
 /// Handle one inbound WebSocket message.
+///
+/// Returns the connection-level event if the relay sent one.
 fn handle_inbound(
     msg: WsMessage,
-    local_addr: SocketAddr,
-    writer: &PriorityWriter,
-    streams: &Arc<Mutex<HashMap<u32, StreamState>>>,
-    msg_seq_counter: &Arc<AtomicU32>,
-    ws: &mut WebSocket<MaybeTlsStream<TcpStream>>,
-) -> Result<()> {
+    session: &mut Session,
+    draining: Option<u32>,
+    link: &mut RelayLink,
+) -> Result<Option<ConnectionEvent>> {
     match msg {
         WsMessage::Text(text) => {
             debug!("Received text message: {}", text);
         }
         WsMessage::Binary(data) => match Envelope::decode(&data) {
             Ok(envelope) => {
-                dispatch_message(envelope, local_addr, writer, streams, msg_seq_counter);
+                if envelope.connection_id != session.connection_id {
+                    // The relay was evicted from memory and restarted its
+                    // side of the session; its sequence numbers start over.
+                    debug!("Relay restarted session state");
+                    session.restart(envelope.connection_id);
+                }
+                if !session.accept_inbound(envelope.msg_seq) {
+                    debug!("Skipping replayed envelope {}", envelope.msg_seq);
+                    return Ok(None);
+                }
+                return Ok(dispatch_message(envelope, session, draining));
             }
             Err(e) => {
                 error!("Error decoding message: {}", e);
@@ -502,7 +1637,7 @@ fn handle_inbound(
         WsMessage::Ping(_data) => {
             debug!("Received ping");
             // Tungstenite auto-queues a pong internally, just flush it out.
-            let _ = ws.flush();
+            let _ = link.flush();
         }
         WsMessage::Pong(_) => {
             debug!("Received pong");
@@ -513,113 +1648,268 @@ fn handle_inbound(
         }
         WsMessage::Frame(_) => {}
     }
-    Ok(())
+    Ok(None)
 }
 
 /// Dispatch a decoded envelope: fast operations run inline, slow I/O spawns a thread.
+///
+/// Returns the connection-level event if the envelope carried one.
 fn dispatch_message(
     envelope: Envelope,
-    local_addr: SocketAddr,
-    writer: &PriorityWriter,
-    streams: &Arc<Mutex<HashMap<u32, StreamState>>>,
-    msg_seq_counter: &Arc<AtomicU32>,
-) {
+    session: &Session,
+    draining: Option<u32>,
+) -> Option<ConnectionEvent> {
     let stream_id = envelope.stream_id;
-    let connection_id = envelope.connection_id;
+    let upstream = &session.upstream;
+    let max_chunk_size = session.max_chunk_size;
+    let workers = &session.workers;
+    let upgraded = &session.upgraded;
+    let writer = &session.writer;
+    let streams = &session.streams;
 
     match envelope.payload {
         Payload::Http(http) => match http {
+            // A draining connection only finishes what it already has
+            HttpMessage::RequestInit(_)
+                if draining.is_some_and(|last_msg_seq| envelope.msg_seq > last_msg_seq) =>
+            {
+                debug!("Stream {}: refused, connection is draining", stream_id);
+                let _ = writer.send_meta(
+                    stream_id,
+                    response_abort(AbortReason::Cancelled, "Connection is draining"),
+                );
+            }
             // Fast path: just store data in the streams map (inline)
             HttpMessage::RequestInit(init) => {
                 let method = init.method;
-                let uri = init.uri;
                 let has_body = init.has_body;
-                let headers: Vec<(String, String)> = init
-                    .headers
-                    .into_iter()
-                    .map(|h| (h.name, String::from_utf8_lossy(&h.value).into_owned()))
-                    .collect();
+                let headers = init.headers;
                 debug!(
                     "Stream {}: {} {} (hasBody: {})",
-                    stream_id, method, uri, has_body
+                    stream_id, method, init.uri, has_body
                 );
-
-                let is_websocket = headers.iter().any(|(name, value)| {
-                    name.eq_ignore_ascii_case("upgrade") && value.eq_ignore_ascii_case("websocket")
+                let Some(uri) = origin_form(&init.uri, &session.tunnel_url) else {
+                    warn!(
+                        "Stream {}: {} {:?} -> 400 (invalid request target)",
+                        stream_id, method, init.uri
+                    );
+                    reject_bad_target(stream_id, writer);
+                    return None;
+                };
+                let uri = uri.into_owned();
+
+                let is_websocket = headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case("upgrade")
+                        && header.value.eq_ignore_ascii_case(b"websocket")
                 });
+                // Any other upgrade becomes a byte pipe once switched, its
+                // request body carrying the client's side
+                let is_upgrade = !is_websocket
+                    && headers
+                        .iter()
+                        .any(|header| header.name.eq_ignore_ascii_case("upgrade"));
+
+                // Refuse early rather than buffer a body we couldn't forward
+                let pool = if is_websocket || is_upgrade {
+                    upgraded
+                } else {
+                    workers
+                };
+                if pool.is_saturated() {
+                    warn!(
+                        "Stream {}: too many concurrent streams, refusing",
+                        stream_id
+                    );
+                    reject_busy(stream_id, writer);
+                    return None;
+                }
 
                 if is_websocket {
                     debug!("Stream {}: WebSocket upgrade request", stream_id);
-                    // WebSocket upgrade does blocking I/O — spawn a thread
-                    let writer = writer.clone();
-                    let streams = streams.clone();
-                    let msg_seq_counter = msg_seq_counter.clone();
-                    thread::spawn(move || {
-                        if let Err(e) = handle_websocket_upgrade(
-                            stream_id,
-                            connection_id,
-                            local_addr,
-                            &uri,
-                            &headers,
-                            writer,
-                            streams,
-                            msg_seq_counter,
-                        ) {
-                            error!("Stream {}: WebSocket upgrade error: {}", stream_id, e);
-                        }
-                    });
+                    // WebSocket upgrade does blocking I/O, and then holds its
+                    // worker for as long as the socket stays open
+                    let worker_writer = writer.clone();
+                    let worker_streams = streams.clone();
+                    let upstream = upstream.clone();
+                    let accepted = upgraded.try_execute(
+                        move || {
+                            if let Err(e) = handle_websocket_upgrade(
+                                stream_id,
+                                &upstream,
+                                &uri,
+                                &headers,
+                                max_chunk_size,
+                                worker_writer,
+                                worker_streams,
+                            ) {
+                                error!("Stream {}: WebSocket upgrade error: {}", stream_id, e);
+                            }
+                        },
+                        refuse_busy(stream_id, writer, streams),
+                    );
+                    if !accepted {
+                        warn!(
+                            "Stream {}: too many concurrent streams, refusing",
+                            stream_id
+                        );
+                        reject_busy(stream_id, writer);
+                    }
+                } else if is_upgrade {
+                    debug!("Stream {}: protocol upgrade request", stream_id);
+                    let (client_tx, client_rx) = if has_body {
+                        let (tx, rx) = mpsc::sync_channel(upgrade::CLIENT_QUEUE_CHUNKS);
+                        (Some(tx), Some(rx))
+                    } else {
+                        (None, None)
+                    };
+                    streams.lock().unwrap().insert(
+                        stream_id,
+                        StreamState {
+                            stream_type: StreamType::Upgraded {
+                                write_tx: client_tx,
+                                socket: None,
+                            },
+                        },
+                    );
+                    let request = UpgradeRequest {
+                        method,
+                        uri,
+                        headers,
+                        client_rx,
+                    };
+                    let worker_writer = writer.clone();
+                    let worker_streams = streams.clone();
+                    let upstream = upstream.clone();
+                    let accepted = upgraded.try_execute(
+                        move || {
+                            if let Err(e) = handle_upgrade(
+                                stream_id,
+                                &upstream,
+                                request,
+                                max_chunk_size,
+                                worker_writer,
+                                worker_streams,
+                            ) {
+                                error!("Stream {}: upgrade error: {}", stream_id, e);
+                            }
+                        },
+                        refuse_busy(stream_id, writer, streams),
+                    );
+                    if !accepted {
+                        warn!(
+                            "Stream {}: too many concurrent streams, refusing",
+                            stream_id
+                        );
+                        streams.lock().unwrap().remove(&stream_id);
+                        reject_busy(stream_id, writer);
+                    }
                 } else {
-                    let mut streams_guard = streams.lock().unwrap();
-                    streams_guard.insert(
+                    // Forwarded right away; the body follows as it arrives
+                    let (body_tx, body) = if has_body {
+                        let (tx, body) = RequestBody::channel(content_length(&headers));
+                        (Some(tx), body)
+                    } else {
+                        (None, RequestBody::empty())
+                    };
+                    streams.lock().unwrap().insert(
                         stream_id,
                         StreamState {
-                            stream_type: StreamType::Http {
-                                pending_request: Some(PendingRequest {
+                            stream_type: StreamType::Http { body_tx },
+                        },
+                    );
+                    let worker_writer = writer.clone();
+                    let worker_streams = streams.clone();
+                    let upstream = upstream.clone();
+                    let accepted = workers.try_execute(
+                        move || {
+                            if let Err(e) = process_request(
+                                stream_id,
+                                &upstream,
+                                RequestHead {
                                     method,
                                     uri,
                                     headers,
-                                    body_chunks: vec![],
-                                    has_body,
-                                }),
-                            },
+                                },
+                                body,
+                                max_chunk_size,
+                                worker_writer,
+                                worker_streams,
+                            ) {
+                                error!("Stream {}: Error processing request: {}", stream_id, e);
+                            }
                         },
+                        refuse_busy(stream_id, writer, streams),
                     );
+                    if !accepted {
+                        warn!(
+                            "Stream {}: too many concurrent streams, refusing",
+                            stream_id
+                        );
+                        streams.lock().unwrap().remove(&stream_id);
+                        reject_busy(stream_id, writer);
+                    }
                 }
             }
-            // Fast path: append body data (inline)
+            // Passed on to the worker forwarding the request
             HttpMessage::RequestBodyChunk(chunk) => {
                 let mut streams_guard = streams.lock().unwrap();
-                if let Some(state) = streams_guard.get_mut(&stream_id)
-                    && let StreamType::Http {
-                        pending_request: Some(pending),
-                    } = &mut state.stream_type
+                if let Some(StreamState {
+                    stream_type:
+                        StreamType::Upgraded {
+                            write_tx: Some(tx), ..
+                        },
+                }) = streams_guard.get(&stream_id)
                 {
-                    pending.body_chunks.push(chunk.data);
+                    // The relay can't be slowed down, so a local server
+                    // that stops reading is cut off rather than buffered for
+                    if let Err(mpsc::TrySendError::Full(_)) = tx.try_send(chunk.data) {
+                        warn!(
+                            "Stream {}: local server not reading, closing upgrade",
+                            stream_id
+                        );
+                        close_upgraded(streams_guard.remove(&stream_id));
+                        drop(streams_guard);
+                        let _ = writer.send_meta(
+                            stream_id,
+                            response_abort(AbortReason::FlowControl, "Local server not reading"),
+                        );
+                    }
+                } else {
+                    feed_request_body(
+                        stream_id,
+                        BodyEvent::Data(chunk.data),
+                        &mut streams_guard,
+                        writer,
+                    );
                 }
             }
-            // Slow path: forward to local server — spawn a thread
+            HttpMessage::RequestTrailers(trailers) => {
+                feed_request_body(
+                    stream_id,
+                    BodyEvent::Trailers(trailers.headers),
+                    &mut streams.lock().unwrap(),
+                    writer,
+                );
+            }
             HttpMessage::RequestEnd(_) => {
                 debug!("Stream {}: request end", stream_id);
-                let writer = writer.clone();
-                let streams = streams.clone();
-                let msg_seq_counter = msg_seq_counter.clone();
-                thread::spawn(move || {
-                    if let Err(e) = process_request(
-                        stream_id,
-                        connection_id,
-                        local_addr,
-                        writer,
-                        streams,
-                        msg_seq_counter,
-                    ) {
-                        error!("Stream {}: Error processing request: {}", stream_id, e);
+                let mut streams_guard = streams.lock().unwrap();
+                match streams_guard
+                    .get_mut(&stream_id)
+                    .map(|state| &mut state.stream_type)
+                {
+                    // The client's half-close
+                    Some(StreamType::Upgraded { write_tx, .. }) => *write_tx = None,
+                    Some(StreamType::Http { .. }) => {
+                        feed_request_body(stream_id, BodyEvent::End, &mut streams_guard, writer);
                     }
-                });
+                    // Refused or aborted earlier
+                    _ => {}
+                }
             }
             HttpMessage::RequestAbort(abort) => {
                 warn!("Stream {}: request aborted: {:?}", stream_id, abort.reason);
-                let mut streams_guard = streams.lock().unwrap();
-                streams_guard.remove(&stream_id);
+                close_upgraded(streams.lock().unwrap().remove(&stream_id));
             }
             _ => {
                 warn!("Stream {}: unexpected HTTP message from server", stream_id);
@@ -630,169 +1920,234 @@ fn dispatch_message(
                 "Stream {}: Received WebSocket frame (opcode: {:?})",
                 stream_id, frame.opcode
             );
+            // Keeps concurrent downloads to small chunks while it lasts
+            writer.mark_interactive();
             let mut streams_guard = streams.lock().unwrap();
             handle_ws_frame(stream_id, frame, &mut streams_guard);
         }
-        Payload::Control(control) => {
-            if let Err(e) = handle_control(connection_id, control, writer.clone()) {
+        Payload::Control(control) => match handle_control(control, writer.clone()) {
+            Ok(event) => return event,
+            Err(e) => {
                 error!("Error handling control message: {}", e);
             }
-        }
+        },
     }
+    None
 }
 
-/// Handle decoded control message
-fn handle_control(connection_id: u64, control: Control, writer: PriorityWriter) -> Result<()> {
-    match control {
-        Control::Ping(ping) => {
-            debug!("Received control ping");
-            let pong = encode_control_pong(connection_id, &ping.data);
-            writer
-                .send_control(WsMessage::Binary(pong.into()))
-                .context("Failed to send control pong")?;
+/// Pass part of a request body on to the worker forwarding it.
+///
+/// The relay can't be slowed down, so a local server that stops reading is
+/// cut off rather than buffered for. A worker that stopped reading on its
+/// own, having answered already, just gets no more of the body.
+fn feed_request_body(
+    stream_id: u32,
+    event: BodyEvent,
+    streams: &mut HashMap<u32, StreamState>,
+    writer: &PriorityWriter,
+) {
+    let Some(StreamState {
+        stream_type: StreamType::Http { body_tx },
+    }) = streams.get_mut(&stream_id)
+    else {
+        return;
+    };
+    let Some(tx) = body_tx else {
+        return;
+    };
+    let end = matches!(event, BodyEvent::End);
+    match tx.try_send(event) {
+        Ok(()) if end => *body_tx = None,
+        Ok(()) => {}
+        Err(mpsc::TrySendError::Full(_)) => {
+            warn!(
+                "Stream {}: local server not reading request body, aborting",
+                stream_id
+            );
+            streams.remove(&stream_id);
+            let _ = writer.send_meta(
+                stream_id,
+                response_abort(AbortReason::FlowControl, "Local server not reading"),
+            );
         }
-        Control::Pong(_) => {
+        Err(mpsc::TrySendError::Disconnected(_)) => *body_tx = None,
+    }
+}
+
+/// The length a request's Content-Length header gives, if it gives one.
+fn content_length(headers: &[Header]) -> Option<u64> {
+    let header = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("content-length"))?;
+    std::str::from_utf8(&header.value).ok()?.trim().parse().ok()
+}
+
+/// Close the local connection of a removed stream, if it was upgraded.
+fn close_upgraded(state: Option<StreamState>) {
+    if let Some(StreamState {
+        stream_type:
+            StreamType::Upgraded {
+                socket: Some(socket),
+                ..
+            },
+    }) = state
+    {
+        socket.close();
+    }
+}
+
+/// A job that refuses a stream with 503, for when it waited too long for a
+/// worker.
+fn refuse_busy(
+    stream_id: u32,
+    writer: &PriorityWriter,
+    streams: &Arc<Mutex<HashMap<u32, StreamState>>>,
+) -> impl FnOnce() + Send + 'static {
+    let writer = writer.clone();
+    let streams = streams.clone();
+    move || {
+        warn!(
+            "Stream {}: waited too long for a worker, refusing",
+            stream_id
+        );
+        streams.lock().unwrap().remove(&stream_id);
+        reject_busy(stream_id, &writer);
+    }
+}
+
+/// Answer a stream with 503 because no worker is free to forward it.
+///
+/// Sent whole at Meta priority: the IO loop calls this, and must never wait
+/// for body budget.
+fn reject_busy(stream_id: u32, writer: &PriorityWriter) {
+    let body = Bytes::from_static(
+        b"Service Unavailable: too many concurrent requests through this tunnel",
+    );
+    let _ = writer.send_meta(
+        stream_id,
+        response_init(
+            503,
+            HttpVersion::H1,
+            vec![Header {
+                name: "Retry-After".to_string(),
+                value: Bytes::from_static(b"1"),
+            }],
+            true,
+            body.len() as u64,
+        ),
+    );
+    let _ = writer.send_meta(stream_id, response_body_chunk(body, 0, true));
+    let _ = writer.send_meta(stream_id, response_end());
+}
+
+/// Answer a stream with 400 because its request-target isn't one the
+/// tunnel forwards. Sent whole at Meta priority, like [`reject_busy`].
+fn reject_bad_target(stream_id: u32, writer: &PriorityWriter) {
+    let body = Bytes::from_static(b"Bad Request: invalid request target");
+    let _ = writer.send_meta(
+        stream_id,
+        response_init(400, HttpVersion::H1, Vec::new(), true, body.len() as u64),
+    );
+    let _ = writer.send_meta(stream_id, response_body_chunk(body, 0, true));
+    let _ = writer.send_meta(stream_id, response_end());
+}
+
+/// Handle decoded control message
+///
+/// GoAway, Ack and Pong are handed back to the IO loop, which owns the
+/// connection lifecycle, the replay buffer and liveness probing.
+fn handle_control(control: Control, writer: PriorityWriter) -> Result<Option<ConnectionEvent>> {
+    match control {
+        Control::Ping(ping) => {
+            debug!("Received control ping");
+            writer
+                .send_control(control_pong(&ping.data))
+                .context("Failed to send control pong")?;
+        }
+        Control::Pong(pong) => {
             debug!("Received control pong");
+            return Ok(Some(ConnectionEvent::Pong(pong.data)));
         }
         Control::Error(error) => {
             error!("Control error {}: {}", error.code, error.message);
         }
         Control::GoAway(go_away) => {
-            warn!("Received GoAway: {}", go_away.reason);
+            return Ok(Some(ConnectionEvent::GoAway(go_away)));
+        }
+        Control::Ack(ack) => {
+            return Ok(Some(ConnectionEvent::Ack(ack.last_msg_seq)));
         }
         Control::FlowWindowUpdate(_) => {
             // Flow control - ignore for now
             debug!("Received FlowWindowUpdate (not implemented)");
         }
     }
-    Ok(())
+    Ok(None)
 }
 
-/// Process a complete request and stream response back
+/// A request as it is forwarded, less its body.
+struct RequestHead {
+    method: String,
+    uri: String,
+    headers: Vec<Header>,
+}
+
+/// Forward a request to the local server, its body following as it
+/// arrives, and stream the response back.
 fn process_request(
     stream_id: u32,
-    connection_id: u64,
-    local_addr: SocketAddr,
+    upstream: &Upstream,
+    request: RequestHead,
+    body: RequestBody,
+    max_chunk_size: usize,
     writer: PriorityWriter,
     streams: Arc<Mutex<HashMap<u32, StreamState>>>,
-    msg_seq_counter: Arc<AtomicU32>,
 ) -> Result<()> {
-    // Extract request data
-    let request = {
-        let mut streams = streams.lock().unwrap();
-        if let Some(state) = streams.get_mut(&stream_id) {
-            if let StreamType::Http { pending_request } = &mut state.stream_type {
-                pending_request.take()
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    };
-
-    let Some(request) = request else {
-        return Ok(());
-    };
-
-    // Concatenate body chunks
-    let body: Vec<u8> = request.body_chunks.into_iter().flatten().collect();
-
-    // Forward to local server and stream back
-    let result = forward_to_local_streaming(
-        local_addr,
+    // Forward to local server and stream back; interim responses go out
+    // as they arrive, ahead of the final one
+    let result = upstream.forward(
         &request.method,
         &request.uri,
         request.headers,
         body,
+        &mut |interim: InterimResponse| {
+            debug!("Stream {}: interim response {}", stream_id, interim.status);
+            let _ = writer.send_meta(stream_id, response_interim(interim.status, interim.headers));
+        },
     );
 
     match result {
-        Ok(mut resp) => {
-            let status = resp.status().as_u16();
-            let resp_headers: Vec<(String, String)> = resp
-                .headers()
-                .iter()
-                .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
-                .collect();
-
-            // Send response init immediately — META priority so it jumps ahead of body chunks
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_init = encode_response_init(
-                connection_id,
-                stream_id,
-                msg_seq,
-                status,
-                &resp_headers,
-                true, // assume body exists, ResponseEnd will close it
-            );
-            writer.send_meta(WsMessage::Binary(response_init.into()))?;
-
-            info!(
-                "Stream {}: {} {} -> {}",
-                stream_id, request.method, request.uri, status
-            );
-
-            // Stream body chunks — BODY priority (lowest)
-            let mut reader = resp.body_mut().as_reader();
-            let mut buf = [0u8; 16384];
-            let mut chunk_seq: u32 = 0;
-
-            loop {
-                match reader.read(&mut buf) {
-                    Ok(0) => break, // EOF
-                    Ok(n) => {
-                        let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-                        let body_chunk = encode_response_body_chunk(
-                            connection_id,
-                            stream_id,
-                            msg_seq,
-                            &buf[..n],
-                            chunk_seq,
-                            false,
-                        );
-                        writer.send_body(WsMessage::Binary(body_chunk.into()))?;
-                        chunk_seq += 1;
-                    }
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        // Non-blocking read returned no data, yield briefly
-                        thread::sleep(Duration::from_millis(1));
-                        continue;
-                    }
-                    Err(e) => {
-                        warn!("Stream {}: Error reading response body: {}", stream_id, e);
-                        break;
-                    }
-                }
-            }
-
-            // Send response end — BODY priority so it's sent after all body chunks
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_end = encode_response_end(connection_id, stream_id, msg_seq);
-            writer.send_body(WsMessage::Binary(response_end.into()))?;
-        }
+        Ok(resp) => forward_response(
+            stream_id,
+            &request.method,
+            &request.uri,
+            resp,
+            max_chunk_size,
+            &writer,
+        )?,
+        // Aborted while its body was arriving; nobody is waiting for an
+        // answer
+        Err(_) if !streams.lock().unwrap().contains_key(&stream_id) => return Ok(()),
         Err(e) => {
             // Send error response
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_init =
-                encode_response_init(connection_id, stream_id, msg_seq, 502, &[], true);
-            writer.send_meta(WsMessage::Binary(response_init.into()))?;
-
             let error_body = format!("Bad Gateway: {}", e);
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let body_chunk = encode_response_body_chunk(
-                connection_id,
+            writer.send_meta(
                 stream_id,
-                msg_seq,
-                error_body.as_bytes(),
-                0,
-                true,
-            );
-            writer.send_body(WsMessage::Binary(body_chunk.into()))?;
+                response_init(
+                    502,
+                    HttpVersion::H1,
+                    Vec::new(),
+                    true,
+                    error_body.len() as u64,
+                ),
+            )?;
+
+            writer.send_body(
+                stream_id,
+                response_body_chunk(Bytes::from(error_body), 0, true),
+            )?;
 
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_end = encode_response_end(connection_id, stream_id, msg_seq);
-            writer.send_body(WsMessage::Binary(response_end.into()))?;
+            writer.send_body(stream_id, response_end())?;
 
             warn!(
                 "Stream {}: {} {} -> 502 ({})",
@@ -810,272 +2165,504 @@ fn process_request(
     Ok(())
 }
 
+/// Stream a local server's response back through the tunnel.
+fn forward_response(
+    stream_id: u32,
+    method: &str,
+    uri: &str,
+    mut resp: UpstreamResponse,
+    max_chunk_size: usize,
+    writer: &PriorityWriter,
+) -> Result<()> {
+    let status = resp.status;
+    let (has_body, content_length) = response_body_length(method, status, &resp.headers);
+
+    // Send response init immediately — META priority so it jumps ahead of body chunks
+    writer.send_meta(
+        stream_id,
+        response_init(
+            status,
+            resp.version,
+            std::mem::take(&mut resp.headers),
+            has_body,
+            content_length.unwrap_or(0),
+        ),
+    )?;
+
+    info!("Stream {}: {} {} -> {}", stream_id, method, uri, status);
+
+    // Stream body chunks — BODY priority (lowest). The body is read ahead
+    // of the tunnel, so a slow client doesn't hold the local server up
+    let mut body: Box<dyn UpstreamBody> = if has_body {
+        Box::new(ResponseSpool::start(stream_id, resp.body)?)
+    } else {
+        resp.body
+    };
+    let reader = &mut body;
+    let mut sizer = ChunkSizer::new(max_chunk_size);
+    // Filled bytes are split off and the rest is read into next time, so
+    // only what was handed off is ever zeroed again
+    let mut buf = BytesMut::new();
+    let mut chunk_seq: u32 = 0;
+    let mut sent: u64 = 0;
+    let mut aborted = false;
+    // Whatever follows a response that has no body is not forwarded
+    let mut complete = !has_body;
+
+    while !complete {
+        let size = sizer.next_size(writer.has_interactive());
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        match reader.read(&mut buf[..size]) {
+            Ok(0) => {
+                complete = true;
+                break;
+            }
+            Ok(n) => {
+                // Hand the filled bytes off without copying; the
+                // rest of the allocation backs the next read
+                let data = buf.split_to(n).freeze();
+                // Blocks while the stream is over its outbound budget,
+                // which in turn stops us reading from the local server
+                let send_started = Instant::now();
+                match writer.send_body(stream_id, response_body_chunk(data, chunk_seq, false)) {
+                    Ok(()) => sizer.record(n, send_started.elapsed()),
+                    Err(WriteError::Overload) => {
+                        warn!("Stream {}: tunnel too slow, aborting response", stream_id);
+                        writer.send_meta(
+                            stream_id,
+                            response_abort(AbortReason::Overload, "Outbound queue full"),
+                        )?;
+                        aborted = true;
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                chunk_seq += 1;
+                sent += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // Non-blocking read returned no data, yield briefly
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            Err(e) => {
+                warn!("Stream {}: Error reading response body: {}", stream_id, e);
+                writer.send_body(
+                    stream_id,
+                    response_abort(AbortReason::ResetByPeer, "Local server response failed"),
+                )?;
+                aborted = true;
+                break;
+            }
+        }
+    }
+
+    // A body cut short must not look complete to the client
+    if has_body
+        && complete
+        && let Some(expected) = content_length
+        && sent != expected
+    {
+        warn!(
+            "Stream {}: local server sent {} of {} body bytes",
+            stream_id, sent, expected
+        );
+        writer.send_body(
+            stream_id,
+            response_abort(AbortReason::ProtocolError, "Response body length mismatch"),
+        )?;
+        complete = false;
+        aborted = true;
+    }
+
+    // Trailers only follow a body that was read to the end
+    if complete {
+        match body.trailers() {
+            Ok(trailers) if !trailers.is_empty() => {
+                writer.send_body(stream_id, response_trailers(trailers))?;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Stream {}: Error reading trailers: {}", stream_id, e),
+        }
+    }
+
+    // Send response end — BODY priority so it's sent after all body chunks
+    if !aborted {
+        writer.send_body(stream_id, response_end())?;
+    }
+
+    Ok(())
+}
+
 // =============================================================================
 // WebSocket Handling
 // =============================================================================
 
+/// Close code for a WebSocket dropped because the tunnel couldn't keep up
+/// (RFC 6455 registry: "Try Again Later").
+const WS_CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// Close code for a local server that broke the WebSocket protocol.
+const WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
 /// Handle WebSocket upgrade request - connect to local WS server and start proxying
-#[allow(clippy::too_many_arguments)]
 fn handle_websocket_upgrade(
     stream_id: u32,
-    connection_id: u64,
-    local_addr: SocketAddr,
+    upstream: &Upstream,
     uri: &str,
-    headers: &[(String, String)],
+    headers: &[Header],
+    max_chunk_size: usize,
     writer: PriorityWriter,
     streams: Arc<Mutex<HashMap<u32, StreamState>>>,
-    msg_seq_counter: Arc<AtomicU32>,
 ) -> Result<()> {
-    // Build local WebSocket URL
-    let local_url = format!("ws://{}{}", local_addr, uri);
-
-    // Build WebSocket request with forwarded headers
-    let mut request = http::Request::builder()
-        .uri(&local_url)
-        .header(
-            "Sec-WebSocket-Key",
-            tungstenite::handshake::client::generate_key(),
-        )
-        .header("Sec-WebSocket-Version", "13")
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Host", format!("{}", local_addr));
-
-    // Forward relevant headers
-    for (name, value) in headers {
-        let name_lower = name.to_lowercase();
-        // Forward protocol negotiation headers
-        if name_lower == "sec-websocket-protocol"
-            || name_lower == "sec-websocket-extensions"
-            || name_lower == "origin"
+    // The handshake is the CLI's own; the client's is with the edge.
+    // Everything else the client sent goes on, filtered as for any request.
+    let key = tungstenite::handshake::client::generate_key();
+    let mut request_headers = Vec::with_capacity(headers.len() + 3);
+    request_headers.extend([
+        Header {
+            name: "Upgrade".to_string(),
+            value: Bytes::from_static(b"websocket"),
+        },
+        Header {
+            name: "Sec-WebSocket-Key".to_string(),
+            value: Bytes::from(key.clone()),
+        },
+        Header {
+            name: "Sec-WebSocket-Version".to_string(),
+            value: Bytes::from_static(b"13"),
+        },
+    ]);
+    for header in headers {
+        let name = header.name.as_str();
+        if name.eq_ignore_ascii_case("sec-websocket-key")
+            || name.eq_ignore_ascii_case("sec-websocket-version")
         {
-            request = request.header(name.as_str(), value.as_str());
+            continue;
+        }
+        if name.eq_ignore_ascii_case("sec-websocket-extensions") {
+            if let Some(offer) = deflate::offer(&header.value) {
+                request_headers.push(Header {
+                    name: header.name.clone(),
+                    value: offer,
+                });
+            }
+            continue;
         }
+        request_headers.push(header.clone());
     }
 
-    let request = request
-        .body(())
-        .context("Failed to build WebSocket request")?;
-
     // Connect to local WebSocket server
-    let local_ws_result = connect(request);
+    let result = upstream
+        .upgrade(
+            "GET",
+            uri,
+            &request_headers,
+            &mut |interim: InterimResponse| {
+                let _ =
+                    writer.send_meta(stream_id, response_interim(interim.status, interim.headers));
+            },
+        )
+        .and_then(|upgrade| match upgrade {
+            Upgrade::Switched { headers, conn } => {
+                let inflater = accept_websocket(&key, &headers)?;
+                Ok(Ok((headers, conn, inflater)))
+            }
+            Upgrade::Refused(resp) => Ok(Err(resp)),
+        });
 
-    match local_ws_result {
-        Ok((local_ws, response)) => {
-            info!(
-                "Stream {}: Connected to local WebSocket server (status: {})",
-                stream_id,
-                response.status()
+    let (response_headers, conn, mut inflater) = match result {
+        Ok(Ok(switched)) => switched,
+        Ok(Err(resp)) => {
+            // The client sees the WebSocket fail; the server's answer is
+            // still forwarded as sent
+            return forward_response(stream_id, "GET", uri, resp, max_chunk_size, &writer);
+        }
+        Err(e) => {
+            // Failed to connect to local WebSocket server
+            warn!(
+                "Stream {}: Failed to connect to local WebSocket: {:#}",
+                stream_id, e
             );
 
-            // Send successful upgrade response to server
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_headers: Vec<(String, String)> = response
-                .headers()
-                .iter()
-                .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
-                .collect();
-
-            let response_init = encode_response_init(
-                connection_id,
+            // Send error response
+            let error_body = format!("Failed to connect to local WebSocket server: {:#}", e);
+            writer.send_meta(
                 stream_id,
-                msg_seq,
+                response_init(
+                    502, // Bad Gateway
+                    HttpVersion::H1,
+                    Vec::new(),
+                    true,
+                    error_body.len() as u64,
+                ),
+            )?;
+            writer.send_body(
+                stream_id,
+                response_body_chunk(Bytes::from(error_body), 0, true),
+            )?;
+
+            writer.send_body(stream_id, response_end())?;
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Stream {}: Connected to local WebSocket server{}",
+        stream_id,
+        if inflater.is_some() {
+            " (permessage-deflate)"
+        } else {
+            ""
+        }
+    );
+
+    // Send successful upgrade response to server. The extensions stay
+    // between the CLI and the local server.
+    let response_headers = response_headers
+        .into_iter()
+        .filter(|header| !header.name.eq_ignore_ascii_case("sec-websocket-extensions"))
+        .collect();
+
+    writer
+        .send_meta(
+            stream_id,
+            response_init(
                 101, // Switching Protocols
-                &response_headers,
+                HttpVersion::H1,
+                response_headers,
                 false,
-            );
-            writer
-                .send_meta(WsMessage::Binary(response_init.into()))
-                .context("Failed to send WS upgrade response")?;
+                0,
+            ),
+        )
+        .context("Failed to send WS upgrade response")?;
 
-            // Create channel for sending messages to local WebSocket
-            let (local_tx, local_rx) = mpsc::channel::<WsMessage>();
+    // Create channel for sending frames to local WebSocket
+    let (local_tx, local_rx) = mpsc::channel::<Frame>();
 
-            // Store WebSocket state
-            {
-                let mut streams_guard = streams.lock().unwrap();
-                streams_guard.insert(
-                    stream_id,
-                    StreamState {
-                        stream_type: StreamType::WebSocket {
-                            local_ws: LocalWebSocket {
-                                write_tx: local_tx,
-                                stream_id,
-                            },
-                        },
+    // Store WebSocket state
+    {
+        let mut streams_guard = streams.lock().unwrap();
+        streams_guard.insert(
+            stream_id,
+            StreamState {
+                stream_type: StreamType::WebSocket {
+                    local_ws: LocalWebSocket {
+                        write_tx: local_tx,
+                        stream_id,
                     },
-                );
+                },
+            },
+        );
+    }
+
+    // Single IO thread owns the local WebSocket: it drains outbound frames
+    // from the channel, then polls for inbound frames with a short read
+    // timeout, so neither direction can starve the other. A timed-out read
+    // leaves any partial frame, or TLS record, buffered for the next one.
+    let leftover = conn.buffer().to_vec();
+    let socket = conn.into_inner();
+    if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(10))) {
+        warn!(
+            "Stream {}: Failed to set local WS read timeout: {}",
+            stream_id, e
+        );
+    }
+
+    // Frames go through as they are, fragments included, so the local
+    // server sees the client's messages exactly as the relay got them
+    let mut local_ws = FrameSocket::from_partially_read(socket, leftover);
+    // Whether the message in progress, if any, is compressed
+    let mut message: Option<bool> = None;
+
+    // Run the IO loop on this worker until the socket closes
+    'io: loop {
+        // Forward pending client frames to the local server
+        loop {
+            match local_rx.try_recv() {
+                Ok(frame) => {
+                    let is_close =
+                        matches!(frame.header().opcode, OpCode::Control(WsControl::Close));
+                    if local_ws.send(frame).is_err() || is_close {
+                        break 'io;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => break 'io,
             }
+        }
 
-            // Single IO thread owns the local WebSocket (tungstenite sockets
-            // can't be split): it drains outbound messages from the channel,
-            // then polls for inbound frames with a short read timeout, so
-            // neither direction can starve the other. The local connection is
-            // always plain TCP (ws://), so a short timeout is safe here.
-            if let Err(e) = set_read_timeout(&local_ws, Some(Duration::from_millis(10))) {
-                warn!(
-                    "Stream {}: Failed to set local WS read timeout: {}",
-                    stream_id, e
+        // Poll for one inbound frame from the local server
+        let frame = match local_ws.read(None) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue;
+            }
+            Err(e) => {
+                debug!("Stream {}: Local WebSocket failed: {}", stream_id, e);
+                break;
+            }
+        };
+
+        let header = frame.header().clone();
+        let out = match header.opcode {
+            OpCode::Control(WsControl::Ping) => {
+                // Workers can't ping the client, so the CLI answers
+                if local_ws
+                    .send(client_frame(Frame::pong(frame.into_payload())))
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            OpCode::Control(WsControl::Pong) => continue,
+            OpCode::Control(WsControl::Close) => {
+                let payload = frame.payload();
+                let (code, reason) = match payload {
+                    [high, low, reason @ ..] => (
+                        Some(u16::from_be_bytes([*high, *low])),
+                        Bytes::copy_from_slice(reason),
+                    ),
+                    _ => (None, Bytes::new()),
+                };
+                // Answer the close, as the relay's own reply comes too late
+                let _ = local_ws.send(client_frame(Frame::from_payload(
+                    FrameHeader::default(),
+                    close_payload(code, &[]),
+                )));
+                ws_frame(WebSocketOpcode::Close, true, reason, code)
+            }
+            OpCode::Data(data) => match read_data_frame(
+                &mut message,
+                inflater.as_mut(),
+                &header,
+                data,
+                frame.payload(),
+            ) {
+                Ok(out) => out,
+                Err(e) => {
+                    warn!(
+                        "Stream {}: Local WebSocket protocol error: {}",
+                        stream_id, e
+                    );
+                    let _ = local_ws.send(client_frame(Frame::from_payload(
+                        FrameHeader::default(),
+                        close_payload(Some(WS_CLOSE_PROTOCOL_ERROR), e.as_bytes()),
+                    )));
+                    ws_frame(
+                        WebSocketOpcode::Close,
+                        true,
+                        Bytes::from_static(b"Local server protocol error"),
+                        Some(WS_CLOSE_PROTOCOL_ERROR),
+                    )
+                }
+            },
+            OpCode::Control(WsControl::Reserved(_)) => continue,
+        };
+
+        let is_close = matches!(&out, Payload::Ws(frame) if frame.opcode == WebSocketOpcode::Close);
+        match writer.send_ws(stream_id, out) {
+            Ok(()) => {}
+            Err(WriteError::Overload) => {
+                warn!("Stream {}: tunnel too slow, closing WebSocket", stream_id);
+                let _ = writer.send_meta(
+                    stream_id,
+                    ws_frame(
+                        WebSocketOpcode::Close,
+                        true,
+                        Bytes::from_static(b"Tunnel overloaded"),
+                        Some(WS_CLOSE_TRY_AGAIN_LATER),
+                    ),
                 );
+                let _ = local_ws.send(client_frame(Frame::from_payload(
+                    FrameHeader::default(),
+                    close_payload(Some(WS_CLOSE_TRY_AGAIN_LATER), b"Tunnel overloaded"),
+                )));
+                break;
             }
+            Err(WriteError::Closed) => break,
+        }
 
-            let writer_clone = writer.clone();
-            let msg_seq_counter_clone = msg_seq_counter.clone();
-            let streams_clone = streams.clone();
-            let mut local_ws = local_ws;
-
-            thread::spawn(move || {
-                'io: loop {
-                    // Forward pending client frames to the local server
-                    loop {
-                        match local_rx.try_recv() {
-                            Ok(msg) => {
-                                let is_close = matches!(msg, WsMessage::Close(_));
-                                if local_ws.send(msg).is_err() || is_close {
-                                    break 'io;
-                                }
-                            }
-                            Err(mpsc::TryRecvError::Empty) => break,
-                            Err(mpsc::TryRecvError::Disconnected) => break 'io,
-                        }
-                    }
+        if is_close {
+            break;
+        }
+    }
 
-                    // Poll for one inbound frame from the local server
-                    let msg = match local_ws.read() {
-                        Ok(msg) => msg,
-                        Err(tungstenite::Error::Io(ref e))
-                            if e.kind() == std::io::ErrorKind::WouldBlock
-                                || e.kind() == std::io::ErrorKind::TimedOut =>
-                        {
-                            continue;
-                        }
-                        Err(_) => break,
-                    };
+    // Clean up stream when local WS closes
+    local_ws.get_ref().shutdown();
+    let mut streams_guard = streams.lock().unwrap();
+    streams_guard.remove(&stream_id);
+    debug!("Stream {}: Local WebSocket closed", stream_id);
 
-                    let frame = match &msg {
-                        WsMessage::Text(text) => {
-                            let msg_seq = msg_seq_counter_clone.fetch_add(1, Ordering::SeqCst);
-                            encode_ws_frame(
-                                connection_id,
-                                stream_id,
-                                msg_seq,
-                                WebSocketOpcode::Text,
-                                text.as_bytes(),
-                                None,
-                            )
-                        }
-                        WsMessage::Binary(data) => {
-                            let msg_seq = msg_seq_counter_clone.fetch_add(1, Ordering::SeqCst);
-                            encode_ws_frame(
-                                connection_id,
-                                stream_id,
-                                msg_seq,
-                                WebSocketOpcode::Binary,
-                                data,
-                                None,
-                            )
-                        }
-                        WsMessage::Ping(data) => {
-                            let msg_seq = msg_seq_counter_clone.fetch_add(1, Ordering::SeqCst);
-                            encode_ws_frame(
-                                connection_id,
-                                stream_id,
-                                msg_seq,
-                                WebSocketOpcode::Ping,
-                                data,
-                                None,
-                            )
-                        }
-                        WsMessage::Pong(data) => {
-                            let msg_seq = msg_seq_counter_clone.fetch_add(1, Ordering::SeqCst);
-                            encode_ws_frame(
-                                connection_id,
-                                stream_id,
-                                msg_seq,
-                                WebSocketOpcode::Pong,
-                                data,
-                                None,
-                            )
-                        }
-                        WsMessage::Close(frame) => {
-                            let msg_seq = msg_seq_counter_clone.fetch_add(1, Ordering::SeqCst);
-                            let code = frame.as_ref().map(|f| f.code.into()).unwrap_or(1000u16);
-                            encode_ws_frame(
-                                connection_id,
-                                stream_id,
-                                msg_seq,
-                                WebSocketOpcode::Close,
-                                &[],
-                                Some(code),
-                            )
-                        }
-                        WsMessage::Frame(_) => continue, // Raw frames, skip
-                    };
+    Ok(())
+}
 
-                    if writer_clone
-                        .send_meta(WsMessage::Binary(frame.into()))
-                        .is_err()
-                    {
-                        break;
-                    }
+/// Check the local server's 101 against the handshake the CLI started.
+fn accept_websocket(key: &str, headers: &[Header]) -> Result<Option<Inflater>> {
+    let accept = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("sec-websocket-accept"))
+        .context("Local server's 101 has no Sec-WebSocket-Accept")?;
+    if accept.value != tungstenite::handshake::derive_accept_key(key.as_bytes()).as_bytes() {
+        bail!("Local server's Sec-WebSocket-Accept doesn't match the key");
+    }
+    deflate::accept(headers)
+}
 
-                    if matches!(msg, WsMessage::Close(_)) {
-                        break;
-                    }
-                }
+/// Turn a data frame from the local server into one for the tunnel, keeping
+/// its place in the message. Compressed frames are inflated on the way.
+fn read_data_frame(
+    message: &mut Option<bool>,
+    inflater: Option<&mut Inflater>,
+    header: &FrameHeader,
+    data: WsData,
+    payload: &[u8],
+) -> Result<Payload, String> {
+    if header.mask.is_some() {
+        return Err("masked frame from server".to_string());
+    }
+    if header.rsv2 || header.rsv3 {
+        return Err("reserved bits set".to_string());
+    }
 
-                // Clean up stream when local WS closes
-                let mut streams_guard = streams_clone.lock().unwrap();
-                streams_guard.remove(&stream_id);
-                debug!("Stream {}: Local WebSocket closed", stream_id);
-            });
+    let (opcode, compressed) = match (data, *message) {
+        (WsData::Continue, Some(compressed)) => {
+            if header.rsv1 {
+                return Err("RSV1 set on a continuation frame".to_string());
+            }
+            (WebSocketOpcode::Continuation, compressed)
         }
-        Err(e) => {
-            // Failed to connect to local WebSocket server
-            warn!(
-                "Stream {}: Failed to connect to local WebSocket: {}",
-                stream_id, e
-            );
-
-            // Send error response
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_init = encode_response_init(
-                connection_id,
-                stream_id,
-                msg_seq,
-                502, // Bad Gateway
-                &[],
-                true,
-            );
-            writer.send_meta(WsMessage::Binary(response_init.into()))?;
+        (WsData::Continue, None) => return Err("continuation without a message".to_string()),
+        (_, Some(_)) => return Err("new message before the last one ended".to_string()),
+        (WsData::Text, None) => (WebSocketOpcode::Text, header.rsv1),
+        (WsData::Binary, None) => (WebSocketOpcode::Binary, header.rsv1),
+        (WsData::Reserved(op), None) => return Err(format!("reserved opcode {}", op)),
+    };
 
-            let error_body = format!("Failed to connect to local WebSocket server: {}", e);
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let body_chunk = encode_response_body_chunk(
-                connection_id,
-                stream_id,
-                msg_seq,
-                error_body.as_bytes(),
-                0,
-                true,
-            );
-            writer.send_body(WsMessage::Binary(body_chunk.into()))?;
+    let payload = match (compressed, inflater) {
+        (false, _) => Bytes::copy_from_slice(payload),
+        (true, Some(inflater)) => inflater
+            .inflate(payload, header.is_final)
+            .map_err(|e| e.to_string())?,
+        (true, None) => return Err("RSV1 set without permessage-deflate".to_string()),
+    };
 
-            let msg_seq = msg_seq_counter.fetch_add(1, Ordering::SeqCst);
-            let response_end = encode_response_end(connection_id, stream_id, msg_seq);
-            writer.send_body(WsMessage::Binary(response_end.into()))?;
-        }
+    if header.is_final {
+        *message = None;
+    } else {
+        *message = Some(compressed);
     }
-
-    Ok(())
+    Ok(ws_frame(opcode, header.is_final, payload, None))
 }
 
-/// Handle WebSocket frame from server (forward to local WebSocket)
-fn handle_ws_frame(
-    stream_id: u32,
-    frame: WebSocketFrame,
-    streams: &mut HashMap<u32, StreamState>,
-) {
+fn handle_ws_frame(stream_id: u32, frame: WebSocketFrame, streams: &mut HashMap<u32, StreamState>) {
     let Some(state) = streams.get(&stream_id) else {
         debug!("Stream {}: No stream found for WS frame", stream_id);
         return;
@@ -1086,82 +2673,58 @@ fn handle_ws_frame(
         return;
     };
 
-    let msg = match frame.opcode {
-        WebSocketOpcode::Text => match String::from_utf8(frame.payload.to_vec()) {
-            Ok(text) => WsMessage::Text(text.into()),
-            Err(_) => {
-                debug!("Stream {}: Invalid UTF-8 in text frame", stream_id);
-                return;
-            }
-        },
-        WebSocketOpcode::Binary => WsMessage::Binary(frame.payload),
-        WebSocketOpcode::Close => WsMessage::Close(frame.close_code.map(|code| CloseFrame {
-            code: code.into(),
-            reason: "".into(),
-        })),
-        WebSocketOpcode::Ping => WsMessage::Ping(frame.payload),
-        WebSocketOpcode::Pong => WsMessage::Pong(frame.payload),
-        WebSocketOpcode::Continuation => {
-            debug!("Stream {}: Continuation frames are not supported", stream_id);
-            return;
-        }
+    let (opcode, payload) = match frame.opcode {
+        WebSocketOpcode::Continuation => (OpCode::Data(WsData::Continue), frame.payload),
+        WebSocketOpcode::Text => (OpCode::Data(WsData::Text), frame.payload),
+        WebSocketOpcode::Binary => (OpCode::Data(WsData::Binary), frame.payload),
+        WebSocketOpcode::Close => (
+            OpCode::Control(WsControl::Close),
+            close_payload(frame.close_code, &frame.payload),
+        ),
+        WebSocketOpcode::Ping => (OpCode::Control(WsControl::Ping), frame.payload),
+        WebSocketOpcode::Pong => (OpCode::Control(WsControl::Pong), frame.payload),
+    };
+    let header = FrameHeader {
+        is_final: frame.fin,
+        rsv1: frame.rsv1,
+        rsv2: frame.rsv2,
+        rsv3: frame.rsv3,
+        opcode,
+        mask: None,
     };
 
-    if local_ws.write_tx.send(msg).is_err() {
+    if local_ws
+        .write_tx
+        .send(client_frame(Frame::from_payload(header, payload)))
+        .is_err()
+    {
         debug!("Stream {}: Failed to send to local WebSocket", stream_id);
     }
 }
 
-/// Forward request to local server, returning the response for streaming.
-///
-/// Does NOT read the response body — the caller streams it in chunks.
-fn forward_to_local_streaming(
-    local_addr: SocketAddr,
-    method: &str,
-    uri: &str,
-    headers: Vec<(String, String)>,
-    body: Vec<u8>,
-) -> Result<http::Response<ureq::Body>> {
-    let url = format!("http://{}{}", local_addr, uri);
-
-    // No global timeout — streaming responses (SSE) can last indefinitely.
-    // Individual read timeouts are handled at the chunk-read level.
-    let agent = crate::http_client::agent();
-
-    // Build an http::Request manually so we can handle any method uniformly
-    let mut builder = http::Request::builder().method(method).uri(&url);
-
-    for (name, value) in &headers {
-        // Skip hop-by-hop headers and Accept-Encoding
-        let name_lower = name.to_lowercase();
-        if name_lower == "host"
-            || name_lower == "connection"
-            || name_lower == "upgrade"
-            || name_lower == "transfer-encoding"
-            || name_lower == "accept-encoding"
-        {
-            continue;
-        }
-        builder = builder.header(name.as_str(), value.as_str());
-    }
-
-    // Override Accept-Encoding to prevent local server from compressing.
-    // Cloudflare's edge will handle compression for the client.
-    builder = builder.header("Accept-Encoding", "identity");
+/// Mask a frame for the local server, as every client frame must be.
+fn client_frame(mut frame: Frame) -> Frame {
+    frame.header_mut().mask = Some(fastrand::u32(..).to_ne_bytes());
+    frame
+}
 
-    let resp = if !body.is_empty() {
-        let request = builder.body(body).context("Failed to build request")?;
-        agent
-            .run(request)
-            .context("Failed to forward request to local server")?
-    } else {
-        let request = builder.body(()).context("Failed to build request")?;
-        agent
-            .run(request)
-            .context("Failed to forward request to local server")?
+/// A close frame's payload: the code, then as much of the reason as fits.
+/// Codes that only describe the closing side's view (no code, abnormal
+/// closure, TLS failure) must not be sent, so they close without one.
+fn close_payload(code: Option<u16>, reason: &[u8]) -> Bytes {
+    let code = match code {
+        Some(code) if !matches!(code, 1005 | 1006 | 1015) => code,
+        _ => return Bytes::new(),
     };
-
-    Ok(resp)
+    // Control frames carry 125 bytes; keep whole characters
+    let mut end = reason.len().min(123);
+    while end < reason.len() && end > 0 && reason[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    let mut payload = BytesMut::with_capacity(2 + end);
+    payload.extend_from_slice(&code.to_be_bytes());
+    payload.extend_from_slice(&reason[..end]);
+    payload.freeze()
 }
 
 // =============================================================================
@@ -1175,113 +2738,429 @@ fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
-fn encode_envelope(connection_id: u64, stream_id: u32, msg_seq: u32, payload: Payload) -> Vec<u8> {
-    let envelope = Envelope {
+fn response_init(
+    status: u16,
+    version: HttpVersion,
+    headers: Vec<Header>,
+    has_body: bool,
+    content_length: u64,
+) -> Payload {
+    Payload::Http(HttpMessage::ResponseInit(HttpResponseInit {
         timestamp_ms: now_ms(),
-        connection_id,
-        stream_id,
-        msg_seq,
-        payload,
-    };
-    envelope.encode().expect("failed to encode envelope")
+        status,
+        headers,
+        has_body,
+        version,
+        content_length,
+    }))
 }
 
-fn encode_response_init(
-    connection_id: u64,
-    stream_id: u32,
-    msg_seq: u32,
-    status: u16,
-    headers: &[(String, String)],
-    has_body: bool,
-) -> Vec<u8> {
-    let headers = headers
+/// Whether a response carries a body, and its length if the server declared
+/// one. HEAD requests and 1xx, 204 and 304 responses never have a body.
+fn response_body_length(method: &str, status: u16, headers: &[Header]) -> (bool, Option<u64>) {
+    if method.eq_ignore_ascii_case("HEAD") || status < 200 || status == 204 || status == 304 {
+        return (false, None);
+    }
+    let content_length = headers
         .iter()
-        .map(|(name, value)| Header {
-            name: name.clone(),
-            value: Bytes::copy_from_slice(value.as_bytes()),
-        })
-        .collect();
-    encode_envelope(
-        connection_id,
-        stream_id,
-        msg_seq,
-        Payload::Http(HttpMessage::ResponseInit(HttpResponseInit {
-            timestamp_ms: now_ms(),
-            status,
-            headers,
-            has_body,
-            content_length: 0,
-        })),
-    )
+        .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+        .and_then(|header| std::str::from_utf8(&header.value).ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    (content_length != Some(0), content_length)
 }
 
-fn encode_response_body_chunk(
-    connection_id: u64,
-    stream_id: u32,
-    msg_seq: u32,
-    data: &[u8],
-    seq: u32,
-    is_last: bool,
-) -> Vec<u8> {
-    encode_envelope(
-        connection_id,
-        stream_id,
-        msg_seq,
-        Payload::Http(HttpMessage::ResponseBodyChunk(HttpBodyChunk {
-            timestamp_ms: now_ms(),
-            data: Bytes::copy_from_slice(data),
-            seq,
-            is_last,
-        })),
-    )
+fn response_interim(status: u16, headers: Vec<Header>) -> Payload {
+    Payload::Http(HttpMessage::ResponseInterim(HttpInterimResponse {
+        timestamp_ms: now_ms(),
+        status,
+        headers,
+    }))
 }
 
-fn encode_response_end(connection_id: u64, stream_id: u32, msg_seq: u32) -> Vec<u8> {
-    encode_envelope(
-        connection_id,
-        stream_id,
-        msg_seq,
-        Payload::Http(HttpMessage::ResponseEnd(HttpResponseEnd {
-            timestamp_ms: now_ms(),
-        })),
-    )
+fn response_body_chunk(data: Bytes, seq: u32, is_last: bool) -> Payload {
+    Payload::Http(HttpMessage::ResponseBodyChunk(HttpBodyChunk {
+        timestamp_ms: now_ms(),
+        data,
+        seq,
+        is_last,
+    }))
 }
 
-fn encode_control_pong(connection_id: u64, data: &Bytes) -> Vec<u8> {
-    encode_envelope(
-        connection_id,
-        0,
-        0,
-        Payload::Control(Control::Pong(Pong {
-            timestamp_ms: now_ms(),
-            data: data.clone(),
-        })),
-    )
+fn response_trailers(headers: Vec<Header>) -> Payload {
+    Payload::Http(HttpMessage::ResponseTrailers(HttpTrailers {
+        timestamp_ms: now_ms(),
+        headers,
+    }))
 }
 
-fn encode_ws_frame(
-    connection_id: u64,
-    stream_id: u32,
-    msg_seq: u32,
+fn response_end() -> Payload {
+    Payload::Http(HttpMessage::ResponseEnd(HttpResponseEnd {
+        timestamp_ms: now_ms(),
+    }))
+}
+
+fn response_abort(reason: AbortReason, detail: &str) -> Payload {
+    Payload::Http(HttpMessage::ResponseAbort(HttpResponseAbort {
+        timestamp_ms: now_ms(),
+        reason,
+        detail: detail.to_string(),
+    }))
+}
+
+fn control_ping(data: Bytes) -> Payload {
+    Payload::Control(Control::Ping(Ping {
+        timestamp_ms: now_ms(),
+        data,
+    }))
+}
+
+fn control_pong(data: &Bytes) -> Payload {
+    Payload::Control(Control::Pong(Pong {
+        timestamp_ms: now_ms(),
+        data: data.clone(),
+    }))
+}
+
+fn control_ack(last_msg_seq: u32) -> Payload {
+    Payload::Control(Control::Ack(Ack {
+        timestamp_ms: now_ms(),
+        last_msg_seq,
+    }))
+}
+
+fn ws_frame(
     opcode: WebSocketOpcode,
-    payload: &[u8],
+    fin: bool,
+    payload: Bytes,
     close_code: Option<u16>,
-) -> Vec<u8> {
-    encode_envelope(
-        connection_id,
-        stream_id,
-        msg_seq,
-        Payload::Ws(WebSocketFrame {
-            timestamp_ms: now_ms(),
-            fin: true,
-            rsv1: false,
-            rsv2: false,
-            rsv3: false,
-            opcode,
-            masked: false,
-            mask_key: 0,
-            payload: Bytes::copy_from_slice(payload),
-            close_code,
-        }),
-    )
+) -> Payload {
+    Payload::Ws(WebSocketFrame {
+        timestamp_ms: now_ms(),
+        fin,
+        rsv1: false,
+        rsv2: false,
+        rsv3: false,
+        opcode,
+        masked: false,
+        mask_key: 0,
+        payload,
+        close_code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use dotunnel::transport::message::{Control, Envelope, GoAway, GoAwayCode, Payload};
+    use tungstenite::{Message as WsMessage, WebSocket};
+    use url::Url;
+
+    use super::writer::WritePriority;
+    use super::{
+        control_ack, response_end, run_tunnel, Connection, ConnectionExit, HeaderCase,
+        LivenessConfig, PrioritizedMsg, RelayLink, Session, SessionConfig, StatusBoard, Target,
+        TlsOptions, Transport, Tunnel, TunnelReady, TunnelStatus, Upstream, UpstreamProtocol,
+        WorkerPool, ACK_EVERY, ACK_INTERVAL,
+    };
+
+    fn test_tunnel() -> Tunnel {
+        let tls = TlsOptions {
+            server_name: None,
+            ca_file: None,
+            insecure: false,
+        };
+        let upstream = Upstream::new(
+            Target::parse("http://127.0.0.1:1", None, &tls).unwrap(),
+            UpstreamProtocol::Http1,
+            HeaderCase::Preserve,
+        );
+        Tunnel {
+            service_url: "http://127.0.0.1:1".to_string(),
+            token: String::new(),
+            subdomain: None,
+            connections: 1,
+            transport: Transport::Websocket,
+            websocket_blocked_at: Mutex::new(None),
+            session_config: SessionConfig {
+                upstream,
+                websocket_weight: 1,
+                max_chunk_size: 64 * 1024,
+                liveness: LivenessConfig::default(),
+                workers: WorkerPool::new(4, 4),
+                upgraded: WorkerPool::new(4, 0),
+            },
+            max_reconnect_attempts: None,
+            max_reconnect_duration: None,
+            tunnel_info: Mutex::new(None),
+            announced: AtomicBool::new(false),
+            status: StatusBoard::new(TunnelStatus {
+                pid: 0,
+                profile: String::new(),
+                tunnel_url: String::new(),
+                local_addr: String::new(),
+                connected: false,
+                rtt_ms: None,
+                updated_at: 0,
+            }),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A connected link, and the relay's end of it.
+    fn relay_pair() -> (RelayLink, WebSocket<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let relay = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            tungstenite::accept(stream).unwrap()
+        });
+        let (ws, _) = tungstenite::connect(url).unwrap();
+        (RelayLink::WebSocket(Box::new(ws)), relay.join().unwrap())
+    }
+
+    fn go_away(code: GoAwayCode) -> WsMessage {
+        let envelope = Envelope {
+            timestamp_ms: 0,
+            connection_id: 1,
+            stream_id: 0,
+            msg_seq: 0,
+            payload: Payload::Control(Control::GoAway(GoAway {
+                timestamp_ms: 0,
+                last_msg_seq: 0,
+                reason: "test".to_string(),
+                code,
+            })),
+        };
+        WsMessage::Binary(envelope.encode().unwrap().into())
+    }
+
+    /// Read until the CLI closes the connection; true if it closed cleanly.
+    fn closed_cleanly(relay: &mut WebSocket<TcpStream>) -> bool {
+        relay
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        loop {
+            match relay.read() {
+                Ok(WsMessage::Close(_)) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn run_until_go_away(code: GoAwayCode) -> (ConnectionExit, WebSocket<TcpStream>) {
+        let tunnel = test_tunnel();
+        let (link, mut relay) = relay_pair();
+        relay.send(go_away(code)).unwrap();
+
+        let exit = run_tunnel(link, test_session(&tunnel), &mut None, 0, &tunnel).unwrap();
+        (exit, relay)
+    }
+
+    fn test_session(tunnel: &Tunnel) -> Session {
+        Session::new(
+            &tunnel.session_config,
+            Url::parse("https://test.example").unwrap(),
+        )
+    }
+
+    /// Seal a sequenced message, as the IO loop would before writing it.
+    fn seal(session: &mut Session) -> Bytes {
+        session.seal(PrioritizedMsg {
+            priority: WritePriority::Meta,
+            stream_id: 1,
+            payload: response_end(),
+        })
+    }
+
+    fn replay_seqs(session: &Session) -> Vec<u32> {
+        session.replay.iter().map(|(msg_seq, _)| *msg_seq).collect()
+    }
+
+    /// The Ack `maybe_ack` queued, if any.
+    fn queued_ack(session: &Session) -> Option<u32> {
+        let msg = session.write_rx.try_recv().ok()?;
+        let Payload::Control(Control::Ack(ack)) = msg.payload else {
+            panic!("expected an ack");
+        };
+        Some(ack.last_msg_seq)
+    }
+
+    #[test]
+    fn ack_trims_replay() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        for _ in 0..3 {
+            seal(&mut session);
+        }
+        assert_eq!(replay_seqs(&session), [1, 2, 3]);
+
+        session.acknowledge(2);
+        assert_eq!(replay_seqs(&session), [3]);
+        assert_eq!(session.replay_bytes, session.replay[0].1.len());
+
+        // A stale Ack changes nothing
+        session.acknowledge(1);
+        assert_eq!(replay_seqs(&session), [3]);
+
+        session.acknowledge(3);
+        assert!(session.replay.is_empty());
+        assert_eq!(session.replay_bytes, 0);
+    }
+
+    #[test]
+    fn sealing_copies_nothing() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        let first = seal(&mut session);
+        let second = seal(&mut session);
+
+        // Written where they were serialized, and kept for replay as the
+        // same bytes
+        assert_eq!(second.as_ptr(), first.as_ptr().wrapping_add(first.len()));
+        assert_eq!(session.replay[0].1.as_ptr(), first.as_ptr());
+        assert_eq!(session.replay[1].1.as_ptr(), second.as_ptr());
+    }
+
+    #[test]
+    fn control_is_not_replayed() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        let data = session.seal(PrioritizedMsg {
+            priority: WritePriority::Control,
+            stream_id: 0,
+            payload: control_ack(0),
+        });
+
+        assert_eq!(Envelope::decode(&data).unwrap().msg_seq, 0);
+        assert!(session.replay.is_empty());
+        assert_eq!(seal(&mut session).len(), session.replay_bytes);
+        assert_eq!(replay_seqs(&session), [1]);
+    }
+
+    #[test]
+    fn duplicate_and_out_of_order_inbound_is_rejected() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+
+        assert!(session.accept_inbound(1));
+        assert!(session.accept_inbound(2));
+        // Replayed after a resume
+        assert!(!session.accept_inbound(2));
+        assert!(!session.accept_inbound(1));
+        // Unsequenced control messages always pass
+        assert!(session.accept_inbound(0));
+        assert!(session.accept_inbound(4));
+        assert!(!session.accept_inbound(3));
+
+        assert_eq!(session.last_received_seq, 4);
+        assert_eq!(session.unacked_inbound, 3);
+    }
+
+    #[test]
+    fn restart_forgets_sequence_state() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        session.accept_inbound(5);
+        seal(&mut session);
+        session.resumable = false;
+
+        session.restart(7);
+
+        assert_eq!(session.connection_id, 7);
+        assert!(session.replay.is_empty());
+        assert_eq!(session.replay_bytes, 0);
+        assert!(session.resumable);
+        // The relay's sequence numbers start over
+        assert!(session.accept_inbound(1));
+    }
+
+    #[test]
+    fn acks_every_few_envelopes() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        for msg_seq in 1..ACK_EVERY {
+            session.accept_inbound(msg_seq);
+        }
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), None);
+
+        session.accept_inbound(ACK_EVERY);
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), Some(ACK_EVERY));
+
+        // Nothing new since
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), None);
+    }
+
+    #[test]
+    fn acks_once_the_oldest_is_due() {
+        let tunnel = test_tunnel();
+        let mut session = test_session(&tunnel);
+        session.accept_inbound(1);
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), None);
+
+        session.unacked_since = Instant::now().checked_sub(ACK_INTERVAL);
+        session.hold_acks = true;
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), None);
+
+        session.hold_acks = false;
+        session.maybe_ack();
+        assert_eq!(queued_ack(&session), Some(1));
+    }
+
+    #[test]
+    fn resume_replays_what_the_relay_missed() {
+        let tunnel = test_tunnel();
+        let (link, _old_relay) = relay_pair();
+        let mut conn = Connection::new(link, test_session(&tunnel)).unwrap();
+        for _ in 0..3 {
+            seal(&mut conn.session);
+        }
+
+        let (link, mut relay) = relay_pair();
+        let ready = TunnelReady {
+            connection_id: "1".to_string(),
+            resumed: true,
+            last_msg_seq: 1,
+        };
+        conn.switch_link(link, &ready).unwrap();
+
+        for expected in [2, 3] {
+            let WsMessage::Binary(data) = relay.read().unwrap() else {
+                panic!("expected an envelope");
+            };
+            assert_eq!(Envelope::decode(&data).unwrap().msg_seq, expected);
+        }
+        // Kept until the relay acknowledges them on the new link
+        assert_eq!(replay_seqs(&conn.session), [2, 3]);
+    }
+
+    #[test]
+    fn restart_drains_in_background_and_reconnects() {
+        let (exit, mut relay) = run_until_go_away(GoAwayCode::Restart);
+
+        // The lane opens a replacement straight away...
+        assert!(matches!(exit, ConnectionExit::GoAway));
+        // ...while the old connection drains and closes on its own
+        assert!(closed_cleanly(&mut relay));
+    }
+
+    #[test]
+    fn replaced_drains_and_gives_up_the_lane() {
+        let (exit, mut relay) = run_until_go_away(GoAwayCode::Replaced);
+
+        assert!(matches!(exit, ConnectionExit::Replaced));
+        assert!(closed_cleanly(&mut relay));
+    }
 }
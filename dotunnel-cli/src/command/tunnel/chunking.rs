@@ -0,0 +1,175 @@
+//! Adaptive body chunk sizing.
+//!
+//! Every body chunk costs an envelope on each side of the tunnel, so bulk
+//! transfers want large chunks. A large chunk also sits in front of
+//! everything queued behind it, so while WebSocket traffic is flowing the
+//! size stays small.
+
+use std::time::Duration;
+
+/// Starting size, and the size used while interactive traffic flows.
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Default for `--max-chunk-size`.
+pub(super) const DEFAULT_MAX_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Hard ceiling, well inside the relay's 32 MiB WebSocket message limit.
+const MAX_CHUNK_SIZE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Full reads in a row before the chunk size doubles.
+const GROW_AFTER: u32 = 4;
+
+/// A send that blocked at least this long means the tunnel, not the local
+/// server, is the bottleneck.
+const SEND_STALL: Duration = Duration::from_millis(5);
+
+/// Parse `--max-chunk-size`.
+pub(super) fn parse_max_chunk_size(value: &str) -> Result<usize, String> {
+    let size: usize = value.parse().map_err(|e| format!("{}", e))?;
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE_LIMIT).contains(&size) {
+        return Err(format!(
+            "must be between {} and {} bytes",
+            MIN_CHUNK_SIZE, MAX_CHUNK_SIZE_LIMIT
+        ));
+    }
+    Ok(size)
+}
+
+/// Picks the read size for one response body.
+///
+/// Grows toward the ceiling while the local server keeps filling whole
+/// buffers and the tunnel keeps up; halves when a send stalls on the
+/// outbound budget; drops to the minimum while interactive traffic flows.
+pub(super) struct ChunkSizer {
+    size: usize,
+    max_size: usize,
+    full_reads: u32,
+}
+
+impl ChunkSizer {
+    pub(super) fn new(max_size: usize) -> Self {
+        Self {
+            size: MIN_CHUNK_SIZE,
+            max_size: max_size.max(MIN_CHUNK_SIZE),
+            full_reads: 0,
+        }
+    }
+
+    /// Size of the next read.
+    pub(super) fn next_size(&mut self, interactive: bool) -> usize {
+        if interactive {
+            self.size = MIN_CHUNK_SIZE;
+            self.full_reads = 0;
+        }
+        self.size
+    }
+
+    /// Record a read of `read` bytes whose send took `send_time`.
+    pub(super) fn record(&mut self, read: usize, send_time: Duration) {
+        if send_time >= SEND_STALL {
+            self.size = (self.size / 2).max(MIN_CHUNK_SIZE);
+            self.full_reads = 0;
+            return;
+        }
+
+        if read < self.size {
+            // The local server is the bottleneck; bigger buffers won't help
+            self.full_reads = 0;
+            return;
+        }
+
+        self.full_reads += 1;
+        if self.full_reads >= GROW_AFTER {
+            self.size = (self.size * 2).min(self.max_size);
+            self.full_reads = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        parse_max_chunk_size, ChunkSizer, GROW_AFTER, MAX_CHUNK_SIZE_LIMIT, MIN_CHUNK_SIZE,
+        SEND_STALL,
+    };
+
+    /// Make `reads` full reads, each sent without stalling, and return the
+    /// size of the next one.
+    fn fill(sizer: &mut ChunkSizer, reads: u32) -> usize {
+        for _ in 0..reads {
+            let size = sizer.next_size(false);
+            sizer.record(size, Duration::ZERO);
+        }
+        sizer.next_size(false)
+    }
+
+    #[test]
+    fn grows_after_full_reads() {
+        let mut sizer = ChunkSizer::new(4 * MIN_CHUNK_SIZE);
+        assert_eq!(sizer.next_size(false), MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, GROW_AFTER - 1), MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, 1), 2 * MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, GROW_AFTER), 4 * MIN_CHUNK_SIZE);
+        // Capped at the maximum
+        assert_eq!(fill(&mut sizer, GROW_AFTER), 4 * MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn short_read_restarts_the_count() {
+        let mut sizer = ChunkSizer::new(4 * MIN_CHUNK_SIZE);
+        fill(&mut sizer, GROW_AFTER - 1);
+        sizer.record(MIN_CHUNK_SIZE - 1, Duration::ZERO);
+        assert_eq!(fill(&mut sizer, GROW_AFTER - 1), MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, 1), 2 * MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn halves_on_stall() {
+        let mut sizer = ChunkSizer::new(8 * MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, 3 * GROW_AFTER), 8 * MIN_CHUNK_SIZE);
+
+        sizer.record(8 * MIN_CHUNK_SIZE, SEND_STALL);
+        assert_eq!(sizer.next_size(false), 4 * MIN_CHUNK_SIZE);
+        sizer.record(4 * MIN_CHUNK_SIZE, SEND_STALL);
+        sizer.record(2 * MIN_CHUNK_SIZE, SEND_STALL);
+        sizer.record(MIN_CHUNK_SIZE, SEND_STALL);
+        assert_eq!(sizer.next_size(false), MIN_CHUNK_SIZE);
+
+        // The stall also restarts the count toward growing again
+        assert_eq!(fill(&mut sizer, GROW_AFTER - 1), MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn resets_while_interactive() {
+        let mut sizer = ChunkSizer::new(8 * MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, 2 * GROW_AFTER), 4 * MIN_CHUNK_SIZE);
+        fill(&mut sizer, GROW_AFTER - 1);
+
+        assert_eq!(sizer.next_size(true), MIN_CHUNK_SIZE);
+        // Growing starts over from scratch
+        assert_eq!(fill(&mut sizer, GROW_AFTER - 1), MIN_CHUNK_SIZE);
+        assert_eq!(fill(&mut sizer, 1), 2 * MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn max_below_minimum_is_raised() {
+        let mut sizer = ChunkSizer::new(1);
+        assert_eq!(fill(&mut sizer, GROW_AFTER), MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn max_chunk_size_bounds() {
+        assert_eq!(parse_max_chunk_size("16384"), Ok(MIN_CHUNK_SIZE));
+        assert_eq!(
+            parse_max_chunk_size(&MAX_CHUNK_SIZE_LIMIT.to_string()),
+            Ok(MAX_CHUNK_SIZE_LIMIT)
+        );
+        assert!(parse_max_chunk_size("16383").is_err());
+        assert!(parse_max_chunk_size(&(MAX_CHUNK_SIZE_LIMIT + 1).to_string()).is_err());
+        assert!(parse_max_chunk_size("0").is_err());
+        assert!(parse_max_chunk_size("-1").is_err());
+        assert!(parse_max_chunk_size("1MiB").is_err());
+    }
+}
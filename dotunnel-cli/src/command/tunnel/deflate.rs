@@ -0,0 +1,238 @@
+//! permessage-deflate (RFC 7692) between the CLI and the local server.
+//!
+//! Workers hands the relay whole, uncompressed messages and compresses for
+//! the client on its own, so the extension can't run end to end. Instead the
+//! client's offer goes to the local server, and if the server takes it, the
+//! CLI holds it: it inflates whatever the server compresses, and sends the
+//! client's messages uncompressed, which the extension allows.
+
+use std::io;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use dotunnel::transport::message::Header;
+use flate2::{Decompress, FlushDecompress, Status};
+
+const EXTENSION: &str = "permessage-deflate";
+
+/// Ends every compressed message; senders strip it, receivers put it back.
+const MESSAGE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The client's permessage-deflate offers from a `Sec-WebSocket-Extensions`
+/// value. Any other extension the server agreed to would have nobody on this
+/// side to speak it, so it isn't offered.
+pub(super) fn offer(value: &[u8]) -> Option<Bytes> {
+    let value = std::str::from_utf8(value).ok()?;
+    let offers: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|offer| extension_name(offer).eq_ignore_ascii_case(EXTENSION))
+        .collect();
+    if offers.is_empty() {
+        return None;
+    }
+    Some(Bytes::from(offers.join(", ")))
+}
+
+/// Check the extensions the server's 101 agreed to, and set up inflating if
+/// it took permessage-deflate.
+pub(super) fn accept(headers: &[Header]) -> Result<Option<Inflater>> {
+    let mut inflater = None;
+    for header in headers {
+        if !header.name.eq_ignore_ascii_case("sec-websocket-extensions") {
+            continue;
+        }
+        let value = std::str::from_utf8(&header.value)?;
+        for extension in value.split(',').map(str::trim) {
+            if extension.is_empty() {
+                continue;
+            }
+            let name = extension_name(extension);
+            if !name.eq_ignore_ascii_case(EXTENSION) {
+                bail!(
+                    "Local server agreed to an extension that wasn't offered: {}",
+                    name
+                );
+            }
+            if inflater.is_some() {
+                bail!("Local server agreed to {} twice", EXTENSION);
+            }
+            inflater = Some(Inflater::new(extension)?);
+        }
+    }
+    Ok(inflater)
+}
+
+fn extension_name(extension: &str) -> &str {
+    extension.split(';').next().unwrap_or_default().trim()
+}
+
+/// Inflates the local server's compressed messages, one frame at a time.
+pub(super) struct Inflater {
+    decompress: Decompress,
+    /// The server starts each message with an empty window
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    fn new(agreed: &str) -> Result<Self> {
+        let mut no_context_takeover = false;
+        for param in agreed.split(';').skip(1) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param.trim(), None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) => no_context_takeover = true,
+                ("client_no_context_takeover", None) => {}
+                // Any window fits in the default one; what the client
+                // sends is never compressed
+                ("server_max_window_bits" | "client_max_window_bits", Some(bits))
+                    if matches!(bits.parse::<u8>(), Ok(8..=15)) => {}
+                _ => bail!("Unsupported {} parameter: {}", EXTENSION, param.trim()),
+            }
+        }
+        Ok(Self {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+        })
+    }
+
+    /// Inflate one frame's payload. `fin` ends the message.
+    pub(super) fn inflate(&mut self, payload: &[u8], fin: bool) -> io::Result<Bytes> {
+        let mut out = Vec::with_capacity(payload.len().saturating_mul(2).max(64));
+        self.feed(payload, &mut out)?;
+        if fin {
+            self.feed(&MESSAGE_TAIL, &mut out)?;
+            if self.no_context_takeover {
+                self.decompress.reset(false);
+            }
+        }
+        Ok(out.into())
+    }
+
+    fn feed(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(1024));
+            }
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(input, out, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = self.decompress.total_out() - total_out;
+            input = &input[consumed..];
+
+            // Done once the input is used up and the output didn't fill,
+            // so nothing is left inside the decompressor
+            if status == Status::StreamEnd || (input.is_empty() && out.len() < out.capacity()) {
+                return Ok(());
+            }
+            if consumed == 0 && produced == 0 && out.len() < out.capacity() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Compressed message is truncated",
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use dotunnel::transport::message::Header;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    use super::{accept, offer, Inflater, MESSAGE_TAIL};
+
+    /// What a server sends: the message deflated, flushed, and the tail
+    /// stripped.
+    fn deflate(compress: &mut Compress, message: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(message.len() + 64);
+        compress
+            .compress_vec(message, &mut out, FlushCompress::Sync)
+            .unwrap();
+        assert!(out.ends_with(&MESSAGE_TAIL));
+        out.truncate(out.len() - MESSAGE_TAIL.len());
+        out
+    }
+
+    fn agreed(value: &str) -> Vec<Header> {
+        vec![Header {
+            name: "Sec-WebSocket-Extensions".to_string(),
+            value: Bytes::copy_from_slice(value.as_bytes()),
+        }]
+    }
+
+    fn inflater(value: &str) -> Inflater {
+        accept(&agreed(value)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn single_frame_messages() {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut inflater = inflater("permessage-deflate");
+        for message in [&b"hello hello hello"[..], b"hello again", b""] {
+            let payload = deflate(&mut compress, message);
+            assert_eq!(&inflater.inflate(&payload, true).unwrap()[..], message);
+        }
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut inflater = inflater("permessage-deflate");
+        let message = b"fragmented ".repeat(500);
+        let payload = deflate(&mut compress, &message);
+
+        let mut inflated = Vec::new();
+        let mut frames = payload.chunks(7).peekable();
+        while let Some(frame) = frames.next() {
+            let fin = frames.peek().is_none();
+            inflated.extend_from_slice(&inflater.inflate(frame, fin).unwrap());
+        }
+        assert_eq!(inflated, message);
+    }
+
+    #[test]
+    fn server_no_context_takeover() {
+        let mut inflater = inflater("permessage-deflate; server_no_context_takeover");
+        for message in [&b"same words same words"[..], b"same words same words"] {
+            // Each message from a fresh window, as the server promised
+            let mut compress = Compress::new(Compression::default(), false);
+            let payload = deflate(&mut compress, message);
+            assert_eq!(&inflater.inflate(&payload, true).unwrap()[..], message);
+        }
+    }
+
+    #[test]
+    fn window_bits_accepted() {
+        inflater("permessage-deflate; server_max_window_bits=10; client_max_window_bits=\"15\"");
+    }
+
+    #[test]
+    fn rejects_unknown_parameter() {
+        assert!(accept(&agreed("permessage-deflate; x-custom")).is_err());
+        assert!(accept(&agreed("permessage-deflate; server_max_window_bits=16")).is_err());
+    }
+
+    #[test]
+    fn rejects_unoffered_extension() {
+        assert!(accept(&agreed("x-webkit-deflate-frame")).is_err());
+        assert!(accept(&agreed("permessage-deflate, permessage-deflate")).is_err());
+        assert!(accept(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn offer_keeps_only_permessage_deflate() {
+        let offered = offer(b"x-foo, permessage-deflate; client_max_window_bits, bar");
+        assert_eq!(
+            offered.as_deref(),
+            Some(&b"permessage-deflate; client_max_window_bits"[..])
+        );
+        assert_eq!(offer(b"x-foo"), None);
+    }
+}
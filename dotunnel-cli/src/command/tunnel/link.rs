@@ -0,0 +1,301 @@
+//! The pipe carrying envelopes to and from the relay.
+//!
+//! Normally a WebSocket. Some corporate networks and proxies break WebSocket
+//! upgrades, so the same messages can also travel over plain HTTP: a
+//! long-lived GET streams the relay's side down, and a chunked POST streams
+//! ours up. Both bodies are a sequence of frames,
+//!
+//! ```text
+//! [opcode: u8][length: u32 BE][payload]
+//! ```
+//!
+//! using WebSocket opcodes (1 = text, 2 = binary), one frame per message.
+
+// Both transports report tungstenite's own (large) error type so the IO
+// loop handles them alike.
+#![allow(clippy::result_large_err)]
+
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tracing::debug;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message as WsMessage, WebSocket};
+use ureq::{Agent, SendBody};
+
+/// Largest frame accepted on the HTTP transport, matching the relay's
+/// WebSocket message limit.
+const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+const OPCODE_TEXT: u8 = 1;
+const OPCODE_BINARY: u8 = 2;
+
+/// How the CLI reaches the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(super) enum Transport {
+    /// WebSocket, falling back to HTTP streaming if the upgrade fails
+    Auto,
+    /// WebSocket only
+    Websocket,
+    /// HTTP streaming only
+    Http,
+}
+
+/// An open relay connection.
+///
+/// The HTTP transport stands in for a WebSocket message for message, so
+/// both report errors as `tungstenite::Error` and the IO loop handles them
+/// alike.
+pub(super) enum RelayLink {
+    WebSocket(Box<WebSocket<MaybeTlsStream<TcpStream>>>),
+    Http(HttpLink),
+}
+
+impl RelayLink {
+    /// Send and flush one message.
+    pub(super) fn send(&mut self, msg: WsMessage) -> tungstenite::Result<()> {
+        match self {
+            Self::WebSocket(ws) => ws.send(msg),
+            Self::Http(link) => link.send(msg),
+        }
+    }
+
+    /// Read one message, or time out with `ErrorKind::TimedOut`.
+    pub(super) fn read(&mut self) -> tungstenite::Result<WsMessage> {
+        match self {
+            Self::WebSocket(ws) => ws.read(),
+            Self::Http(link) => link.read(),
+        }
+    }
+
+    /// Flush anything written internally, such as automatic pongs.
+    pub(super) fn flush(&mut self) -> tungstenite::Result<()> {
+        match self {
+            Self::WebSocket(ws) => ws.flush(),
+            Self::Http(_) => Ok(()),
+        }
+    }
+
+    pub(super) fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::WebSocket(ws) => set_read_timeout(ws, timeout),
+            Self::Http(link) => {
+                link.read_timeout = timeout;
+                Ok(())
+            }
+        }
+    }
+
+    /// Close the connection cleanly.
+    pub(super) fn close(&mut self) {
+        match self {
+            Self::WebSocket(ws) => {
+                let _ = ws.close(None);
+                let _ = ws.flush();
+            }
+            Self::Http(link) => link.close(),
+        }
+    }
+}
+
+/// Set the read timeout on a WebSocket's underlying TCP stream.
+fn set_read_timeout(
+    ws: &WebSocket<MaybeTlsStream<TcpStream>>,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    match ws.get_ref() {
+        MaybeTlsStream::Plain(tcp) => tcp.set_read_timeout(timeout)?,
+        MaybeTlsStream::NativeTls(tls) => tls.get_ref().set_read_timeout(timeout)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+// =============================================================================
+// HTTP Streaming Transport
+// =============================================================================
+
+/// The HTTP transport's two requests, each pumped by its own thread.
+pub(super) struct HttpLink {
+    /// Frames for the upstream POST body; dropping it ends the body. A
+    /// rendezvous channel: the body takes a frame only after writing out
+    /// the one before, so the IO loop is held to the POST's pace as a
+    /// WebSocket's socket would hold it.
+    upstream: Option<mpsc::SyncSender<Vec<u8>>>,
+    /// Messages from the downstream GET, or the error that ended either leg
+    downstream: mpsc::Receiver<io::Result<WsMessage>>,
+    read_timeout: Option<Duration>,
+}
+
+impl HttpLink {
+    /// Start both legs. `body` is the already-open downstream response;
+    /// the upstream POST goes to `upstream_url` with `headers`.
+    pub(super) fn start(
+        agent: Agent,
+        upstream_url: String,
+        headers: Vec<(&'static str, String)>,
+        body: ureq::Body,
+    ) -> io::Result<Self> {
+        let (down_tx, down_rx) = mpsc::channel();
+        let (up_tx, up_rx) = mpsc::sync_channel(0);
+
+        let reader = body.into_reader();
+        let tx = down_tx.clone();
+        thread::Builder::new()
+            .name("dotunnel-downstream".to_string())
+            .spawn(move || read_frames(reader, tx))?;
+
+        thread::Builder::new()
+            .name("dotunnel-upstream".to_string())
+            .spawn(move || {
+                let mut request = agent
+                    .post(&upstream_url)
+                    .config()
+                    .http_status_as_error(false)
+                    .build();
+                for (name, value) in &headers {
+                    request = request.header(*name, value);
+                }
+                let reader = ChannelReader {
+                    rx: up_rx,
+                    chunk: Vec::new(),
+                    pos: 0,
+                };
+                let error = match request.send(SendBody::from_owned_reader(reader)) {
+                    Ok(resp) if resp.status().is_success() => return,
+                    Ok(resp) => io::Error::other(format!("upstream rejected: {}", resp.status())),
+                    Err(e) => io::Error::other(e),
+                };
+                debug!("HTTP upstream ended: {}", error);
+                let _ = down_tx.send(Err(error));
+            })?;
+
+        Ok(Self {
+            upstream: Some(up_tx),
+            downstream: down_rx,
+            read_timeout: None,
+        })
+    }
+
+    fn send(&mut self, msg: WsMessage) -> tungstenite::Result<()> {
+        let (opcode, payload): (u8, Bytes) = match msg {
+            WsMessage::Text(text) => (OPCODE_TEXT, text.into()),
+            WsMessage::Binary(data) => (OPCODE_BINARY, data),
+            // The relay pings over envelopes, never WebSocket control frames
+            _ => return Ok(()),
+        };
+        let upstream = self
+            .upstream
+            .as_ref()
+            .ok_or(tungstenite::Error::AlreadyClosed)?;
+
+        let mut frame = Vec::with_capacity(5 + payload.len());
+        frame.push(opcode);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        upstream
+            .send(frame)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+
+    fn read(&mut self) -> tungstenite::Result<WsMessage> {
+        let received = match self.read_timeout {
+            Some(timeout) => self.downstream.recv_timeout(timeout),
+            None => self
+                .downstream
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Ok(msg)) => Ok(msg),
+            Ok(Err(e)) => Err(e.into()),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(io::Error::from(io::ErrorKind::TimedOut).into())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(tungstenite::Error::ConnectionClosed),
+        }
+    }
+
+    fn close(&mut self) {
+        // Ending the POST body is the clean close
+        self.upstream = None;
+    }
+}
+
+/// Pump downstream frames into the channel until the body ends.
+fn read_frames(mut reader: impl Read, tx: mpsc::Sender<io::Result<WsMessage>>) {
+    let result = (|| -> io::Result<()> {
+        loop {
+            let mut header = [0u8; 5];
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                // Ended between frames: the relay closed the stream
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+
+            let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame of {} bytes exceeds the limit", len),
+                ));
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload)?;
+
+            let msg = match header[0] {
+                OPCODE_TEXT => WsMessage::Text(
+                    String::from_utf8(payload)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                        .into(),
+                ),
+                OPCODE_BINARY => WsMessage::Binary(payload.into()),
+                opcode => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown frame opcode {}", opcode),
+                    ));
+                }
+            };
+            if tx.send(Ok(msg)).is_err() {
+                // The link was dropped
+                return Ok(());
+            }
+        }
+    })();
+
+    if let Err(e) = result {
+        debug!("HTTP downstream ended: {}", e);
+        let _ = tx.send(Err(e));
+    }
+}
+
+/// Upstream request body fed from a channel; ends when the sender is dropped.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,206 @@
+//! Client-side liveness probing.
+//!
+//! The relay pings us, but a half-open TCP connection (a laptop waking from
+//! sleep, a NAT mapping that timed out) never delivers those pings and never
+//! fails our reads either. Sending our own pings and expecting the pongs back
+//! is the only way to notice.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+/// How often a ping is sent, in seconds.
+pub(super) const DEFAULT_PING_INTERVAL_SECS: u64 = 10;
+
+/// Pings that may go unanswered before the connection is declared dead.
+pub(super) const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+
+/// How a connection is probed.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct LivenessConfig {
+    pub(super) ping_interval: Duration,
+    pub(super) max_missed_pongs: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL_SECS),
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+        }
+    }
+}
+
+/// What the IO loop should do after [`Liveness::tick`].
+pub(super) enum Probe {
+    /// Nothing due yet.
+    Idle,
+    /// Send a `Control::Ping` carrying this payload.
+    Send(Bytes),
+    /// Too many pings went unanswered.
+    Dead { missed: u32 },
+}
+
+/// Ping schedule and round-trip time for a single relay connection.
+pub(super) struct Liveness {
+    config: LivenessConfig,
+    next_probe: Instant,
+    /// The ping awaiting its pong, and when it was sent
+    outstanding: Option<(u64, Instant)>,
+    next_id: u64,
+    missed: u32,
+    /// Smoothed round-trip time
+    rtt: Option<Duration>,
+    /// Set when `rtt` has a sample not yet reported
+    rtt_updated: bool,
+}
+
+impl Liveness {
+    pub(super) fn new(config: LivenessConfig) -> Self {
+        Self {
+            config,
+            // Probe right away so the RTT is known early
+            next_probe: Instant::now(),
+            outstanding: None,
+            next_id: 0,
+            missed: 0,
+            rtt: None,
+            rtt_updated: false,
+        }
+    }
+
+    /// Advance the ping schedule.
+    pub(super) fn tick(&mut self) -> Probe {
+        let now = Instant::now();
+        if now < self.next_probe {
+            return Probe::Idle;
+        }
+        self.next_probe = now + self.config.ping_interval;
+
+        if self.outstanding.is_some() {
+            self.missed += 1;
+            if self.missed >= self.config.max_missed_pongs {
+                return Probe::Dead {
+                    missed: self.missed,
+                };
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.outstanding = Some((id, now));
+        Probe::Send(Bytes::copy_from_slice(&id.to_be_bytes()))
+    }
+
+    /// Match a pong against the outstanding ping. Returns the measured RTT,
+    /// or `None` if the pong answers something else (e.g. a ping queued
+    /// before a reconnect).
+    pub(super) fn on_pong(&mut self, data: &[u8]) -> Option<Duration> {
+        let id = u64::from_be_bytes(data.try_into().ok()?);
+        let (expected, sent_at) = self.outstanding?;
+        if id != expected {
+            return None;
+        }
+
+        let sample = sent_at.elapsed();
+        self.outstanding = None;
+        self.missed = 0;
+        self.record_rtt(sample);
+        Some(sample)
+    }
+
+    fn record_rtt(&mut self, sample: Duration) {
+        // Same smoothing as TCP's SRTT (RFC 6298): 7/8 history, 1/8 sample
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.rtt_updated = true;
+    }
+
+    /// Smoothed RTT, if it changed since the last call.
+    pub(super) fn take_rtt_update(&mut self) -> Option<Duration> {
+        if !std::mem::take(&mut self.rtt_updated) {
+            return None;
+        }
+        self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Liveness, LivenessConfig, Probe};
+
+    /// Probes on every tick, so tests don't wait out an interval.
+    fn eager(max_missed_pongs: u32) -> Liveness {
+        Liveness::new(LivenessConfig {
+            ping_interval: Duration::ZERO,
+            max_missed_pongs,
+        })
+    }
+
+    fn send(liveness: &mut Liveness) -> Vec<u8> {
+        match liveness.tick() {
+            Probe::Send(data) => data.to_vec(),
+            _ => panic!("expected a ping"),
+        }
+    }
+
+    #[test]
+    fn waits_out_the_interval() {
+        let mut liveness = Liveness::new(LivenessConfig::default());
+        send(&mut liveness);
+        assert!(matches!(liveness.tick(), Probe::Idle));
+    }
+
+    #[test]
+    fn dead_after_max_missed_pongs() {
+        let mut liveness = eager(3);
+        send(&mut liveness);
+        send(&mut liveness);
+        send(&mut liveness);
+        assert!(matches!(liveness.tick(), Probe::Dead { missed: 3 }));
+    }
+
+    #[test]
+    fn pong_clears_missed() {
+        let mut liveness = eager(2);
+        send(&mut liveness);
+        let ping = send(&mut liveness);
+        assert!(liveness.on_pong(&ping).is_some());
+
+        send(&mut liveness);
+        send(&mut liveness);
+        assert!(matches!(liveness.tick(), Probe::Dead { missed: 2 }));
+    }
+
+    #[test]
+    fn stale_pong_is_ignored() {
+        let mut liveness = eager(3);
+        let stale = send(&mut liveness);
+        let ping = send(&mut liveness);
+
+        assert!(liveness.on_pong(&stale).is_none());
+        assert!(liveness.on_pong(b"short").is_none());
+        assert!(liveness.take_rtt_update().is_none());
+        assert!(liveness.on_pong(&ping).is_some());
+        assert!(liveness.take_rtt_update().is_some());
+        // Each sample is reported once
+        assert!(liveness.take_rtt_update().is_none());
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut liveness = eager(3);
+        liveness.record_rtt(Duration::from_millis(80));
+        assert_eq!(liveness.take_rtt_update(), Some(Duration::from_millis(80)));
+
+        liveness.record_rtt(Duration::from_millis(160));
+        assert_eq!(liveness.take_rtt_update(), Some(Duration::from_millis(90)));
+
+        liveness.record_rtt(Duration::from_millis(10));
+        assert_eq!(liveness.take_rtt_update(), Some(Duration::from_millis(80)));
+    }
+}
@@ -0,0 +1,334 @@
+//! Bounded worker pool for upstream forwarding.
+//!
+//! Each stream that talks to the local server occupies a worker. Streams
+//! beyond the worker limit wait in a bounded queue; beyond that they are
+//! refused, so a traffic spike against a public URL degrades into 503s
+//! instead of thousands of threads. A stream that waits in the queue too
+//! long is refused as well, rather than answered after the client gave up.
+//!
+//! HTTP requests and upgraded connections get separate pools: a WebSocket
+//! holds its worker for as long as it stays open, and a few dozen idle HMR
+//! sockets mustn't leave no worker for the page requests next to them.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{error, warn};
+
+/// Default for `--max-concurrent-streams`.
+pub(super) const DEFAULT_MAX_CONCURRENT_STREAMS: usize = 64;
+
+/// Default for `--max-websockets`.
+pub(super) const DEFAULT_MAX_WEBSOCKETS: usize = 256;
+
+/// How long a job may wait in the queue before it is refused instead.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An idle worker exits after this long, so a burst doesn't leave its
+/// threads behind.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A job waiting for a worker.
+struct Queued {
+    job: Job,
+    /// Run instead of `job` once `deadline` has passed
+    refuse: Job,
+    deadline: Instant,
+}
+
+struct PoolState {
+    /// Oldest first, so deadlines are in order too
+    queue: VecDeque<Queued>,
+    workers: usize,
+    idle: usize,
+}
+
+struct Inner {
+    state: Mutex<PoolState>,
+    available: Condvar,
+    max_workers: usize,
+    max_queued: usize,
+    queue_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+/// Handle to the pool; cheap to clone and shared by every session.
+#[derive(Clone)]
+pub(super) struct WorkerPool {
+    inner: Arc<Inner>,
+}
+
+impl WorkerPool {
+    /// A pool running at most `max_workers` jobs at once, with up to
+    /// `max_queued` more waiting for one.
+    pub(super) fn new(max_workers: usize, max_queued: usize) -> Self {
+        Self::with_timeouts(max_workers, max_queued, QUEUE_TIMEOUT, IDLE_TIMEOUT)
+    }
+
+    fn with_timeouts(
+        max_workers: usize,
+        max_queued: usize,
+        queue_timeout: Duration,
+        idle_timeout: Duration,
+    ) -> Self {
+        let max_workers = max_workers.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    workers: 0,
+                    idle: 0,
+                }),
+                available: Condvar::new(),
+                max_workers,
+                max_queued,
+                queue_timeout,
+                idle_timeout,
+            }),
+        }
+    }
+
+    /// Whether a job submitted now would be refused.
+    pub(super) fn is_saturated(&self) -> bool {
+        self.expire_queued();
+        let state = self.inner.state.lock().unwrap();
+        self.inner.is_full(&state)
+    }
+
+    /// Run `job` on a worker, or queue it if all are busy. If no worker
+    /// takes it within [`QUEUE_TIMEOUT`], `refuse` runs instead.
+    ///
+    /// Returns false, dropping both, when the queue is full too.
+    pub(super) fn try_execute(
+        &self,
+        job: impl FnOnce() + Send + 'static,
+        refuse: impl FnOnce() + Send + 'static,
+    ) -> bool {
+        self.expire_queued();
+        let mut state = self.inner.state.lock().unwrap();
+        if self.inner.is_full(&state) {
+            return false;
+        }
+        state.queue.push_back(Queued {
+            job: Box::new(job),
+            refuse: Box::new(refuse),
+            deadline: Instant::now() + self.inner.queue_timeout,
+        });
+
+        if state.idle >= state.queue.len() {
+            drop(state);
+            self.inner.available.notify_one();
+            return true;
+        }
+
+        if state.workers < self.inner.max_workers {
+            state.workers += 1;
+            let inner = self.inner.clone();
+            let spawned = thread::Builder::new()
+                .name("dotunnel-worker".to_string())
+                .spawn(move || worker_loop(&inner));
+            if let Err(e) = spawned {
+                warn!("Failed to spawn worker thread: {}", e);
+                state.workers -= 1;
+                if state.workers == 0 {
+                    // Nobody would ever run it
+                    state.queue.pop_back();
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Refuse queued jobs that have waited too long. Called from the IO
+    /// loop too, so they are answered even while every worker stays busy.
+    pub(super) fn expire_queued(&self) {
+        let mut expired = Vec::new();
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            let now = Instant::now();
+            while state
+                .queue
+                .front()
+                .is_some_and(|queued| queued.deadline <= now)
+            {
+                expired.extend(state.queue.pop_front());
+            }
+        }
+        for queued in expired {
+            run(queued.refuse);
+        }
+    }
+}
+
+impl Inner {
+    /// No worker free or startable, and no room left in the queue.
+    fn is_full(&self, state: &PoolState) -> bool {
+        let can_run = state.idle > state.queue.len() || state.workers < self.max_workers;
+        !can_run && state.queue.len() >= self.max_queued
+    }
+}
+
+fn worker_loop(inner: &Inner) {
+    let mut state = inner.state.lock().unwrap();
+    loop {
+        if let Some(queued) = state.queue.pop_front() {
+            drop(state);
+            if queued.deadline <= Instant::now() {
+                run(queued.refuse);
+            } else {
+                run(queued.job);
+            }
+            state = inner.state.lock().unwrap();
+            continue;
+        }
+
+        state.idle += 1;
+        let (guard, timeout) = inner
+            .available
+            .wait_timeout(state, inner.idle_timeout)
+            .unwrap();
+        state = guard;
+        state.idle -= 1;
+
+        if timeout.timed_out() && state.queue.is_empty() {
+            state.workers -= 1;
+            return;
+        }
+    }
+}
+
+/// A panicking job must not take the worker with it, or the pool would
+/// count a thread that no longer exists.
+fn run(job: Job) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("Worker job panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::WorkerPool;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn pool(max_workers: usize, max_queued: usize) -> WorkerPool {
+        WorkerPool::with_timeouts(max_workers, max_queued, TIMEOUT, TIMEOUT)
+    }
+
+    fn workers(pool: &WorkerPool) -> usize {
+        pool.inner.state.lock().unwrap().workers
+    }
+
+    /// Occupy a worker until the returned sender is dropped.
+    fn block(pool: &WorkerPool) -> mpsc::Sender<()> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        assert!(pool.try_execute(
+            move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            },
+            || panic!("refused"),
+        ));
+        started_rx.recv().unwrap();
+        release_tx
+    }
+
+    #[test]
+    fn refuses_once_the_queue_is_full() {
+        let pool = pool(1, 1);
+        let release = block(&pool);
+        assert!(!pool.is_saturated());
+
+        let (ran_tx, ran_rx) = mpsc::channel();
+        assert!(pool.try_execute(move || ran_tx.send(()).unwrap(), || {}));
+        assert!(pool.is_saturated());
+        assert!(!pool.try_execute(|| panic!("ran"), || panic!("refused")));
+
+        drop(release);
+        ran_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(workers(&pool), 1);
+    }
+
+    #[test]
+    fn expired_jobs_are_refused() {
+        let pool = pool(1, 1);
+        let release = block(&pool);
+
+        let (refused_tx, refused_rx) = mpsc::channel();
+        assert!(pool.try_execute(|| panic!("ran"), move || refused_tx.send(()).unwrap()));
+        pool.expire_queued();
+        assert!(refused_rx.try_recv().is_err());
+
+        thread::sleep(TIMEOUT);
+        pool.expire_queued();
+        refused_rx.try_recv().unwrap();
+        assert!(!pool.is_saturated());
+        drop(release);
+    }
+
+    #[test]
+    fn worker_refuses_a_job_that_expired_in_the_queue() {
+        let pool = pool(1, 1);
+        let release = block(&pool);
+
+        let (refused_tx, refused_rx) = mpsc::channel();
+        assert!(pool.try_execute(|| panic!("ran"), move || refused_tx.send(()).unwrap()));
+        thread::sleep(TIMEOUT);
+
+        // Taken by the worker before the IO loop got to it
+        drop(release);
+        refused_rx.recv_timeout(TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn panicking_job_keeps_its_worker() {
+        let pool = pool(1, 0);
+        let (ran_tx, ran_rx) = mpsc::channel();
+        let panicking = ran_tx.clone();
+        assert!(pool.try_execute(
+            move || {
+                panicking.send(()).unwrap();
+                panic!("job panicked");
+            },
+            || {}
+        ));
+        ran_rx.recv_timeout(TIMEOUT).unwrap();
+
+        // The same worker takes the next job
+        while pool.is_saturated() {
+            thread::yield_now();
+        }
+        assert!(pool.try_execute(move || ran_tx.send(()).unwrap(), || {}));
+        ran_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(workers(&pool), 1);
+    }
+
+    #[test]
+    fn idle_workers_exit() {
+        let pool = pool(2, 0);
+        let release = block(&pool);
+        drop(block(&pool));
+        drop(release);
+        assert_eq!(workers(&pool), 2);
+
+        thread::sleep(TIMEOUT * 3);
+        assert_eq!(workers(&pool), 0);
+
+        // and are started again when needed
+        let (ran_tx, ran_rx) = mpsc::channel();
+        assert!(pool.try_execute(move || ran_tx.send(()).unwrap(), || {}));
+        ran_rx.recv_timeout(TIMEOUT).unwrap();
+    }
+}
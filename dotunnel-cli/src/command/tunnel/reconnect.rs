@@ -0,0 +1,270 @@
+//! Reconnection policy and relay error classification.
+
+use std::time::{Duration, Instant};
+
+use super::ErrorResponse;
+
+// =============================================================================
+// Reconnection Constants
+// =============================================================================
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A connection that stayed up this long counts as healthy, and the next
+/// failure starts backing off from scratch.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+// =============================================================================
+// Exit Codes
+// =============================================================================
+
+/// The token was rejected (`EX_NOPERM`).
+const EXIT_UNAUTHORIZED: i32 = 77;
+
+/// The request can never succeed as configured, e.g. an unknown subdomain
+/// (`EX_CONFIG`).
+const EXIT_CONFIG: i32 = 78;
+
+/// Gave up after the configured attempts or duration (`EX_TEMPFAIL`).
+const EXIT_GAVE_UP: i32 = 75;
+
+// =============================================================================
+// Fatal Errors
+// =============================================================================
+
+/// An error that retrying will not fix. The tunnel exits with
+/// [`FatalError::exit_code`] instead of reconnecting.
+#[derive(Debug, thiserror::Error)]
+pub enum FatalError {
+    #[error("{message}")]
+    Unauthorized { message: String },
+
+    #[error("{message}")]
+    Rejected {
+        code: Option<String>,
+        message: String,
+    },
+
+    #[error("Giving up after {attempts} failed attempt(s)")]
+    GaveUp { attempts: u32 },
+
+    /// Another process connected to the same tunnel; reconnecting would
+    /// only take it back, and the two would evict each other forever.
+    #[error("Another dotunnel process took over this tunnel")]
+    Replaced,
+}
+
+impl FatalError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FatalError::Unauthorized { .. } => EXIT_UNAUTHORIZED,
+            FatalError::Rejected { .. } | FatalError::Replaced => EXIT_CONFIG,
+            FatalError::GaveUp { .. } => EXIT_GAVE_UP,
+        }
+    }
+}
+
+/// Turn a relay error response into an error, fatal unless a retry might
+/// succeed.
+///
+/// Only the relay's own `code`s are fatal. Any other response, 4xx
+/// included, may have come from a proxy in between (a 403 from a filter,
+/// a 407 asking for credentials) and is retried.
+pub(super) fn classify(status: u16, response: ErrorResponse) -> anyhow::Error {
+    let ErrorResponse { error, code } = response;
+
+    match code.as_deref() {
+        Some("unauthorized" | "invalid_token") => FatalError::Unauthorized {
+            message: format!("{} (run 'dotunnel login' again)", error),
+        }
+        .into(),
+        Some("tunnel_not_found" | "invalid_body" | "missing_tunnel_id" | "method_not_allowed") => {
+            FatalError::Rejected {
+                code,
+                message: error,
+            }
+            .into()
+        }
+        // Including `tunnel_creation_failed`: an ephemeral tunnel's random
+        // subdomain can collide, and the next attempt draws another
+        _ => anyhow::anyhow!("{} ({})", error, status),
+    }
+}
+
+// =============================================================================
+// Reconnect Policy
+// =============================================================================
+
+/// Exponential backoff with full jitter, bounded by an optional number of
+/// attempts and an optional total duration.
+///
+/// Both limits count from the first failure since the last stable
+/// connection.
+pub(super) struct ReconnectPolicy {
+    max_attempts: Option<u32>,
+    max_duration: Option<Duration>,
+    /// Consecutive failed attempts
+    attempts: u32,
+    /// When the current streak of failures began
+    failing_since: Option<Instant>,
+}
+
+impl ReconnectPolicy {
+    pub(super) fn new(max_attempts: Option<u32>, max_duration: Option<Duration>) -> Self {
+        Self {
+            max_attempts,
+            max_duration,
+            attempts: 0,
+            failing_since: None,
+        }
+    }
+
+    /// Forget past failures.
+    pub(super) fn reset(&mut self) {
+        self.attempts = 0;
+        self.failing_since = None;
+    }
+
+    /// Reset if a connection established at `connected_at` proved stable.
+    pub(super) fn connection_ended(&mut self, connected_at: Option<Instant>) {
+        if connected_at.is_some_and(|at| at.elapsed() >= STABLE_CONNECTION) {
+            self.reset();
+        }
+    }
+
+    /// Record a failed attempt and return how long to wait before the next
+    /// one, or the error to exit with once a limit is reached.
+    pub(super) fn next_delay(&mut self) -> Result<Duration, FatalError> {
+        let failing_since = *self.failing_since.get_or_insert_with(Instant::now);
+        self.attempts += 1;
+
+        let out_of_attempts = self.max_attempts.is_some_and(|max| self.attempts >= max);
+        let out_of_time = self
+            .max_duration
+            .is_some_and(|max| failing_since.elapsed() >= max);
+        if out_of_attempts || out_of_time {
+            return Err(FatalError::GaveUp {
+                attempts: self.attempts,
+            });
+        }
+
+        // Full jitter: uniformly random between zero and the capped
+        // exponential, so clients dropped together don't return together.
+        let exponent = (self.attempts - 1).min(16);
+        let ceiling = INITIAL_BACKOFF
+            .saturating_mul(1 << exponent)
+            .min(MAX_BACKOFF);
+        let jittered = fastrand::u64(0..=ceiling.as_millis() as u64);
+        Ok(Duration::from_millis(jittered))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::ErrorResponse;
+    use super::{
+        classify, FatalError, ReconnectPolicy, EXIT_CONFIG, EXIT_GAVE_UP, EXIT_UNAUTHORIZED,
+        INITIAL_BACKOFF, MAX_BACKOFF, STABLE_CONNECTION,
+    };
+
+    fn fatal(status: u16, code: Option<&str>) -> Option<i32> {
+        let response = ErrorResponse {
+            error: "error".to_string(),
+            code: code.map(str::to_string),
+        };
+        classify(status, response)
+            .downcast_ref::<FatalError>()
+            .map(FatalError::exit_code)
+    }
+
+    #[test]
+    fn relay_codes_are_fatal() {
+        assert_eq!(fatal(401, Some("unauthorized")), Some(EXIT_UNAUTHORIZED));
+        assert_eq!(fatal(401, Some("invalid_token")), Some(EXIT_UNAUTHORIZED));
+        assert_eq!(fatal(404, Some("tunnel_not_found")), Some(EXIT_CONFIG));
+        assert_eq!(fatal(400, Some("invalid_body")), Some(EXIT_CONFIG));
+        assert_eq!(fatal(400, Some("missing_tunnel_id")), Some(EXIT_CONFIG));
+        assert_eq!(fatal(405, Some("method_not_allowed")), Some(EXIT_CONFIG));
+    }
+
+    #[test]
+    fn everything_else_is_retried() {
+        assert_eq!(fatal(400, Some("tunnel_creation_failed")), None);
+        assert_eq!(fatal(400, Some("something_new")), None);
+        // Bare responses, as a proxy in between would send them
+        for status in [400, 401, 403, 404, 407, 408, 429, 500, 502, 503] {
+            assert_eq!(fatal(status, None), None, "status {}", status);
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(FatalError::Replaced.exit_code(), EXIT_CONFIG);
+        assert_eq!(FatalError::GaveUp { attempts: 1 }.exit_code(), EXIT_GAVE_UP);
+    }
+
+    #[test]
+    fn delays_stay_within_the_capped_exponential() {
+        let mut policy = ReconnectPolicy::new(None, None);
+        for attempt in 0..20u32 {
+            let ceiling = INITIAL_BACKOFF
+                .saturating_mul(1 << attempt.min(16))
+                .min(MAX_BACKOFF);
+            let delay = policy.next_delay().unwrap();
+            assert!(delay <= ceiling, "attempt {}: {:?}", attempt + 1, delay);
+        }
+        assert_eq!(policy.attempts, 20);
+    }
+
+    #[test]
+    fn delays_are_jittered() {
+        let mut policy = ReconnectPolicy::new(None, None);
+        // Past the cap, every delay is drawn from the same 0..=60s range
+        for _ in 0..7 {
+            policy.next_delay().unwrap();
+        }
+        let delays: Vec<_> = (0..8).map(|_| policy.next_delay().unwrap()).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn stable_connection_resets() {
+        let mut policy = ReconnectPolicy::new(Some(3), None);
+        policy.next_delay().unwrap();
+        policy.next_delay().unwrap();
+
+        // A connection that dropped quickly keeps the streak going
+        policy.connection_ended(Some(Instant::now()));
+        policy.connection_ended(None);
+        assert_eq!(policy.attempts, 2);
+
+        let connected_at = Instant::now().checked_sub(STABLE_CONNECTION).unwrap();
+        policy.connection_ended(Some(connected_at));
+        assert_eq!(policy.attempts, 0);
+        assert!(policy.failing_since.is_none());
+        assert!(policy.next_delay().unwrap() <= INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut policy = ReconnectPolicy::new(Some(3), None);
+        assert!(policy.next_delay().is_ok());
+        assert!(policy.next_delay().is_ok());
+        let err = policy.next_delay().unwrap_err();
+        assert!(matches!(err, FatalError::GaveUp { attempts: 3 }));
+        assert_eq!(err.exit_code(), EXIT_GAVE_UP);
+    }
+
+    #[test]
+    fn gives_up_after_max_duration() {
+        let mut policy = ReconnectPolicy::new(None, Some(Duration::from_secs(60)));
+        assert!(policy.next_delay().is_ok());
+
+        policy.failing_since = Instant::now().checked_sub(Duration::from_secs(61));
+        let err = policy.next_delay().unwrap_err();
+        assert!(matches!(err, FatalError::GaveUp { attempts: 2 }));
+    }
+}
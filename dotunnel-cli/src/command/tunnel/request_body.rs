@@ -0,0 +1,222 @@
+//! Request bodies, streamed to the local server as they arrive.
+//!
+//! A request is forwarded as soon as its head arrives, and the IO loop
+//! passes its body on to the worker forwarding it through a bounded
+//! channel. The relay can't be slowed down, so a local server that falls
+//! [`QUEUE_CHUNKS`] chunks behind is cut off rather than buffered for.
+
+use std::io;
+use std::sync::mpsc;
+
+use bytes::Bytes;
+use dotunnel::transport::message::Header;
+
+/// Body chunks queued for the local server before the request is aborted.
+pub(super) const QUEUE_CHUNKS: usize = 256;
+
+/// What the IO loop passes on of a request body.
+pub(super) enum BodyEvent {
+    Data(Bytes),
+    Trailers(Vec<Header>),
+    /// The body is complete. A sender dropped without it means the
+    /// request was aborted.
+    End,
+}
+
+/// What [`RequestBody::poll_chunk`] found.
+pub(super) enum BodyPoll {
+    Chunk(Bytes),
+    End,
+    /// Nothing has arrived yet
+    Pending,
+}
+
+/// The IO loop's end, kept in the stream map.
+pub(super) type BodySender = mpsc::SyncSender<BodyEvent>;
+
+/// A request body, as the worker forwarding it reads it.
+pub(super) struct RequestBody {
+    /// None for a request without a body, and once the body has ended
+    rx: Option<mpsc::Receiver<BodyEvent>>,
+    /// As the client declared it
+    content_length: Option<u64>,
+    trailers: Vec<Header>,
+    /// Set once any of the body has been taken off the channel
+    started: bool,
+}
+
+impl RequestBody {
+    /// A body fed through the returned sender.
+    pub(super) fn channel(content_length: Option<u64>) -> (BodySender, Self) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CHUNKS);
+        let body = Self {
+            rx: Some(rx),
+            content_length,
+            trailers: Vec::new(),
+            started: false,
+        };
+        (tx, body)
+    }
+
+    /// The body of a request that has none.
+    pub(super) fn empty() -> Self {
+        Self {
+            rx: None,
+            content_length: Some(0),
+            trailers: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Whether the request has no body at all.
+    pub(super) fn is_empty(&self) -> bool {
+        self.rx.is_none() && !self.started
+    }
+
+    /// The length the client declared, if it did.
+    pub(super) fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Whether any of the body has been read, so the request can no longer
+    /// be sent again.
+    pub(super) fn started(&self) -> bool {
+        self.started
+    }
+
+    /// The next chunk, waiting for it to arrive; None once the body is
+    /// complete.
+    pub(super) fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            let Some(rx) = &self.rx else {
+                return Ok(None);
+            };
+            let event = rx.recv().map_err(|_| mpsc::TryRecvError::Disconnected);
+            match self.accept(event)? {
+                BodyPoll::Chunk(data) => return Ok(Some(data)),
+                BodyPoll::End => return Ok(None),
+                BodyPoll::Pending => {}
+            }
+        }
+    }
+
+    /// The next chunk if it has arrived, without waiting.
+    pub(super) fn poll_chunk(&mut self) -> io::Result<BodyPoll> {
+        loop {
+            let Some(rx) = &self.rx else {
+                return Ok(BodyPoll::End);
+            };
+            match rx.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => return Ok(BodyPoll::Pending),
+                event => match self.accept(event)? {
+                    BodyPoll::Pending => {}
+                    poll => return Ok(poll),
+                },
+            }
+        }
+    }
+
+    /// Take in an event off the channel; Pending if it doesn't end the wait
+    /// for a chunk.
+    fn accept(&mut self, event: Result<BodyEvent, mpsc::TryRecvError>) -> io::Result<BodyPoll> {
+        self.started = true;
+        match event {
+            Ok(BodyEvent::Data(data)) if data.is_empty() => Ok(BodyPoll::Pending),
+            Ok(BodyEvent::Data(data)) => Ok(BodyPoll::Chunk(data)),
+            Ok(BodyEvent::Trailers(trailers)) => {
+                self.trailers.extend(trailers);
+                Ok(BodyPoll::Pending)
+            }
+            Ok(BodyEvent::End) => {
+                self.rx = None;
+                Ok(BodyPoll::End)
+            }
+            Err(_) => {
+                self.rx = None;
+                Err(io::Error::other("request aborted by client"))
+            }
+        }
+    }
+
+    /// Trailers that followed the body, once [`next_chunk`](Self::next_chunk)
+    /// has returned None.
+    pub(super) fn take_trailers(&mut self) -> Vec<Header> {
+        std::mem::take(&mut self.trailers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use dotunnel::transport::message::Header;
+
+    use super::{BodyEvent, BodyPoll, RequestBody, QUEUE_CHUNKS};
+
+    #[test]
+    fn chunks_then_trailers_in_order() {
+        let (tx, mut body) = RequestBody::channel(None);
+        tx.send(BodyEvent::Data(Bytes::from_static(b"hello ")))
+            .unwrap();
+        tx.send(BodyEvent::Data(Bytes::new())).unwrap();
+        tx.send(BodyEvent::Data(Bytes::from_static(b"world")))
+            .unwrap();
+        tx.send(BodyEvent::Trailers(vec![Header {
+            name: "grpc-status".to_string(),
+            value: Bytes::from_static(b"0"),
+        }]))
+        .unwrap();
+        tx.send(BodyEvent::End).unwrap();
+
+        assert!(!body.is_empty());
+        assert_eq!(body.next_chunk().unwrap().unwrap(), "hello ");
+        assert!(body.started());
+        assert_eq!(body.next_chunk().unwrap().unwrap(), "world");
+        assert!(body.next_chunk().unwrap().is_none());
+        assert!(body.next_chunk().unwrap().is_none());
+        assert_eq!(body.take_trailers()[0].name, "grpc-status");
+    }
+
+    #[test]
+    fn dropped_sender_is_an_abort() {
+        let (tx, mut body) = RequestBody::channel(Some(10));
+        tx.send(BodyEvent::Data(Bytes::from_static(b"hello")))
+            .unwrap();
+        drop(tx);
+
+        assert_eq!(body.next_chunk().unwrap().unwrap(), "hello");
+        assert!(body.next_chunk().is_err());
+    }
+
+    #[test]
+    fn poll_doesnt_wait() {
+        let (tx, mut body) = RequestBody::channel(None);
+        assert!(matches!(body.poll_chunk().unwrap(), BodyPoll::Pending));
+        assert!(!body.started());
+
+        tx.send(BodyEvent::Data(Bytes::from_static(b"hello")))
+            .unwrap();
+        assert!(matches!(body.poll_chunk().unwrap(), BodyPoll::Chunk(data) if data == "hello"));
+        tx.send(BodyEvent::End).unwrap();
+        assert!(matches!(body.poll_chunk().unwrap(), BodyPoll::End));
+        assert!(matches!(body.poll_chunk().unwrap(), BodyPoll::End));
+    }
+
+    #[test]
+    fn queue_is_bounded() {
+        let (tx, _body) = RequestBody::channel(None);
+        for _ in 0..QUEUE_CHUNKS {
+            tx.try_send(BodyEvent::Data(Bytes::from_static(b"x")))
+                .unwrap();
+        }
+        assert!(tx.try_send(BodyEvent::End).is_err());
+    }
+
+    #[test]
+    fn empty_body_ends_at_once() {
+        let mut body = RequestBody::empty();
+        assert!(body.is_empty());
+        assert_eq!(body.content_length(), Some(0));
+        assert!(body.next_chunk().unwrap().is_none());
+        assert!(!body.started());
+    }
+}
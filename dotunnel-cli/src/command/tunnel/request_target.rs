@@ -0,0 +1,113 @@
+//! Request-target validation.
+//!
+//! The relay sends origin-form targets (`/path?query`), which go to the
+//! local server as they are. Anything else could be read as naming another
+//! host, `@evil.example/` after an authority or an absolute URL, so it is
+//! refused. The one exception is absolute-form naming the tunnel itself,
+//! which is reduced to its origin-form. Either way the request only ever
+//! reaches the configured upstream.
+
+use std::borrow::Cow;
+
+use url::Url;
+
+/// The origin-form of `uri`, or None if it isn't one to forward.
+pub(super) fn origin_form<'a>(uri: &'a str, tunnel_url: &Url) -> Option<Cow<'a, str>> {
+    let target = if uri.starts_with('/') {
+        Cow::Borrowed(uri)
+    } else {
+        strip_tunnel_origin(uri, tunnel_url)?
+    };
+    is_origin_form(&target).then_some(target)
+}
+
+/// Path and query of an absolute-form target with the tunnel's scheme, host
+/// and port.
+fn strip_tunnel_origin<'a>(uri: &'a str, tunnel_url: &Url) -> Option<Cow<'a, str>> {
+    let (scheme, rest) = uri.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case(tunnel_url.scheme()) {
+        return None;
+    }
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+
+    // Compared as written, so userinfo or anything else a parser might
+    // read differently never matches
+    let host = tunnel_url.host_str()?;
+    let port = tunnel_url.port_or_known_default()?;
+    let same_host = authority.eq_ignore_ascii_case(host)
+        || authority
+            .strip_suffix(&format!(":{}", port))
+            .is_some_and(|name| name.eq_ignore_ascii_case(host));
+    if !same_host {
+        return None;
+    }
+
+    Some(match path {
+        "" => Cow::Borrowed("/"),
+        path if path.starts_with('?') => Cow::Owned(format!("/{}", path)),
+        path => Cow::Borrowed(path),
+    })
+}
+
+/// An absolute path and optional query, RFC 9112 §3.2.1. Characters outside
+/// RFC 3986 that browsers leave unescaped, such as `|` or `^`, are let
+/// through; whitespace, controls, fragments and non-ASCII are not. Neither
+/// is a leading `//`, which URL resolvers take for a host.
+fn is_origin_form(target: &str) -> bool {
+    target.starts_with('/')
+        && !target.starts_with("//")
+        && target.bytes().all(|b| b.is_ascii_graphic() && b != b'#')
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::origin_form;
+
+    fn check(uri: &str) -> Option<String> {
+        let tunnel_url = Url::parse("https://tunnel.host/").unwrap();
+        origin_form(uri, &tunnel_url).map(|target| target.into_owned())
+    }
+
+    #[test]
+    fn passes_origin_form() {
+        assert_eq!(check("/").as_deref(), Some("/"));
+        assert_eq!(check("/ok?q").as_deref(), Some("/ok?q"));
+        assert_eq!(check("/a|b^c").as_deref(), Some("/a|b^c"));
+    }
+
+    #[test]
+    fn refuses_other_hosts() {
+        assert_eq!(check("//evil"), None);
+        assert_eq!(check("@evil.example/"), None);
+        assert_eq!(check("*"), None);
+        assert_eq!(check("host:443"), None);
+        assert_eq!(check("https://evil"), None);
+        assert_eq!(check("https://evil/x"), None);
+        assert_eq!(check("https://tunnel.host@evil/"), None);
+        assert_eq!(check("https://user@tunnel.host/"), None);
+        assert_eq!(check("https://tunnel.host:8443/x"), None);
+        assert_eq!(check("http://tunnel.host/x"), None);
+    }
+
+    #[test]
+    fn reduces_absolute_form_naming_the_tunnel() {
+        assert_eq!(check("https://tunnel.host").as_deref(), Some("/"));
+        assert_eq!(check("https://tunnel.host?q").as_deref(), Some("/?q"));
+        assert_eq!(check("https://tunnel.host:443/x").as_deref(), Some("/x"));
+        assert_eq!(check("HTTPS://Tunnel.Host/x?y").as_deref(), Some("/x?y"));
+        assert_eq!(check("https://tunnel.host//evil"), None);
+    }
+
+    #[test]
+    fn refuses_malformed_targets() {
+        assert_eq!(check("/a#frag"), None);
+        assert_eq!(check("https://tunnel.host/a#frag"), None);
+        assert_eq!(check("/caf\u{e9}"), None);
+        assert_eq!(check("/a b"), None);
+        assert_eq!(check("/a\tb"), None);
+        assert_eq!(check(""), None);
+    }
+}
@@ -0,0 +1,346 @@
+//! Response body buffering with spill-to-disk.
+//!
+//! A response body is read from the local server as fast as it comes and
+//! waits here for the tunnel, so a slow client doesn't hold the local
+//! server up. Small bodies stay in memory, and past a threshold the body
+//! moves to a temp file, so a multi-gigabyte download costs disk space
+//! instead of memory.
+//!
+//! Disk writes never happen on the IO loop: the thread reading the body
+//! from the local server writes it out.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use dotunnel::transport::message::Header;
+use tracing::debug;
+
+use super::upstream::UpstreamBody;
+
+/// Bytes of a response body read ahead in memory before the rest goes to
+/// disk. Kept small: every response being downloaded holds this much.
+const RESPONSE_SPILL_THRESHOLD: usize = 1024 * 1024;
+
+/// Read size when reading a response body ahead.
+const READ_AHEAD_SIZE: usize = 64 * 1024;
+
+/// Temp file holding a spilled body; deleted on drop.
+struct SpoolFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl SpoolFile {
+    /// Create the file, keeping its name for now.
+    fn open(stream_id: u32) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "dotunnel-{}-{}-{:016x}.body",
+            std::process::id(),
+            stream_id,
+            fastrand::u64(..)
+        ));
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&path)?;
+        Ok(Self { file, path })
+    }
+
+    /// Unlink right away where an open file outlives its name, so nothing
+    /// is left behind if the process dies.
+    fn unlink(&self) {
+        #[cfg(unix)]
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        #[cfg(not(unix))]
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A response body read ahead of the tunnel.
+///
+/// A thread reads the local server's body into memory, and into a temp file
+/// once [`RESPONSE_SPILL_THRESHOLD`] bytes are waiting; reads take it back
+/// out in order. Dropping the reader stops the thread once its pending read
+/// returns.
+pub(super) struct ResponseSpool {
+    shared: Arc<ReadAhead>,
+    /// Read end of the temp file, once the body has spilled
+    file: Option<File>,
+    /// Bytes read back from the temp file
+    file_pos: u64,
+}
+
+struct ReadAhead {
+    state: Mutex<ReadAheadState>,
+    changed: Condvar,
+}
+
+struct ReadAheadState {
+    /// Read before anything went to disk
+    memory: VecDeque<Bytes>,
+    memory_len: usize,
+    /// Read end of the temp file, until the reader takes it
+    file: Option<File>,
+    spilled: bool,
+    /// Bytes written to the temp file
+    file_len: u64,
+    /// How the body ended, once it has
+    end: Option<io::Result<()>>,
+    trailers: io::Result<Vec<Header>>,
+    /// The reader is gone
+    cancelled: bool,
+}
+
+impl ResponseSpool {
+    /// Start reading `body` ahead on a thread of its own.
+    pub(super) fn start(stream_id: u32, body: Box<dyn UpstreamBody>) -> io::Result<Self> {
+        let shared = Arc::new(ReadAhead {
+            state: Mutex::new(ReadAheadState {
+                memory: VecDeque::new(),
+                memory_len: 0,
+                file: None,
+                spilled: false,
+                file_len: 0,
+                end: None,
+                trailers: Ok(Vec::new()),
+                cancelled: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let ahead = shared.clone();
+        thread::Builder::new()
+            .name("dotunnel-read-ahead".to_string())
+            .spawn(move || read_ahead(stream_id, body, &ahead))?;
+        Ok(Self {
+            shared,
+            file: None,
+            file_pos: 0,
+        })
+    }
+}
+
+impl Read for ResponseSpool {
+    /// Fill `buf` with what has been read ahead, waiting only while nothing
+    /// has.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if !state.memory.is_empty() {
+                let mut filled = 0;
+                while filled < buf.len()
+                    && let Some(chunk) = state.memory.front_mut()
+                {
+                    let n = chunk.len().min(buf.len() - filled);
+                    buf[filled..filled + n].copy_from_slice(&chunk[..n]);
+                    filled += n;
+                    if n == chunk.len() {
+                        state.memory.pop_front();
+                    } else {
+                        *chunk = chunk.slice(n..);
+                    }
+                }
+                state.memory_len -= filled;
+                self.shared.changed.notify_all();
+                return Ok(filled);
+            }
+
+            if self.file.is_none() {
+                self.file = state.file.take();
+            }
+            let available = state.file_len - self.file_pos;
+            if available > 0
+                && let Some(file) = &mut self.file
+            {
+                drop(state);
+                let want = buf.len().min(available.try_into().unwrap_or(usize::MAX));
+                let n = file.read(&mut buf[..want])?;
+                self.file_pos += n as u64;
+                return Ok(n);
+            }
+
+            match state.end.take() {
+                Some(Ok(())) => {
+                    state.end = Some(Ok(()));
+                    return Ok(0);
+                }
+                // Reported once, like the read it came from
+                Some(Err(e)) => {
+                    state.end = Some(Ok(()));
+                    return Err(e);
+                }
+                None => state = self.shared.changed.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+impl UpstreamBody for ResponseSpool {
+    fn trailers(&mut self) -> io::Result<Vec<Header>> {
+        let mut state = self.shared.state.lock().unwrap();
+        std::mem::replace(&mut state.trailers, Ok(Vec::new()))
+    }
+}
+
+impl Drop for ResponseSpool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().cancelled = true;
+    }
+}
+
+/// Read `body` to the end into `ahead`.
+fn read_ahead(stream_id: u32, mut body: Box<dyn UpstreamBody>, ahead: &ReadAhead) {
+    let mut buf = vec![0u8; READ_AHEAD_SIZE];
+    let mut spill: Option<SpoolFile> = None;
+
+    let end = loop {
+        if ahead.state.lock().unwrap().cancelled {
+            return;
+        }
+        let n = match body.read(&mut buf) {
+            Ok(0) => {
+                let trailers = body.trailers();
+                ahead.state.lock().unwrap().trailers = trailers;
+                break Ok(());
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+
+        let mut state = ahead.state.lock().unwrap();
+        if !state.spilled && state.memory_len + n <= RESPONSE_SPILL_THRESHOLD {
+            state.memory.push_back(Bytes::copy_from_slice(&buf[..n]));
+            state.memory_len += n;
+            drop(state);
+            ahead.changed.notify_all();
+            continue;
+        }
+        drop(state);
+
+        // Once the client falls behind, everything after goes through the
+        // file so the body stays in order
+        let file = match &mut spill {
+            Some(spool) => &mut spool.file,
+            None => match open_spill(stream_id, ahead) {
+                Ok(spool) => &mut spill.insert(spool).file,
+                Err(e) => break Err(e),
+            },
+        };
+        if let Err(e) = file.write_all(&buf[..n]) {
+            break Err(e);
+        }
+        ahead.state.lock().unwrap().file_len += n as u64;
+        ahead.changed.notify_all();
+    };
+
+    ahead.state.lock().unwrap().end = Some(end);
+    ahead.changed.notify_all();
+}
+
+/// Create the temp file a response body spills to, and hand its read end
+/// to the reader.
+fn open_spill(stream_id: u32, ahead: &ReadAhead) -> io::Result<SpoolFile> {
+    let spool = SpoolFile::open(stream_id)?;
+    let reader = File::open(&spool.path);
+    spool.unlink();
+    debug!(
+        "Stream {}: client behind by over {} response bytes, spilling to {:?}",
+        stream_id, RESPONSE_SPILL_THRESHOLD, spool.path
+    );
+
+    let mut state = ahead.state.lock().unwrap();
+    state.file = Some(reader?);
+    state.spilled = true;
+    Ok(spool)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+    use std::thread;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use dotunnel::transport::message::Header;
+
+    use super::{RESPONSE_SPILL_THRESHOLD, ResponseSpool};
+    use crate::command::tunnel::upstream::UpstreamBody;
+
+    struct Body(Cursor<Vec<u8>>);
+
+    impl Read for Body {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl UpstreamBody for Body {
+        fn trailers(&mut self) -> io::Result<Vec<Header>> {
+            Ok(vec![Header {
+                name: "grpc-status".to_string(),
+                value: Bytes::from_static(b"0"),
+            }])
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn response_read_back_in_order() {
+        let data = pattern(RESPONSE_SPILL_THRESHOLD * 3 + 1000);
+        let mut spool = ResponseSpool::start(1, Box::new(Body(Cursor::new(data.clone())))).unwrap();
+
+        // Fall behind so the rest goes to disk
+        while spool.shared.state.lock().unwrap().end.is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(spool.shared.state.lock().unwrap().spilled);
+
+        let mut body = Vec::new();
+        let mut buf = vec![0u8; 10_000];
+        loop {
+            let n = spool.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        assert!(body == data);
+        assert_eq!(spool.trailers().unwrap()[0].name, "grpc-status");
+    }
+
+    #[test]
+    fn response_keeping_up_stays_in_memory() {
+        let mut spool =
+            ResponseSpool::start(1, Box::new(Body(Cursor::new(b"small body".to_vec())))).unwrap();
+        let mut body = Vec::new();
+        spool.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"small body");
+        assert!(!spool.shared.state.lock().unwrap().spilled);
+    }
+}
@@ -0,0 +1,201 @@
+//! Protocol upgrades other than WebSocket.
+//!
+//! The request goes to the local server with its `Upgrade` header. If the
+//! server answers `101 Switching Protocols`, the stream stops being HTTP:
+//! request body chunks carry the client's bytes to the server, response
+//! body chunks carry the server's back, and each side's end is a half-close.
+//! Workers can only switch a client to WebSocket, so the relay hands the
+//! visitor a streamed response for the server's side and streams the
+//! visitor's request body in as the client's. Any other answer is forwarded
+//! as a normal response, and the request body goes nowhere.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use dotunnel::transport::message::{AbortReason, Header, HttpVersion};
+use tracing::{debug, info, warn};
+
+use super::upstream::{InterimResponse, Upgrade, Upstream};
+use super::writer::{PriorityWriter, WriteError};
+use super::{
+    forward_response, response_abort, response_body_chunk, response_end, response_init,
+    response_interim, StreamState, StreamType,
+};
+
+/// How long a read from the local server waits before client bytes get
+/// their turn.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Client chunks queued for the local server before the stream is cut off.
+pub(super) const CLIENT_QUEUE_CHUNKS: usize = 256;
+
+/// An upgrade request, and the client's bytes for after the switch.
+pub(super) struct UpgradeRequest {
+    pub(super) method: String,
+    pub(super) uri: String,
+    pub(super) headers: Vec<Header>,
+    /// None when the request has no body, so the client never sends any
+    pub(super) client_rx: Option<mpsc::Receiver<Bytes>>,
+}
+
+/// Send an upgrade request and, once switched, carry bytes both ways until
+/// the local server hangs up. Runs on a worker for the stream's lifetime.
+pub(super) fn handle_upgrade(
+    stream_id: u32,
+    upstream: &Upstream,
+    request: UpgradeRequest,
+    max_chunk_size: usize,
+    writer: PriorityWriter,
+    streams: Arc<Mutex<HashMap<u32, StreamState>>>,
+) -> Result<()> {
+    let result = upstream.upgrade(
+        &request.method,
+        &request.uri,
+        &request.headers,
+        &mut |interim: InterimResponse| {
+            let _ = writer.send_meta(stream_id, response_interim(interim.status, interim.headers));
+        },
+    );
+
+    let (headers, mut conn) = match result {
+        Ok(Upgrade::Switched { headers, conn }) => (headers, conn),
+        Ok(Upgrade::Refused(resp)) => {
+            let result = forward_response(
+                stream_id,
+                &request.method,
+                &request.uri,
+                resp,
+                max_chunk_size,
+                &writer,
+            );
+            streams.lock().unwrap().remove(&stream_id);
+            return result;
+        }
+        Err(e) => {
+            warn!(
+                "Stream {}: {} {} -> 502 ({})",
+                stream_id, request.method, request.uri, e
+            );
+            let error_body = format!("Bad Gateway: {}", e);
+            writer.send_meta(
+                stream_id,
+                response_init(
+                    502,
+                    HttpVersion::H1,
+                    Vec::new(),
+                    true,
+                    error_body.len() as u64,
+                ),
+            )?;
+            writer.send_body(
+                stream_id,
+                response_body_chunk(Bytes::from(error_body), 0, true),
+            )?;
+            writer.send_body(stream_id, response_end())?;
+            streams.lock().unwrap().remove(&stream_id);
+            return Ok(());
+        }
+    };
+
+    // Let an abort from the relay close the connection
+    let closer = conn.get_ref().closer()?;
+    {
+        let mut streams = streams.lock().unwrap();
+        match streams
+            .get_mut(&stream_id)
+            .map(|state| &mut state.stream_type)
+        {
+            Some(StreamType::Upgraded { socket, .. }) => *socket = Some(closer),
+            // Aborted while the server was answering
+            _ => {
+                closer.close();
+                return Ok(());
+            }
+        }
+    }
+
+    writer
+        .send_meta(
+            stream_id,
+            response_init(101, HttpVersion::H1, headers, true, 0),
+        )
+        .context("Failed to send upgrade response")?;
+    info!(
+        "Stream {}: {} {} -> 101",
+        stream_id, request.method, request.uri
+    );
+
+    // This worker carries both directions, as a TLS connection can't be
+    // split across threads: client bytes are written between short reads
+    conn.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut client_rx = request.client_rx;
+    let mut buf = BytesMut::new();
+    let mut chunk_seq: u32 = 0;
+    loop {
+        // Client to server, until the relay ends the request side
+        while let Some(rx) = &client_rx {
+            match rx.try_recv() {
+                Ok(data) => {
+                    if conn.get_mut().write_all(&data).is_err() {
+                        client_rx = None;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    let _ = conn.get_mut().shutdown_write();
+                    conn.get_ref().set_read_timeout(None)?;
+                    client_rx = None;
+                }
+            }
+        }
+
+        // Server to client
+        // Only what the last read handed off is zeroed again
+        if buf.len() < max_chunk_size {
+            buf.resize(max_chunk_size, 0);
+        }
+        match conn.read(&mut buf) {
+            Ok(0) => {
+                writer.send_body(stream_id, response_end())?;
+                break;
+            }
+            Ok(n) => {
+                let data = buf.split_to(n).freeze();
+                match writer.send_body(stream_id, response_body_chunk(data, chunk_seq, false)) {
+                    Ok(()) => chunk_seq += 1,
+                    Err(WriteError::Overload) => {
+                        warn!("Stream {}: tunnel too slow, closing upgrade", stream_id);
+                        writer.send_meta(
+                            stream_id,
+                            response_abort(AbortReason::Overload, "Outbound queue full"),
+                        )?;
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => {
+                debug!("Stream {}: upgraded connection failed: {}", stream_id, e);
+                writer.send_body(
+                    stream_id,
+                    response_abort(AbortReason::ResetByPeer, "Local server connection failed"),
+                )?;
+                break;
+            }
+        }
+    }
+
+    conn.get_ref().shutdown();
+    streams.lock().unwrap().remove(&stream_id);
+    debug!("Stream {}: upgraded connection closed", stream_id);
+    Ok(())
+}
@@ -0,0 +1,574 @@
+//! Forwarding requests to the local server.
+//!
+//! HTTP/1.1 goes through a client of our own, in [`http1`]. HTTP/2 is spoken in cleartext with prior
+//! knowledge (h2c), which is what gRPC servers and HTTP/2-only dev servers
+//! expect, or over TLS once ALPN agrees on it. `auto` asks the server once,
+//! with a preface in cleartext or with ALPN over TLS, and uses whichever
+//! protocol it answers in.
+
+use std::fmt;
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, Bytes};
+use dotunnel::transport::message::{Header, HttpVersion};
+use h2::client::{ResponseFuture, SendRequest};
+use h2::{RecvStream, SendStream};
+use tokio::runtime::{Handle, Runtime};
+use tracing::{debug, info};
+
+use super::request_body::{BodyPoll, RequestBody};
+use http1::Http1Client;
+use tls::Alpn;
+
+pub(super) use http1::{HeaderCase, Upgrade};
+pub(super) use target::{Closer, Conn, Target};
+pub(super) use tls::TlsOptions;
+
+mod http1;
+mod resolve;
+mod target;
+mod tls;
+
+/// How long the `auto` probe waits for the server to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Client connection preface, RFC 9113 §3.4.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// SETTINGS frame type.
+const H2_FRAME_SETTINGS: u8 = 0x4;
+
+/// How long a body held back by `Expect: 100-continue` waits for the go-ahead
+/// before it is sent anyway, as curl does.
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a request body that is slow to arrive waits on the server at a
+/// time, watching for an early response.
+const BODY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Protocol spoken to the local server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(super) enum UpstreamProtocol {
+    /// HTTP/1.1
+    Http1,
+    /// HTTP/2, with prior knowledge in cleartext or by ALPN over TLS
+    H2c,
+    /// Probe the server and use HTTP/2 if it speaks it
+    Auto,
+}
+
+/// A response from the local server, whichever protocol carried it.
+pub(super) struct UpstreamResponse {
+    pub(super) status: u16,
+    pub(super) version: HttpVersion,
+    pub(super) headers: Vec<Header>,
+    pub(super) body: Box<dyn UpstreamBody>,
+}
+
+/// A 1xx response that preceded the final one, e.g. 103 Early Hints.
+pub(super) struct InterimResponse {
+    pub(super) status: u16,
+    pub(super) headers: Vec<Header>,
+}
+
+/// A response body, and the trailers that may follow it.
+pub(super) trait UpstreamBody: Read + Send {
+    /// Trailers sent after the body; only meaningful once the body has been
+    /// read to the end.
+    fn trailers(&mut self) -> io::Result<Vec<Header>>;
+}
+
+/// The local server, shared by every session of a tunnel.
+#[derive(Clone)]
+pub(super) struct Upstream {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    target: Arc<Target>,
+    protocol: UpstreamProtocol,
+    http1: Http1Client,
+    /// What the `auto` probe found, once it has reached the server
+    speaks_h2: OnceLock<bool>,
+    /// Started on first use
+    h2: OnceLock<H2Client>,
+}
+
+impl Upstream {
+    pub(super) fn new(target: Target, protocol: UpstreamProtocol, header_case: HeaderCase) -> Self {
+        let target = Arc::new(target);
+        Self {
+            inner: Arc::new(Inner {
+                target: target.clone(),
+                protocol,
+                http1: Http1Client::new(target, header_case),
+                speaks_h2: OnceLock::new(),
+                h2: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Send a request, streaming its body as it arrives, and return once
+    /// the final response head has arrived, passing any interim responses
+    /// before it to `on_interim`.
+    pub(super) fn forward(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: Vec<Header>,
+        body: RequestBody,
+        on_interim: &mut dyn FnMut(InterimResponse),
+    ) -> Result<UpstreamResponse> {
+        if self.use_h2() {
+            self.h2_client()?
+                .forward(method, uri, &headers, body, on_interim)
+        } else {
+            self.inner
+                .http1
+                .forward(method, uri, &headers, body, on_interim)
+        }
+    }
+
+    /// Ask the local server to switch protocols. Upgrade is an HTTP/1.1
+    /// mechanism, so this speaks HTTP/1.1 whatever the upstream protocol.
+    pub(super) fn upgrade(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[Header],
+        on_interim: &mut dyn FnMut(InterimResponse),
+    ) -> Result<Upgrade> {
+        self.inner.http1.upgrade(method, uri, headers, on_interim)
+    }
+
+    fn use_h2(&self) -> bool {
+        match self.inner.protocol {
+            UpstreamProtocol::Http1 => false,
+            UpstreamProtocol::H2c => true,
+            UpstreamProtocol::Auto => {
+                if let Some(&speaks_h2) = self.inner.speaks_h2.get() {
+                    return speaks_h2;
+                }
+                match probe_h2(&self.inner.target) {
+                    Ok(speaks_h2) => {
+                        if self.inner.speaks_h2.set(speaks_h2).is_ok() {
+                            info!(
+                                "Local server speaks {}",
+                                if speaks_h2 { "HTTP/2" } else { "HTTP/1.1" }
+                            );
+                        }
+                        speaks_h2
+                    }
+                    // Probe again next time; the server may not be up yet
+                    Err(e) => {
+                        debug!("HTTP/2 probe failed: {}", e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    fn h2_client(&self) -> Result<&H2Client> {
+        if let Some(client) = self.inner.h2.get() {
+            return Ok(client);
+        }
+        let client =
+            H2Client::new(self.inner.target.clone()).context("Failed to start HTTP/2 client")?;
+        Ok(self.inner.h2.get_or_init(|| client))
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.target.fmt(f)
+    }
+}
+
+/// Whether the server speaks HTTP/2: over TLS, whether ALPN picked it, and
+/// in cleartext, whether it answers an HTTP/2 preface with SETTINGS.
+///
+/// An HTTP/1.1 server sees a malformed request and replies with an error or
+/// hangs up; nothing reaches the application either way.
+fn probe_h2(target: &Target) -> io::Result<bool> {
+    let mut conn = target.connect(Alpn::Any, Some(PROBE_TIMEOUT))?;
+    if let Conn::Tls(_) = conn {
+        return Ok(conn.negotiated_h2());
+    }
+    conn.write_all(H2_PREFACE)?;
+    // Empty SETTINGS frame on stream 0
+    conn.write_all(&[0, 0, 0, H2_FRAME_SETTINGS, 0, 0, 0, 0, 0])?;
+
+    let mut frame_header = [0u8; 9];
+    match conn.read_exact(&mut frame_header) {
+        Ok(()) => Ok(frame_header[3] == H2_FRAME_SETTINGS && frame_header[5..] == [0; 4]),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a request header must not be passed on as is.
+fn is_hop_by_hop(name: &str) -> bool {
+    name.eq_ignore_ascii_case("host")
+        || name.eq_ignore_ascii_case("connection")
+        || name.eq_ignore_ascii_case("upgrade")
+        || name.eq_ignore_ascii_case("transfer-encoding")
+        || name.eq_ignore_ascii_case("keep-alive")
+        || name.eq_ignore_ascii_case("proxy-connection")
+        || name.eq_ignore_ascii_case("accept-encoding")
+}
+
+/// Headers as h2 received them, values byte for byte.
+///
+/// h2 only hands over a `HeaderMap`, which groups repeated names where the
+/// first of them appeared; values under one name keep their order. HTTP/1.1
+/// responses never come through here and keep their exact order.
+fn header_pairs(headers: &http::HeaderMap) -> Vec<Header> {
+    headers
+        .iter()
+        .map(|(name, value)| Header {
+            name: name.to_string(),
+            value: Bytes::copy_from_slice(value.as_bytes()),
+        })
+        .collect()
+}
+
+// =============================================================================
+// HTTP/2
+// =============================================================================
+
+/// HTTP/2 connection to the local server, multiplexing every stream.
+///
+/// h2 is async, so it runs on a small runtime of its own; worker threads
+/// block on it per request and per body read.
+struct H2Client {
+    runtime: Runtime,
+    target: Arc<Target>,
+    /// The live connection, replaced when it fails
+    sender: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl H2Client {
+    fn new(target: Arc<Target>) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("dotunnel-h2")
+            .enable_io()
+            .enable_time()
+            .build()?;
+        Ok(Self {
+            runtime,
+            target,
+            sender: Mutex::new(None),
+        })
+    }
+
+    fn forward(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[Header],
+        mut body: RequestBody,
+        on_interim: &mut dyn FnMut(InterimResponse),
+    ) -> Result<UpstreamResponse> {
+        let expect_continue = headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("expect")
+                && header.value.eq_ignore_ascii_case(b"100-continue")
+        });
+
+        let mut builder = http::Request::builder().method(method).uri(format!(
+            "{}://{}{}",
+            self.target.scheme(),
+            self.target.authority(),
+            uri
+        ));
+        for header in headers {
+            // TE is connection-specific in HTTP/2, except for "trailers",
+            // which gRPC requires
+            if is_hop_by_hop(&header.name)
+                || (header.name.eq_ignore_ascii_case("te")
+                    && !header.value.eq_ignore_ascii_case(b"trailers"))
+            {
+                continue;
+            }
+            builder = builder.header(header.name.as_str(), header.value.as_ref());
+        }
+        let request = builder
+            .header("accept-encoding", "identity")
+            .body(())
+            .context("Failed to build request")?;
+
+        let (parts, recv) = self.runtime.block_on(async {
+            let mut sender = self
+                .ready_sender()
+                .await
+                .context("Failed to connect to local server over HTTP/2")?;
+
+            let empty = body.is_empty();
+            let (mut response, mut stream) = sender
+                .send_request(request, empty)
+                .context("Failed to forward request to local server")?;
+
+            // A server that answers before asking for the body doesn't get it
+            let send =
+                empty || !expect_continue || await_continue(&mut response, on_interim).await?;
+            let mut early = None;
+            if send && !empty {
+                early = send_body(&mut stream, &mut body, &mut response, on_interim)
+                    .await
+                    .context("Failed to send request body to local server")?;
+            }
+            if let Some(response) = early {
+                return anyhow::Ok(response.into_parts());
+            }
+
+            while let Some(interim) = poll_fn(|cx| response.poll_informational(cx)).await {
+                on_interim(interim_response(
+                    interim.context("Failed to forward request to local server")?,
+                ));
+            }
+            let response = response
+                .await
+                .context("Failed to forward request to local server")?;
+            anyhow::Ok(response.into_parts())
+        })?;
+
+        Ok(UpstreamResponse {
+            status: parts.status.as_u16(),
+            version: HttpVersion::H2,
+            headers: header_pairs(&parts.headers),
+            body: Box::new(H2Body {
+                handle: self.runtime.handle().clone(),
+                recv,
+                chunk: Bytes::new(),
+            }),
+        })
+    }
+
+    /// A sender with room for a new stream, connecting if there is none.
+    async fn ready_sender(&self) -> Result<SendRequest<Bytes>> {
+        let cached = self.sender.lock().unwrap().clone();
+        if let Some(sender) = cached {
+            match sender.ready().await {
+                Ok(sender) => return Ok(sender),
+                Err(e) => debug!("HTTP/2 connection to local server lost: {}", e),
+            }
+        }
+
+        let conn = self.target.connect_h2().await?;
+        let (sender, connection) = h2::client::handshake(conn).await?;
+        self.runtime.spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("HTTP/2 connection to local server closed: {}", e);
+            }
+        });
+        *self.sender.lock().unwrap() = Some(sender.clone());
+        Ok(sender.ready().await?)
+    }
+}
+
+/// Wait for `100 Continue` before sending a body, passing on other interim
+/// responses meanwhile. False if the final response came first.
+async fn await_continue(
+    response: &mut ResponseFuture,
+    on_interim: &mut dyn FnMut(InterimResponse),
+) -> Result<bool> {
+    let wait = async {
+        while let Some(interim) = poll_fn(|cx| response.poll_informational(cx)).await {
+            let interim =
+                interim_response(interim.context("Failed to forward request to local server")?);
+            let status = interim.status;
+            on_interim(interim);
+            if status == 100 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    tokio::time::timeout(EXPECT_CONTINUE_TIMEOUT, wait)
+        .await
+        .unwrap_or(Ok(true))
+}
+
+fn interim_response(response: http::Response<()>) -> InterimResponse {
+    InterimResponse {
+        status: response.status().as_u16(),
+        headers: header_pairs(response.headers()),
+    }
+}
+
+/// Send a request body as it arrives, then its trailers, respecting the
+/// server's flow control window.
+///
+/// While the body is slow to arrive, this watches for the server answering
+/// early. Interim responses are passed on at once. An error response ends
+/// the body; any other final response is kept while the body goes on, since
+/// a server may answer while it still reads.
+async fn send_body(
+    stream: &mut SendStream<Bytes>,
+    body: &mut RequestBody,
+    response: &mut ResponseFuture,
+    on_interim: &mut dyn FnMut(InterimResponse),
+) -> Result<Option<http::Response<RecvStream>>> {
+    let mut early = None;
+    loop {
+        let data = match body.poll_chunk()? {
+            BodyPoll::Chunk(data) => data,
+            BodyPoll::End => break,
+            BodyPoll::Pending if early.is_some() => {
+                tokio::time::sleep(BODY_POLL_INTERVAL).await;
+                continue;
+            }
+            BodyPoll::Pending => {
+                let informational = poll_fn(|cx| response.poll_informational(cx));
+                match tokio::time::timeout(BODY_POLL_INTERVAL, informational).await {
+                    Err(_) => {}
+                    Ok(Some(interim)) => on_interim(interim_response(
+                        interim.context("Failed to forward request to local server")?,
+                    )),
+                    // The final response is in
+                    Ok(None) => {
+                        let final_response = (&mut *response)
+                            .await
+                            .context("Failed to forward request to local server")?;
+                        if final_response.status().as_u16() >= 400 {
+                            return Ok(Some(final_response));
+                        }
+                        early = Some(final_response);
+                    }
+                }
+                continue;
+            }
+        };
+        send_data(stream, data, false).await?;
+    }
+
+    let trailers = body.take_trailers();
+    if trailers.is_empty() {
+        send_data(stream, Bytes::new(), true).await?;
+        return Ok(early);
+    }
+    let mut trailer_map = http::HeaderMap::new();
+    for trailer in trailers {
+        trailer_map.append(
+            http::HeaderName::try_from(trailer.name.as_str()).context("Invalid trailer name")?,
+            http::HeaderValue::from_bytes(&trailer.value).context("Invalid trailer value")?,
+        );
+    }
+    stream
+        .send_trailers(trailer_map)
+        .context("Failed to send request trailers to local server")?;
+    Ok(early)
+}
+
+async fn send_data(
+    stream: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+) -> Result<()> {
+    if data.is_empty() {
+        if end_of_stream {
+            stream.send_data(data, true)?;
+        }
+        return Ok(());
+    }
+
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let granted = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(granted) => granted?,
+            None => bail!("Stream closed by local server"),
+        };
+        if granted == 0 {
+            continue;
+        }
+        let chunk = data.split_to(granted.min(data.len()));
+        stream.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
+
+/// Blocking reader over an HTTP/2 response body.
+struct H2Body {
+    handle: Handle,
+    recv: RecvStream,
+    /// Received but not yet read
+    chunk: Bytes,
+}
+
+impl Read for H2Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.handle.block_on(self.recv.data()) {
+                Some(Ok(data)) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.chunk = data;
+                }
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+impl UpstreamBody for H2Body {
+    fn trailers(&mut self) -> io::Result<Vec<Header>> {
+        match self.handle.block_on(self.recv.trailers()) {
+            Ok(Some(trailers)) => Ok(header_pairs(&trailers)),
+            Ok(None) => Ok(Vec::new()),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::header_pairs;
+
+    #[test]
+    fn header_pairs_keep_values_in_order() {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            ("set-cookie", &b"a=1"[..]),
+            ("x-b", b"1"),
+            ("set-cookie", b"b=\xe9"),
+            ("set-cookie", b"c=3"),
+        ] {
+            headers.append(name, http::HeaderValue::from_bytes(value).unwrap());
+        }
+
+        let pairs: Vec<_> = header_pairs(&headers)
+            .into_iter()
+            .map(|header| (header.name, header.value))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("set-cookie".to_string(), Bytes::from_static(b"a=1")),
+                ("set-cookie".to_string(), Bytes::from_static(b"b=\xe9")),
+                ("set-cookie".to_string(), Bytes::from_static(b"c=3")),
+                ("x-b".to_string(), Bytes::from_static(b"1")),
+            ]
+        );
+    }
+}
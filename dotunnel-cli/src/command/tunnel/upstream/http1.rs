@@ -0,0 +1,1283 @@
+//! HTTP/1.1 client for the local server.
+//!
+//! A general-purpose client normalizes what it sends: it adds headers of its
+//! own, rejects unfamiliar methods and re-frames bodies. This one writes the
+//! request as the browser sent it, with any method and with headers in order
+//! and byte for byte. It frames the body itself, and streams the response
+//! back with its interim responses and chunked trailers.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use dotunnel::transport::message::{Header, HttpVersion};
+use tracing::debug;
+
+use super::super::request_body::{BodyPoll, RequestBody};
+use super::{
+    is_hop_by_hop, Alpn, Conn, InterimResponse, Target, UpstreamBody, UpstreamResponse,
+    BODY_POLL_INTERVAL, EXPECT_CONTINUE_TIMEOUT,
+};
+
+/// Largest response head or trailer block accepted.
+const MAX_HEAD_SIZE: u64 = 64 * 1024;
+
+/// Most headers parsed from one response head.
+const MAX_HEADERS: usize = 128;
+
+/// Idle connections kept for reuse.
+const MAX_IDLE: usize = 16;
+
+/// How header names are written to the local server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(in super::super) enum HeaderCase {
+    /// As received (browsers' HTTP/2 and the relay send them lowercase)
+    Preserve,
+    /// Capitalized words, e.g. `Content-Type`, for servers that care
+    Title,
+}
+
+type IdlePool = Arc<Mutex<Vec<Conn>>>;
+
+pub(super) struct Http1Client {
+    target: Arc<Target>,
+    header_case: HeaderCase,
+    /// Keep-alive connections whose last response was read to the end
+    idle: IdlePool,
+}
+
+/// A parsed response head.
+struct ResponseHead {
+    status: u16,
+    /// HTTP/1.0 responses never keep the connection
+    minor_version: u8,
+    headers: Vec<Header>,
+}
+
+impl Http1Client {
+    pub(super) fn new(target: Arc<Target>, header_case: HeaderCase) -> Self {
+        Self {
+            target,
+            header_case,
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub(super) fn forward(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[Header],
+        mut body: RequestBody,
+        on_interim: &mut dyn FnMut(InterimResponse),
+    ) -> Result<UpstreamResponse> {
+        validate_request_line(method, uri)?;
+
+        // The body is sent as it arrives, so it goes chunked unless the
+        // client said how long it is
+        let length = body.content_length();
+        let chunked = length.is_none();
+        let head = self.encode_head(method, uri, headers, length.unwrap_or(0), chunked, None);
+        let expect_continue = !body.is_empty()
+            && headers.iter().any(|header| {
+                header.name.eq_ignore_ascii_case("expect")
+                    && header.value.eq_ignore_ascii_case(b"100-continue")
+            });
+        let exchange = Exchange {
+            head: &head,
+            head_only: method.eq_ignore_ascii_case("HEAD"),
+            length,
+            expect_continue,
+        };
+
+        loop {
+            let (conn, reused) = self.connection()?;
+            let mut responded = false;
+            match self.exchange(conn, &exchange, &mut body, on_interim, &mut responded) {
+                Ok(response) => return Ok(response),
+                // The server closed an idle connection while it sat in the pool.
+                // Whether it saw the request is unknown, so only a request
+                // that may run twice, and whose body hasn't been consumed, is
+                // sent again.
+                Err(e)
+                    if reused
+                        && !responded
+                        && !body.started()
+                        && is_stale(&e)
+                        && is_idempotent(method) =>
+                {
+                    debug!("Pooled connection to local server went stale: {}", e);
+                }
+                Err(e) => return Err(e).context("Failed to forward request to local server"),
+            }
+        }
+    }
+
+    /// An idle connection if there is one, else a new one.
+    fn connection(&self) -> Result<(Conn, bool)> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok((conn, true));
+        }
+        let conn = self
+            .target
+            .connect(Alpn::Http1, None)
+            .context("Failed to connect to local server")?;
+        Ok((conn, false))
+    }
+
+    /// Ask the local server to switch protocols, as the `Upgrade` header in
+    /// `headers` requests. Always on a connection of its own, which the new
+    /// protocol keeps.
+    pub(super) fn upgrade(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[Header],
+        on_interim: &mut dyn FnMut(InterimResponse),
+    ) -> Result<Upgrade> {
+        validate_request_line(method, uri)?;
+        let protocol = header_value(headers, "upgrade").context("Not an upgrade request")?;
+        let head = self.encode_head(method, uri, headers, 0, false, Some(protocol));
+
+        let mut conn = self
+            .target
+            .connect(Alpn::Http1, None)
+            .context("Failed to connect to local server")?;
+        conn.write_all(&head)
+            .context("Failed to forward request to local server")?;
+
+        let mut reader = BufReader::new(conn);
+        let head = read_final_head(&mut reader, on_interim, &mut false)
+            .context("Failed to forward request to local server")?;
+
+        if head.status == 101 {
+            return Ok(Upgrade::Switched {
+                headers: head.headers,
+                conn: reader,
+            });
+        }
+        Ok(Upgrade::Refused(self.response(
+            reader,
+            head,
+            method.eq_ignore_ascii_case("HEAD"),
+            false,
+        )?))
+    }
+
+    fn encode_head(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[Header],
+        body_len: u64,
+        chunked: bool,
+        upgrade: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut head = Vec::with_capacity(1024);
+        head.extend_from_slice(method.as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(uri.as_bytes());
+        head.extend_from_slice(b" HTTP/1.1\r\n");
+
+        self.write_header(&mut head, "Host", self.target.authority().as_bytes());
+        for header in headers {
+            // The body is framed here, whatever framing it arrived with
+            if is_hop_by_hop(&header.name) || header.name.eq_ignore_ascii_case("content-length") {
+                continue;
+            }
+            self.write_header(&mut head, &header.name, &header.value);
+        }
+        if let Some(protocol) = upgrade {
+            self.write_header(&mut head, "Upgrade", protocol);
+            // h2c's upgrade settings are connection-specific as well
+            let connection: &[u8] = if header_value(headers, "http2-settings").is_some() {
+                b"Upgrade, HTTP2-Settings"
+            } else {
+                b"Upgrade"
+            };
+            self.write_header(&mut head, "Connection", connection);
+        }
+        // Cloudflare's edge compresses for the client
+        self.write_header(&mut head, "Accept-Encoding", b"identity");
+        if chunked {
+            self.write_header(&mut head, "Transfer-Encoding", b"chunked");
+        } else if body_len > 0 || method_expects_body(method) {
+            self.write_header(&mut head, "Content-Length", body_len.to_string().as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    fn write_header(&self, head: &mut Vec<u8>, name: &str, value: &[u8]) {
+        match self.header_case {
+            HeaderCase::Preserve => head.extend_from_slice(name.as_bytes()),
+            HeaderCase::Title => {
+                let mut upper = true;
+                for b in name.bytes() {
+                    head.push(if upper {
+                        b.to_ascii_uppercase()
+                    } else {
+                        b.to_ascii_lowercase()
+                    });
+                    upper = b == b'-';
+                }
+            }
+        }
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value);
+        head.extend_from_slice(b"\r\n");
+    }
+
+    /// Send the request on `conn` and read up to the final response head.
+    fn exchange(
+        &self,
+        conn: Conn,
+        exchange: &Exchange,
+        body: &mut RequestBody,
+        on_interim: &mut dyn FnMut(InterimResponse),
+        responded: &mut bool,
+    ) -> io::Result<UpstreamResponse> {
+        let mut reader = BufReader::new(conn);
+        // The server starts on the request while its body arrives
+        reader.get_mut().write_all(exchange.head)?;
+        let mut early = None;
+        if exchange.expect_continue {
+            early = await_continue(&mut reader, on_interim, responded)?;
+        }
+        // A server that answers before asking for the body doesn't get it
+        let mut body_sent = early.is_none();
+        if body_sent && !body.is_empty() {
+            match write_body(&mut reader, body, exchange.length, on_interim, responded)? {
+                BodyOutcome::Sent(head) => early = head,
+                BodyOutcome::Refused(head) => {
+                    early = Some(head);
+                    body_sent = false;
+                }
+            }
+        }
+        reader.get_mut().flush()?;
+
+        let head = match early {
+            Some(head) => head,
+            None => read_final_head(&mut reader, on_interim, responded)?,
+        };
+
+        self.response(reader, head, exchange.head_only, body_sent)
+    }
+
+    /// The final response, reading its body from `reader`. A `reusable`
+    /// connection goes back to the pool once the body is read, if the
+    /// response allows it.
+    fn response(
+        &self,
+        reader: BufReader<Conn>,
+        head: ResponseHead,
+        head_only: bool,
+        reusable: bool,
+    ) -> io::Result<UpstreamResponse> {
+        let framing = framing(head_only, &head)?;
+        let keep_alive = reusable && keeps_alive(&head, &framing);
+        let body = Http1Body::new(reader, framing, keep_alive.then(|| self.idle.clone()));
+
+        // The body is re-framed on the way out, so its framing stays here.
+        // Transfer-Encoding overrides a Content-Length sent alongside it.
+        let transfer_encoded = header_value(&head.headers, "transfer-encoding").is_some();
+        let mut headers = head.headers;
+        headers.retain(|header| {
+            let name = header.name.as_str();
+            let framing = ["transfer-encoding", "connection", "keep-alive"]
+                .iter()
+                .any(|n| name.eq_ignore_ascii_case(n))
+                || (transfer_encoded && name.eq_ignore_ascii_case("content-length"));
+            !framing
+        });
+
+        Ok(UpstreamResponse {
+            status: head.status,
+            version: HttpVersion::H1,
+            headers,
+            body: Box::new(body),
+        })
+    }
+}
+
+/// The local server's answer to an upgrade request.
+pub(in super::super) enum Upgrade {
+    /// 101: the connection now carries the new protocol, starting with
+    /// whatever is left in the reader
+    Switched {
+        headers: Vec<Header>,
+        conn: BufReader<Conn>,
+    },
+    /// Any other answer, forwarded as a normal response
+    Refused(UpstreamResponse),
+}
+
+/// What is sent, the same on every attempt.
+struct Exchange<'a> {
+    head: &'a [u8],
+    head_only: bool,
+    /// The body's Content-Length; None if it is sent chunked
+    length: Option<u64>,
+    expect_continue: bool,
+}
+
+/// How writing a request body ended.
+enum BodyOutcome {
+    /// All of it went out, and maybe the final response head already came
+    Sent(Option<ResponseHead>),
+    /// The server answered with an error before the body was through
+    Refused(ResponseHead),
+}
+
+impl ResponseHead {
+    fn into_interim(self) -> InterimResponse {
+        InterimResponse {
+            status: self.status,
+            headers: self.headers,
+        }
+    }
+}
+
+/// Read past any interim responses, passing them to `on_interim`, to the
+/// final response head.
+fn read_final_head(
+    reader: &mut impl BufRead,
+    on_interim: &mut dyn FnMut(InterimResponse),
+    responded: &mut bool,
+) -> io::Result<ResponseHead> {
+    loop {
+        let head = read_head(reader, responded)?;
+        if !is_interim(head.status) {
+            return Ok(head);
+        }
+        on_interim(head.into_interim());
+    }
+}
+
+/// Wait for `100 Continue` before sending a body, passing on other interim
+/// responses meanwhile. Returns the final response head if it came first.
+fn await_continue(
+    reader: &mut BufReader<Conn>,
+    on_interim: &mut dyn FnMut(InterimResponse),
+    responded: &mut bool,
+) -> io::Result<Option<ResponseHead>> {
+    loop {
+        if reader.buffer().is_empty() {
+            reader
+                .get_ref()
+                .set_read_timeout(Some(EXPECT_CONTINUE_TIMEOUT))?;
+            let filled = reader.fill_buf().map(|_| ());
+            reader.get_ref().set_read_timeout(None)?;
+            match filled {
+                Ok(_) => {}
+                // Silence means go ahead
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let head = read_head(reader, responded)?;
+        if head.status == 100 {
+            on_interim(head.into_interim());
+            return Ok(None);
+        }
+        if is_interim(head.status) {
+            on_interim(head.into_interim());
+            continue;
+        }
+        return Ok(Some(head));
+    }
+}
+
+/// Write the body as it arrives, with the framing announced in the head:
+/// exactly `length` bytes, or chunked when there is no length, followed by
+/// any trailers.
+///
+/// While the body is slow to arrive, this watches for the server answering
+/// early. Interim responses are passed on at once. An error response ends
+/// the body, as RFC 9112 §9.5 asks; any other final response is kept while
+/// the body goes on, since a server may answer while it still reads.
+fn write_body(
+    reader: &mut BufReader<Conn>,
+    body: &mut RequestBody,
+    length: Option<u64>,
+    on_interim: &mut dyn FnMut(InterimResponse),
+    responded: &mut bool,
+) -> io::Result<BodyOutcome> {
+    let mut early = None;
+    let mut written: u64 = 0;
+    let mut frame = Vec::new();
+    loop {
+        let data = match body.poll_chunk()? {
+            BodyPoll::Chunk(data) => data,
+            BodyPoll::End => break,
+            BodyPoll::Pending if early.is_some() => match body.next_chunk()? {
+                Some(data) => data,
+                None => break,
+            },
+            BodyPoll::Pending => {
+                if let Some(head) = poll_response(reader, on_interim, responded)? {
+                    if head.status >= 400 {
+                        return Ok(BodyOutcome::Refused(head));
+                    }
+                    early = Some(head);
+                }
+                continue;
+            }
+        };
+
+        written += data.len() as u64;
+        match length {
+            Some(length) if written > length => {
+                return Err(invalid_data("request body longer than its Content-Length"));
+            }
+            Some(_) => reader.get_mut().write_all(&data)?,
+            None => {
+                frame.clear();
+                write!(frame, "{:x}\r\n", data.len())?;
+                frame.extend_from_slice(&data);
+                frame.extend_from_slice(b"\r\n");
+                reader.get_mut().write_all(&frame)?;
+            }
+        }
+    }
+
+    let trailers = body.take_trailers();
+    match length {
+        Some(length) if written < length => {
+            return Err(invalid_data("request body shorter than its Content-Length"));
+        }
+        // Trailers can only follow a chunked body
+        Some(_) => {
+            if !trailers.is_empty() {
+                debug!("Dropping request trailers after a body with a Content-Length");
+            }
+        }
+        None => {
+            frame.clear();
+            frame.extend_from_slice(b"0\r\n");
+            for trailer in &trailers {
+                frame.extend_from_slice(trailer.name.as_bytes());
+                frame.extend_from_slice(b": ");
+                frame.extend_from_slice(&trailer.value);
+                frame.extend_from_slice(b"\r\n");
+            }
+            frame.extend_from_slice(b"\r\n");
+            reader.get_mut().write_all(&frame)?;
+        }
+    }
+    Ok(BodyOutcome::Sent(early))
+}
+
+/// Wait briefly for the server to say something while the request is
+/// still going out. Interim responses go to `on_interim`; a final response
+/// head is returned.
+fn poll_response(
+    reader: &mut BufReader<Conn>,
+    on_interim: &mut dyn FnMut(InterimResponse),
+    responded: &mut bool,
+) -> io::Result<Option<ResponseHead>> {
+    if reader.buffer().is_empty() {
+        reader
+            .get_ref()
+            .set_read_timeout(Some(BODY_POLL_INTERVAL))?;
+        let filled = reader.fill_buf().map(|_| ());
+        reader.get_ref().set_read_timeout(None)?;
+        match filled {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let head = read_head(reader, responded)?;
+    if is_interim(head.status) {
+        on_interim(head.into_interim());
+        return Ok(None);
+    }
+    Ok(Some(head))
+}
+
+/// Read and parse one response head.
+fn read_head(reader: &mut impl BufRead, responded: &mut bool) -> io::Result<ResponseHead> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        let line_start = buf.len();
+        let n = reader
+            .by_ref()
+            .take(MAX_HEAD_SIZE - buf.len() as u64)
+            .read_until(b'\n', &mut buf)?;
+        if n == 0 {
+            return Err(if buf.len() as u64 >= MAX_HEAD_SIZE {
+                invalid_data("response head too large")
+            } else if buf.is_empty() {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed before response",
+                )
+            } else {
+                io::ErrorKind::UnexpectedEof.into()
+            });
+        }
+        *responded = true;
+        if matches!(&buf[line_start..], b"\r\n" | b"\n") {
+            break;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&buf) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Err(invalid_data("incomplete response head")),
+        Err(e) => return Err(invalid_data(format!("malformed response head: {}", e))),
+    }
+
+    Ok(ResponseHead {
+        status: response.code.unwrap_or_default(),
+        minor_version: response.version.unwrap_or_default(),
+        headers: response
+            .headers
+            .iter()
+            .map(|header| Header {
+                name: header.name.to_string(),
+                value: Bytes::copy_from_slice(header.value),
+            })
+            .collect(),
+    })
+}
+
+/// How the response body is delimited, per RFC 9112 §6.3. A length that
+/// can't be read with certainty is an error rather than a guess, so the
+/// next response on the connection can't be read out of this one's body.
+fn framing(head_only: bool, head: &ResponseHead) -> io::Result<Framing> {
+    if head_only || head.status == 204 || head.status == 304 || is_interim(head.status) {
+        return Ok(Framing::Done);
+    }
+
+    // Chunked only counts as the final coding, across every field
+    let mut codings = head
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|header| header.value.split(|&b| b == b','))
+        .map(<[u8]>::trim_ascii)
+        .filter(|coding| !coding.is_empty())
+        .peekable();
+    if codings.peek().is_some() {
+        return Ok(match codings.last() {
+            Some(coding) if coding.eq_ignore_ascii_case(b"chunked") => {
+                Framing::Chunked { remaining: 0 }
+            }
+            _ => Framing::Eof,
+        });
+    }
+
+    let mut lengths = head
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("content-length"))
+        .flat_map(|header| header.value.split(|&b| b == b','));
+    let Some(len) = lengths.next() else {
+        return Ok(Framing::Eof);
+    };
+    if lengths.next().is_some() {
+        return Err(invalid_data("more than one Content-Length"));
+    }
+    let len = len.trim_ascii();
+    let len = std::str::from_utf8(len)
+        .ok()
+        .filter(|len| !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|len| len.parse::<u64>().ok())
+        .ok_or_else(|| invalid_data("invalid Content-Length"))?;
+    Ok(if len == 0 {
+        Framing::Done
+    } else {
+        Framing::Length(len)
+    })
+}
+
+/// Whether the connection can carry another request once this response's
+/// body is read.
+fn keeps_alive(head: &ResponseHead, framing: &Framing) -> bool {
+    // A server that sends both can't be trusted to frame the next response
+    let ambiguous = header_value(&head.headers, "transfer-encoding").is_some()
+        && header_value(&head.headers, "content-length").is_some();
+    head.minor_version == 1
+        && !ambiguous
+        && !matches!(framing, Framing::Eof)
+        && !has_token(&head.headers, "connection", "close")
+}
+
+// =============================================================================
+// Response Body
+// =============================================================================
+
+enum Framing {
+    /// Bytes left of a Content-Length body
+    Length(u64),
+    /// Bytes left of the current chunk; 0 between chunks
+    Chunked {
+        remaining: u64,
+    },
+    /// Delimited by the server closing the connection
+    Eof,
+    Done,
+}
+
+struct Http1Body<S = Conn> {
+    /// Taken once the body is done
+    conn: Option<BufReader<S>>,
+    framing: Framing,
+    trailers: Vec<Header>,
+    /// Where the connection goes once the body is done, if it may be reused
+    pool: Option<Arc<Mutex<Vec<S>>>>,
+}
+
+impl<S: Read> Http1Body<S> {
+    fn new(conn: BufReader<S>, framing: Framing, pool: Option<Arc<Mutex<Vec<S>>>>) -> Self {
+        let mut body = Self {
+            conn: Some(conn),
+            framing,
+            trailers: Vec::new(),
+            pool,
+        };
+        if matches!(body.framing, Framing::Done) {
+            body.release();
+        }
+        body
+    }
+
+    /// Done with the connection: back to the pool, or closed.
+    fn release(&mut self) {
+        self.framing = Framing::Done;
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // Anything left over means the server sent more than it framed
+        if let Some(pool) = &self.pool
+            && conn.buffer().is_empty()
+        {
+            let mut idle = pool.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(conn.into_inner());
+            }
+        }
+    }
+
+    /// Start the next chunk, or read the trailers after the last one.
+    fn next_chunk(&mut self) -> io::Result<u64> {
+        let conn = self.conn.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+        let mut line = Vec::new();
+        conn.by_ref()
+            .take(MAX_HEAD_SIZE)
+            .read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // Chunk extensions follow a ';' and are ignored
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size.trim_ascii())
+            .ok()
+            .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|size| u64::from_str_radix(size, 16).ok())
+            .ok_or_else(|| invalid_data("invalid chunk size"))?;
+        if size > 0 {
+            return Ok(size);
+        }
+
+        let mut block = Vec::new();
+        loop {
+            let line_start = block.len();
+            let n = conn
+                .by_ref()
+                .take(MAX_HEAD_SIZE - block.len() as u64)
+                .read_until(b'\n', &mut block)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if matches!(&block[line_start..], b"\r\n" | b"\n") {
+                break;
+            }
+        }
+        if block.len() > 2 {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            match httparse::parse_headers(&block, &mut headers) {
+                Ok(httparse::Status::Complete((_, headers))) => {
+                    self.trailers = headers
+                        .iter()
+                        .map(|header| Header {
+                            name: header.name.to_string(),
+                            value: Bytes::copy_from_slice(header.value),
+                        })
+                        .collect();
+                }
+                _ => return Err(invalid_data("malformed trailers")),
+            }
+        }
+        Ok(0)
+    }
+
+    fn read_framed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let limit = match self.framing {
+                Framing::Done => return Ok(0),
+                Framing::Length(remaining) => remaining,
+                Framing::Chunked { remaining: 0 } => {
+                    let size = self.next_chunk()?;
+                    if size == 0 {
+                        self.release();
+                        return Ok(0);
+                    }
+                    self.framing = Framing::Chunked { remaining: size };
+                    continue;
+                }
+                Framing::Chunked { remaining } => remaining,
+                Framing::Eof => u64::MAX,
+            };
+
+            let conn = self.conn.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+            let want = buf.len().min(usize::try_from(limit).unwrap_or(usize::MAX));
+            let n = conn.read(&mut buf[..want])?;
+            if n == 0 {
+                if matches!(self.framing, Framing::Eof) {
+                    self.release();
+                    return Ok(0);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            match &mut self.framing {
+                Framing::Length(remaining) => {
+                    *remaining -= n as u64;
+                    if *remaining == 0 {
+                        self.release();
+                    }
+                }
+                Framing::Chunked { remaining } => {
+                    *remaining -= n as u64;
+                    if *remaining == 0 {
+                        let mut crlf = Vec::with_capacity(2);
+                        conn.by_ref().take(2).read_until(b'\n', &mut crlf)?;
+                        if !matches!(&crlf[..], b"\r\n" | b"\n") {
+                            return Err(invalid_data("chunk not terminated"));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            return Ok(n);
+        }
+    }
+}
+
+impl<S: Read> Read for Http1Body<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let result = self.read_framed(buf);
+        if result.is_err() {
+            // Where the next response starts is lost, so the connection is
+            // closed rather than pooled
+            self.pool = None;
+            self.conn = None;
+        }
+        result
+    }
+}
+
+impl<S: Read + Send> UpstreamBody for Http1Body<S> {
+    fn trailers(&mut self) -> io::Result<Vec<Header>> {
+        Ok(std::mem::take(&mut self.trailers))
+    }
+}
+
+// =============================================================================
+// Helpers
+// =============================================================================
+
+/// Refuse what would corrupt the request line.
+fn validate_request_line(method: &str, uri: &str) -> Result<()> {
+    if method.is_empty() || !method.bytes().all(is_tchar) {
+        bail!("Invalid request method {:?}", method);
+    }
+    if uri.is_empty() || uri.bytes().any(|b| b <= b' ' || b == 0x7f) {
+        bail!("Invalid request target {:?}", uri);
+    }
+    Ok(())
+}
+
+/// A token character, RFC 9110 §5.6.2.
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Methods whose empty body is still announced as `Content-Length: 0`.
+fn method_expects_body(method: &str) -> bool {
+    ["POST", "PUT", "PATCH"]
+        .iter()
+        .any(|m| method.eq_ignore_ascii_case(m))
+}
+
+/// 1xx other than 101, which ends HTTP/1.1 on the connection.
+fn is_interim(status: u16) -> bool {
+    (100..200).contains(&status) && status != 101
+}
+
+fn header_value<'a>(headers: &'a [Header], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_ref())
+}
+
+/// Whether a comma-separated header lists `token`.
+fn has_token(headers: &[Header], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(name))
+        .flat_map(|header| header.value.split(|&b| b == b','))
+        .any(|value| value.trim_ascii().eq_ignore_ascii_case(token.as_bytes()))
+}
+
+/// Methods that can be sent again without changing the outcome, RFC 9110
+/// §9.2.2.
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .iter()
+        .any(|m| method.eq_ignore_ascii_case(m))
+}
+
+/// A failure that a fresh connection might not have.
+fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use dotunnel::transport::message::Header;
+
+    use super::super::{Target, TlsOptions};
+    use super::{
+        framing, keeps_alive, read_final_head, read_head, Framing, HeaderCase, Http1Body,
+        Http1Client, ResponseHead, UpstreamBody, MAX_HEAD_SIZE,
+    };
+    use crate::command::tunnel::request_body::{BodyEvent, RequestBody};
+
+    type Stream = Cursor<Vec<u8>>;
+
+    /// The response head read from `raw`, and the stream left after it.
+    fn parse(raw: &[u8]) -> (ResponseHead, BufReader<Stream>) {
+        let mut reader = BufReader::new(Cursor::new(raw.to_vec()));
+        let head = read_final_head(&mut reader, &mut |_| {}, &mut false).unwrap();
+        (head, reader)
+    }
+
+    /// The body of the response in `raw`, read to the end, and whether its
+    /// connection went back to the pool.
+    fn body(
+        raw: &[u8],
+        head_only: bool,
+    ) -> (std::io::Result<Vec<u8>>, Vec<(String, String)>, bool) {
+        let (head, reader) = parse(raw);
+        let pool = Arc::new(Mutex::new(Vec::new()));
+        let framing = framing(head_only, &head).unwrap();
+        let keep_alive = keeps_alive(&head, &framing);
+        let mut body = Http1Body::new(reader, framing, keep_alive.then(|| pool.clone()));
+        let mut data = Vec::new();
+        let result = body.read_to_end(&mut data).map(|_| data);
+        let trailers = body
+            .trailers()
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.name, String::from_utf8(t.value.to_vec()).unwrap()))
+            .collect();
+        let pooled = !pool.lock().unwrap().is_empty();
+        (result, trailers, pooled)
+    }
+
+    #[test]
+    fn chunked_with_extensions_and_trailers() {
+        let (data, trailers, pooled) = body(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n6 ; ext\r\n world\r\n\
+              0\r\nGrpc-Status: 0\r\nGrpc-Message: ok\r\n\r\n",
+            false,
+        );
+        assert_eq!(data.unwrap(), b"hello world");
+        assert_eq!(
+            trailers,
+            [
+                ("Grpc-Status".to_string(), "0".to_string()),
+                ("Grpc-Message".to_string(), "ok".to_string()),
+            ]
+        );
+        assert!(pooled);
+    }
+
+    #[test]
+    fn chunked_not_final_coding_is_close_delimited() {
+        let (head, _) = parse(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n",
+        );
+        assert!(matches!(framing(false, &head).unwrap(), Framing::Eof));
+    }
+
+    #[test]
+    fn invalid_chunk_is_never_pooled() {
+        for raw in [
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+        ] {
+            let (data, _, pooled) = body(raw, false);
+            assert!(data.is_err());
+            assert!(!pooled);
+        }
+    }
+
+    #[test]
+    fn content_length_body() {
+        let (data, _, pooled) = body(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", false);
+        assert_eq!(data.unwrap(), b"hello");
+        assert!(pooled);
+    }
+
+    #[test]
+    fn content_length_short_read() {
+        let (data, _, pooled) = body(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello", false);
+        assert_eq!(data.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(!pooled);
+    }
+
+    #[test]
+    fn content_length_overrun_is_never_pooled() {
+        let (data, _, pooled) = body(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 200 OK\r\n\r\n",
+            false,
+        );
+        assert_eq!(data.unwrap(), b"hello");
+        assert!(!pooled);
+    }
+
+    #[test]
+    fn ambiguous_content_length_is_refused() {
+        for raw in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: +5\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: \r\n\r\n",
+        ] {
+            let (head, _) = parse(raw);
+            assert!(framing(false, &head).is_err());
+        }
+    }
+
+    #[test]
+    fn transfer_encoding_with_content_length_is_never_pooled() {
+        let (data, _, pooled) = body(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n0\r\n\r\n",
+            false,
+        );
+        assert_eq!(data.unwrap(), b"hello");
+        assert!(!pooled);
+    }
+
+    #[test]
+    fn close_delimited_body() {
+        let (data, _, pooled) = body(b"HTTP/1.1 200 OK\r\n\r\nuntil the end", false);
+        assert_eq!(data.unwrap(), b"until the end");
+        assert!(!pooled);
+
+        let (data, _, pooled) = body(b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok", false);
+        assert_eq!(data.unwrap(), b"ok");
+        assert!(!pooled);
+    }
+
+    #[test]
+    fn bodiless_responses() {
+        let (data, _, pooled) = body(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true);
+        assert!(data.unwrap().is_empty());
+        assert!(pooled);
+
+        for status in ["204 No Content", "304 Not Modified"] {
+            let raw = format!("HTTP/1.1 {}\r\nContent-Length: 5\r\n\r\n", status);
+            let (data, _, pooled) = body(raw.as_bytes(), false);
+            assert!(data.unwrap().is_empty());
+            assert!(pooled);
+        }
+    }
+
+    #[test]
+    fn interim_responses_before_final() {
+        let mut reader = BufReader::new(Cursor::new(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        ));
+        let mut interim = Vec::new();
+        let head = read_final_head(
+            &mut reader,
+            &mut |response| interim.push((response.status, response.headers.len())),
+            &mut false,
+        )
+        .unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(interim, [(100, 0), (103, 1)]);
+    }
+
+    #[test]
+    fn oversized_head_is_refused() {
+        let mut raw = b"HTTP/1.1 200 OK\r\nX-Big: ".to_vec();
+        raw.resize(MAX_HEAD_SIZE as usize + 1, b'a');
+        raw.extend_from_slice(b"\r\n\r\n");
+        let mut reader = BufReader::new(Cursor::new(raw));
+        let e = read_head(&mut reader, &mut false).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_trailers_are_refused() {
+        let mut raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Big: ".to_vec();
+        raw.resize(raw.len() + MAX_HEAD_SIZE as usize, b'a');
+        raw.extend_from_slice(b"\r\n\r\n");
+        let (data, _, pooled) = body(&raw, false);
+        assert!(data.is_err());
+        assert!(!pooled);
+    }
+
+    /// A listener standing in for the local server, and a client for it.
+    fn local_server() -> (TcpListener, Http1Client) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let tls = TlsOptions {
+            server_name: None,
+            ca_file: None,
+            insecure: false,
+        };
+        let client = Http1Client::new(
+            Arc::new(Target::parse(&url, None, &tls).unwrap()),
+            HeaderCase::Preserve,
+        );
+        (listener, client)
+    }
+
+    fn read_request_head(reader: &mut impl BufRead) -> String {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        head
+    }
+
+    #[test]
+    fn request_goes_out_before_its_body_arrives() {
+        let (listener, client) = local_server();
+        let (head_tx, head_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            head_tx.send(read_request_head(&mut reader)).unwrap();
+
+            let mut body = Vec::new();
+            while !body.ends_with(b"0\r\n\r\n") {
+                reader.read_until(b'\n', &mut body).unwrap();
+            }
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            body
+        });
+
+        let (body_tx, body) = RequestBody::channel(None);
+        let forward = thread::spawn(move || {
+            client
+                .forward("POST", "/upload", &[], body, &mut |_| {})
+                .map(|response| response.status)
+        });
+
+        // The server has the head while the body is still to come
+        let head = head_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(head.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+
+        body_tx
+            .send(BodyEvent::Data(Bytes::from_static(b"hello")))
+            .unwrap();
+        body_tx.send(BodyEvent::End).unwrap();
+        assert_eq!(forward.join().unwrap().unwrap(), 200);
+        assert_eq!(server.join().unwrap(), b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn continue_is_passed_on_before_the_body() {
+        let (listener, client) = local_server();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_request_head(&mut reader);
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .unwrap();
+
+            let mut body = vec![0u8; 5];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            body
+        });
+
+        let (body_tx, body) = RequestBody::channel(Some(5));
+        let (interim_tx, interim_rx) = mpsc::channel();
+        let headers = [Header {
+            name: "expect".to_string(),
+            value: Bytes::from_static(b"100-continue"),
+        }];
+        let forward = thread::spawn(move || {
+            client
+                .forward("PUT", "/upload", &headers, body, &mut |interim| {
+                    interim_tx.send(interim.status).unwrap();
+                })
+                .map(|response| response.status)
+        });
+
+        // The go-ahead reaches the client before it has sent anything
+        let status = interim_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(status, 100);
+
+        body_tx
+            .send(BodyEvent::Data(Bytes::from_static(b"hello")))
+            .unwrap();
+        body_tx.send(BodyEvent::End).unwrap();
+        assert_eq!(forward.join().unwrap().unwrap(), 200);
+        assert_eq!(server.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn early_error_response_ends_the_body() {
+        let (listener, client) = local_server();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_request_head(&mut reader);
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 413 Content Too Large\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            reader
+        });
+
+        // The body never comes, yet the response does
+        let (_body_tx, body) = RequestBody::channel(Some(1 << 30));
+        let response = client
+            .forward("POST", "/upload", &[], body, &mut |_| {})
+            .unwrap();
+        assert_eq!(response.status, 413);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn headers_keep_their_order_both_ways() {
+        let header = |name: &str, value: &'static [u8]| Header {
+            name: name.to_string(),
+            value: Bytes::from_static(value),
+        };
+        let (listener, client) = local_server();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                reader.read_until(b'\n', &mut head).unwrap();
+            }
+            reader
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nX-B: 1\r\n\
+                      Set-Cookie: b=\xe9\r\nX-B: 2\r\nContent-Length: 0\r\n\r\n",
+                )
+                .unwrap();
+            head
+        });
+
+        // Repeated names interleaved with others, and a value that isn't
+        // UTF-8
+        let request = [
+            header("X-A", b"1"),
+            header("Cookie", b"a=1"),
+            header("X-A", b"2"),
+            header("Authorization", b"Legacy \xff\x01"),
+        ];
+        let response = client
+            .forward("GET", "/", &request, RequestBody::empty(), &mut |_| {})
+            .unwrap();
+
+        let head = server.join().unwrap();
+        let sent = head.split(|&b| b == b'\n').skip(2).collect::<Vec<_>>();
+        assert_eq!(
+            sent[..4],
+            [
+                &b"X-A: 1\r"[..],
+                b"Cookie: a=1\r",
+                b"X-A: 2\r",
+                b"Authorization: Legacy \xff\x01\r",
+            ]
+        );
+
+        let received: Vec<_> = response
+            .headers
+            .iter()
+            .map(|header| (header.name.as_str(), header.value.as_ref()))
+            .collect();
+        assert_eq!(
+            received,
+            [
+                ("Set-Cookie", &b"a=1"[..]),
+                ("X-B", b"1"),
+                ("Set-Cookie", b"b=\xe9"),
+                ("X-B", b"2"),
+                ("Content-Length", b"0"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Resolving the local server's host name, and connecting to whichever of
+//! its addresses answers.
+//!
+//! `localhost` commonly resolves to both `::1` and `127.0.0.1` while a dev
+//! server listens on only one of them. Every address is tried, Happy
+//! Eyeballs style (RFC 8305): the two families take turns, starting with the
+//! address that last worked, and each attempt gets a head start before the
+//! next one begins alongside it.
+
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, info};
+
+/// Head start each attempt gets before the next begins, RFC 8305 §5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long resolved addresses are used before the name is looked up again.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The addresses of a host name, kept fresh.
+pub(super) struct Resolver {
+    host: String,
+    port: u16,
+    state: Mutex<State>,
+}
+
+struct State {
+    addrs: Vec<SocketAddr>,
+    /// None once the addresses should be looked up again
+    resolved_at: Option<Instant>,
+    /// The address that last accepted a connection
+    preferred: Option<SocketAddr>,
+}
+
+impl Resolver {
+    pub(super) fn new(host: &str, port: u16) -> Result<Self> {
+        let addrs = lookup(host, port).context("Failed to resolve local address")?;
+        if addrs.is_empty() {
+            bail!("No addresses found for local host");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            state: Mutex::new(State {
+                addrs,
+                resolved_at: Some(Instant::now()),
+                preferred: None,
+            }),
+        })
+    }
+
+    /// Connect to the first address that answers. `timeout` bounds the
+    /// whole race.
+    pub(super) fn connect(&self, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let addrs = self.addrs();
+        match race(&addrs, timeout) {
+            Ok((addr, tcp)) => {
+                let mut state = self.state.lock().unwrap();
+                if state.preferred != Some(addr) {
+                    if addrs.len() > 1 {
+                        info!("Local server answers at {}", addr);
+                    }
+                    state.preferred = Some(addr);
+                }
+                Ok(tcp)
+            }
+            Err(e) => {
+                // The server may have moved
+                self.state.lock().unwrap().resolved_at = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// The addresses to try, in order, looking the name up again first if
+    /// the last lookup is old.
+    fn addrs(&self) -> Vec<SocketAddr> {
+        let stale = {
+            let mut state = self.state.lock().unwrap();
+            let stale = state
+                .resolved_at
+                .is_none_or(|at| at.elapsed() >= RESOLVE_INTERVAL);
+            if stale {
+                // Only one caller looks it up
+                state.resolved_at = Some(Instant::now());
+            }
+            stale
+        };
+
+        if stale {
+            match lookup(&self.host, self.port) {
+                Ok(addrs) if !addrs.is_empty() => {
+                    let mut state = self.state.lock().unwrap();
+                    if state.addrs != addrs {
+                        debug!("{} now resolves to {:?}", self.host, addrs);
+                        state.addrs = addrs;
+                    }
+                }
+                // Keep what worked before; the resolver may be down briefly
+                Ok(_) => debug!("No addresses found for {}", self.host),
+                Err(e) => debug!("Failed to resolve {}: {}", self.host, e),
+            }
+        }
+
+        let state = self.state.lock().unwrap();
+        ordered(&state.addrs, state.preferred)
+    }
+}
+
+fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Alternate the families, starting with the preferred address or, failing
+/// that, whatever the resolver put first.
+fn ordered(addrs: &[SocketAddr], preferred: Option<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = preferred
+        .or_else(|| addrs.first().copied())
+        .is_some_and(|addr| addr.is_ipv6());
+    let (mut first, second): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    if let Some(i) = first.iter().position(|&addr| Some(addr) == preferred) {
+        let addr = first.remove(i);
+        first.insert(0, addr);
+    }
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let mut second = second.into_iter();
+    for addr in first {
+        ordered.push(addr);
+        ordered.extend(second.next());
+    }
+    ordered.extend(second);
+    ordered
+}
+
+/// Start an attempt per address, each after the one before has failed or
+/// had its head start, and take the first connection made. Late winners
+/// are dropped with the channel.
+fn race(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<(SocketAddr, TcpStream)> {
+    if let [addr] = addrs {
+        return attempt(*addr, timeout).map(|tcp| (*addr, tcp));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (tx, rx) = mpsc::channel();
+    let mut queue = addrs.iter().copied();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = queue.next() {
+            let tx = tx.clone();
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            thread::Builder::new()
+                .name("dotunnel-connect".to_string())
+                .spawn(move || {
+                    let _ = tx.send((addr, attempt(addr, timeout)));
+                })?;
+            running += 1;
+        }
+        if running == 0 {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to try")
+            }));
+        }
+
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let wait = match (queue.len(), remaining) {
+            (0, None) => None,
+            (0, Some(remaining)) => Some(remaining),
+            (_, remaining) => Some(remaining.map_or(CONNECTION_ATTEMPT_DELAY, |remaining| {
+                remaining.min(CONNECTION_ATTEMPT_DELAY)
+            })),
+        };
+        let result = match wait {
+            Some(wait) => rx.recv_timeout(wait),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok((addr, Ok(tcp))) => return Ok((addr, tcp)),
+            Ok((addr, Err(e))) => {
+                debug!("Failed to connect to local server at {}: {}", addr, e);
+                running -= 1;
+                last_error = Some(e);
+            }
+            // Time for the next address to start
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!("sender held here"),
+        }
+    }
+}
+
+fn attempt(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) if timeout.is_zero() => Err(io::ErrorKind::TimedOut.into()),
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::{ordered, race, Resolver, State};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// A port on the IPv4 loopback that refuses connections.
+    fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn families_take_turns() {
+        let addrs = [
+            addr("[::1]:80"),
+            addr("[::2]:80"),
+            addr("127.0.0.1:80"),
+            addr("127.0.0.2:80"),
+            addr("127.0.0.3:80"),
+        ];
+        assert_eq!(
+            ordered(&addrs, None),
+            [
+                addr("[::1]:80"),
+                addr("127.0.0.1:80"),
+                addr("[::2]:80"),
+                addr("127.0.0.2:80"),
+                addr("127.0.0.3:80"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolver_order_picks_the_first_family() {
+        let addrs = [addr("127.0.0.1:80"), addr("[::1]:80"), addr("[::2]:80")];
+        assert_eq!(
+            ordered(&addrs, None),
+            [addr("127.0.0.1:80"), addr("[::1]:80"), addr("[::2]:80")]
+        );
+    }
+
+    #[test]
+    fn preferred_address_goes_first() {
+        let addrs = [
+            addr("[::1]:80"),
+            addr("127.0.0.1:80"),
+            addr("[::2]:80"),
+            addr("127.0.0.2:80"),
+        ];
+        assert_eq!(
+            ordered(&addrs, Some(addr("127.0.0.2:80"))),
+            [
+                addr("127.0.0.2:80"),
+                addr("[::1]:80"),
+                addr("127.0.0.1:80"),
+                addr("[::2]:80"),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_other_family() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Nothing listens on the IPv6 loopback, if there is one at all
+        let v6 = SocketAddr::new("::1".parse().unwrap(), port);
+        let v4 = listener.local_addr().unwrap();
+
+        let started = Instant::now();
+        let (winner, _tcp) = race(&[v6, v4], Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(winner, v4);
+        // A refused attempt doesn't wait out its head start
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn every_address_failing_is_an_error() {
+        let port = closed_port();
+        let addrs = [
+            SocketAddr::new("::1".parse().unwrap(), port),
+            SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+        ];
+        assert!(race(&addrs, Some(Duration::from_secs(5))).is_err());
+    }
+
+    #[test]
+    fn connect_remembers_the_address_that_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let v4 = listener.local_addr().unwrap();
+        let v6 = SocketAddr::new("::1".parse().unwrap(), v4.port());
+        let resolver = Resolver {
+            host: "localhost".to_string(),
+            port: v4.port(),
+            state: Mutex::new(State {
+                addrs: vec![v6, v4],
+                resolved_at: Some(Instant::now()),
+                preferred: None,
+            }),
+        };
+
+        resolver.connect(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(resolver.state.lock().unwrap().preferred, Some(v4));
+        // Tried first from now on
+        assert_eq!(resolver.addrs(), [v4, v6]);
+    }
+}
@@ -0,0 +1,277 @@
+//! Where the local server is, and connections to it.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use native_tls::TlsStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use url::Url;
+
+use super::resolve::Resolver;
+use super::tls::{negotiated_h2, Alpn, Tls, TlsOptions};
+
+/// The local server, as given on the command line.
+pub(in super::super) struct Target {
+    /// The URL it was given as, for display
+    url: String,
+    scheme: &'static str,
+    endpoint: Endpoint,
+    /// Sent as `Host`, or `:authority` over HTTP/2
+    authority: String,
+    tls: Option<Tls>,
+}
+
+/// Where connections go.
+enum Endpoint {
+    /// Shared with the blocking task the HTTP/2 client connects on
+    Tcp(Arc<Resolver>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Target {
+    /// An `http://` or `https://` URL without a path, or `unix:` and the
+    /// path of a socket speaking cleartext HTTP. `host_header` overrides the
+    /// host and port the server is told it was reached at; over a socket
+    /// it is `localhost` unless given, as curl sends.
+    pub(in super::super) fn parse(
+        url: &str,
+        host_header: Option<String>,
+        tls: &TlsOptions,
+    ) -> Result<Self> {
+        if let Some(path) = url.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("Upstream socket path is empty");
+            }
+            #[cfg(unix)]
+            return Ok(Self {
+                url: url.to_string(),
+                scheme: "http",
+                endpoint: Endpoint::Unix(PathBuf::from(path)),
+                authority: host_header.unwrap_or_else(|| "localhost".to_string()),
+                tls: None,
+            });
+            #[cfg(not(unix))]
+            bail!("Unix socket upstreams aren't supported on this platform");
+        }
+
+        let parsed = Url::parse(url).with_context(|| format!("Invalid upstream URL: {}", url))?;
+        let scheme = match parsed.scheme() {
+            "http" => "http",
+            "https" => "https",
+            other => bail!("Unsupported upstream scheme: {}", other),
+        };
+        if parsed.path() != "/" || parsed.query().is_some() || parsed.fragment().is_some() {
+            bail!("Upstream URL must not have a path: {}", url);
+        }
+        if !parsed.username().is_empty() || parsed.password().is_some() {
+            bail!("Upstream URL must not have credentials");
+        }
+        let host = parsed.host_str().context("Upstream URL has no host")?;
+        let port = parsed
+            .port_or_known_default()
+            .context("Upstream URL has no port")?;
+
+        let resolver = Resolver::new(host.trim_start_matches('[').trim_end_matches(']'), port)?;
+
+        let host_port = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let tls = match scheme {
+            "https" => Some(Tls::new(
+                host.trim_start_matches('[').trim_end_matches(']'),
+                tls,
+            )?),
+            _ => None,
+        };
+        Ok(Self {
+            url: format!("{}://{}", scheme, host_port),
+            scheme,
+            endpoint: Endpoint::Tcp(Arc::new(resolver)),
+            authority: host_header.unwrap_or(host_port),
+            tls,
+        })
+    }
+
+    pub(super) fn scheme(&self) -> &'static str {
+        self.scheme
+    }
+
+    pub(super) fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    /// Open a connection, offering `alpn` if it is secured. `timeout` bounds
+    /// connecting and each read.
+    pub(super) fn connect(&self, alpn: Alpn, timeout: Option<Duration>) -> io::Result<Conn> {
+        let tcp = match &self.endpoint {
+            Endpoint::Tcp(resolver) => resolver.connect(timeout)?,
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let unix = UnixStream::connect(path)?;
+                unix.set_read_timeout(timeout)?;
+                return Ok(Conn::Unix(unix));
+            }
+        };
+        tcp.set_nodelay(true)?;
+        tcp.set_read_timeout(timeout)?;
+        match &self.tls {
+            Some(tls) => Ok(Conn::Tls(Box::new(tls.connect(tcp, alpn)?))),
+            None => Ok(Conn::Tcp(tcp)),
+        }
+    }
+
+    /// Open a connection for the HTTP/2 client.
+    pub(super) async fn connect_h2(&self) -> io::Result<Box<dyn AsyncConn>> {
+        let resolver = match &self.endpoint {
+            Endpoint::Tcp(resolver) => resolver.clone(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+            }
+        };
+        // Racing the addresses blocks
+        let tcp = tokio::task::spawn_blocking(move || resolver.connect(None))
+            .await
+            .map_err(io::Error::other)??;
+        tcp.set_nodelay(true)?;
+        tcp.set_nonblocking(true)?;
+        let tcp = tokio::net::TcpStream::from_std(tcp)?;
+        let Some(tls) = &self.tls else {
+            return Ok(Box::new(tcp));
+        };
+        let tls = tls.connect_async(tcp, Alpn::H2).await?;
+        if !tls.negotiated_h2() {
+            return Err(io::Error::other("server didn't agree to HTTP/2 in ALPN"));
+        }
+        Ok(Box::new(tls))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+/// What the HTTP/2 client runs over.
+pub(super) trait AsyncConn: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncConn for T {}
+
+// =============================================================================
+// Connection
+// =============================================================================
+
+/// A connection to the local server.
+pub(in super::super) enum Conn {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Conn {
+    /// Whether the server picked HTTP/2 in ALPN.
+    pub(super) fn negotiated_h2(&self) -> bool {
+        match self {
+            Self::Tls(tls) => negotiated_h2(tls),
+            _ => false,
+        }
+    }
+
+    pub(in super::super) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            Self::Tls(tls) => tls.get_ref().set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.set_read_timeout(timeout),
+        }
+    }
+
+    /// A handle that closes the connection from another thread.
+    pub(in super::super) fn closer(&self) -> io::Result<Closer> {
+        match self {
+            Self::Tcp(tcp) => Ok(Closer::Tcp(tcp.try_clone()?)),
+            Self::Tls(tls) => Ok(Closer::Tcp(tls.get_ref().try_clone()?)),
+            #[cfg(unix)]
+            Self::Unix(unix) => Ok(Closer::Unix(unix.try_clone()?)),
+        }
+    }
+
+    /// Tell the server nothing more is coming.
+    pub(in super::super) fn shutdown_write(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.shutdown(Shutdown::Write),
+            Self::Tls(tls) => tls.shutdown(),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.shutdown(Shutdown::Write),
+        }
+    }
+
+    pub(in super::super) fn shutdown(&self) {
+        let _ = match self {
+            Self::Tcp(tcp) => tcp.shutdown(Shutdown::Both),
+            Self::Tls(tls) => tls.get_ref().shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(tcp) => tcp.read(buf),
+            Self::Tls(tls) => tls.read(buf),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(tcp) => tcp.write(buf),
+            Self::Tls(tls) => tls.write(buf),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.flush(),
+            Self::Tls(tls) => tls.flush(),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.flush(),
+        }
+    }
+}
+
+/// Closes a connection owned by another thread.
+pub(in super::super) enum Closer {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Closer {
+    pub(in super::super) fn close(&self) {
+        let _ = match self {
+            Self::Tcp(tcp) => tcp.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Self::Unix(unix) => unix.shutdown(Shutdown::Both),
+        };
+    }
+}
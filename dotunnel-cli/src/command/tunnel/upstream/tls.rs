@@ -0,0 +1,275 @@
+//! TLS to an https local server.
+//!
+//! Local certificates are mostly self-signed or issued by a development CA
+//! such as mkcert's, so the CA to trust can be given, or verification can be
+//! turned off. HTTP/2 is chosen with ALPN, as browsers do.
+
+use std::fmt;
+use std::fs;
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll, Waker};
+
+use anyhow::{bail, Context as _, Result};
+use native_tls::{Certificate, HandshakeError, MidHandshakeTlsStream, TlsConnector, TlsStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// TLS settings from the command line.
+#[derive(Debug, Default)]
+pub(in super::super) struct TlsOptions {
+    /// Name sent in SNI and checked against the certificate
+    pub(in super::super) server_name: Option<String>,
+    /// PEM file of CA certificates to trust, besides the system's
+    pub(in super::super) ca_file: Option<PathBuf>,
+    /// Accept any certificate
+    pub(in super::super) insecure: bool,
+}
+
+/// Protocols offered in ALPN.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Alpn {
+    Http1,
+    H2,
+    /// Either, for finding out which the server prefers
+    Any,
+}
+
+/// How connections to the local server are secured.
+pub(super) struct Tls {
+    server_name: String,
+    http1: TlsConnector,
+    h2: TlsConnector,
+    any: TlsConnector,
+}
+
+impl Tls {
+    pub(super) fn new(host: &str, options: &TlsOptions) -> Result<Self> {
+        let mut roots = Vec::new();
+        if let Some(path) = &options.ca_file {
+            let pem = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read CA file {}", path.display()))?;
+            for block in pem.split_inclusive("-----END CERTIFICATE-----") {
+                if block.contains("-----BEGIN CERTIFICATE-----") {
+                    roots.push(
+                        Certificate::from_pem(block.trim().as_bytes()).with_context(|| {
+                            format!("Invalid certificate in {}", path.display())
+                        })?,
+                    );
+                }
+            }
+            if roots.is_empty() {
+                bail!("No certificates found in {}", path.display());
+            }
+        }
+
+        let connector = |protocols: &[&str]| {
+            let mut builder = TlsConnector::builder();
+            for root in &roots {
+                builder.add_root_certificate(root.clone());
+            }
+            builder
+                .danger_accept_invalid_certs(options.insecure)
+                .danger_accept_invalid_hostnames(options.insecure)
+                .request_alpns(protocols)
+                .build()
+                .context("Failed to set up TLS")
+        };
+
+        Ok(Self {
+            server_name: options
+                .server_name
+                .clone()
+                .unwrap_or_else(|| host.to_string()),
+            http1: connector(&["http/1.1"])?,
+            h2: connector(&["h2"])?,
+            any: connector(&["h2", "http/1.1"])?,
+        })
+    }
+
+    fn connector(&self, alpn: Alpn) -> &TlsConnector {
+        match alpn {
+            Alpn::Http1 => &self.http1,
+            Alpn::H2 => &self.h2,
+            Alpn::Any => &self.any,
+        }
+    }
+
+    pub(super) fn connect(&self, tcp: TcpStream, alpn: Alpn) -> io::Result<TlsStream<TcpStream>> {
+        self.connector(alpn)
+            .connect(&self.server_name, tcp)
+            .map_err(|e| match e {
+                HandshakeError::Failure(e) => io::Error::other(e),
+                HandshakeError::WouldBlock(_) => io::Error::from(io::ErrorKind::WouldBlock),
+            })
+    }
+
+    /// Connect for the HTTP/2 client, which runs on tokio.
+    pub(super) async fn connect_async(
+        &self,
+        tcp: tokio::net::TcpStream,
+        alpn: Alpn,
+    ) -> io::Result<AsyncTls> {
+        let connector = self.connector(alpn);
+        let mut start = Some(Nonblocking { tcp, waker: None });
+        let mut pending: Option<MidHandshakeTlsStream<Nonblocking>> = None;
+        poll_fn(|cx| {
+            let result = match (start.take(), pending.take()) {
+                (Some(mut stream), _) => {
+                    stream.waker = Some(cx.waker().clone());
+                    connector.connect(&self.server_name, stream)
+                }
+                (None, Some(mut mid)) => {
+                    mid.get_mut().waker = Some(cx.waker().clone());
+                    mid.handshake()
+                }
+                (None, None) => unreachable!("TLS handshake polled after completion"),
+            };
+            match result {
+                Ok(tls) => Poll::Ready(Ok(AsyncTls(tls))),
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    pending = Some(mid);
+                    Poll::Pending
+                }
+                Err(HandshakeError::Failure(e)) => Poll::Ready(Err(io::Error::other(e))),
+            }
+        })
+        .await
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Whether the server picked HTTP/2 in ALPN.
+pub(super) fn negotiated_h2<S: Read + Write>(tls: &TlsStream<S>) -> bool {
+    matches!(tls.negotiated_alpn(), Ok(Some(protocol)) if protocol == b"h2")
+}
+
+// =============================================================================
+// Async Adapter
+// =============================================================================
+
+/// A TLS stream for the HTTP/2 client. native-tls is blocking, so it runs
+/// over a socket that reports `WouldBlock` and wakes the task once the
+/// socket is ready again.
+pub(super) struct AsyncTls(TlsStream<Nonblocking>);
+
+impl AsyncTls {
+    pub(super) fn negotiated_h2(&self) -> bool {
+        negotiated_h2(&self.0)
+    }
+
+    fn poll_io<T>(
+        &mut self,
+        cx: &Context<'_>,
+        io: impl FnOnce(&mut TlsStream<Nonblocking>) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        self.0.get_mut().waker = Some(cx.waker().clone());
+        match io(&mut self.0) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl AsyncRead for AsyncTls {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(
+            self.get_mut()
+                .poll_io(cx, |tls| tls.read(buf.initialize_unfilled()))
+        )?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncTls {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(cx, |tls| tls.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(cx, |tls| tls.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(cx, |tls| tls.shutdown())
+    }
+}
+
+/// A tokio socket behind blocking `Read` and `Write`.
+#[derive(Debug)]
+struct Nonblocking {
+    tcp: tokio::net::TcpStream,
+    /// The task to wake once the socket is ready
+    waker: Option<Waker>,
+}
+
+impl Nonblocking {
+    /// After a `WouldBlock`, register for readiness; true if the socket
+    /// turned ready meanwhile and the call should be retried.
+    fn retry(
+        &self,
+        poll_ready: fn(&tokio::net::TcpStream, &mut Context<'_>) -> Poll<io::Result<()>>,
+    ) -> io::Result<bool> {
+        let waker = self
+            .waker
+            .as_ref()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        match poll_ready(&self.tcp, &mut Context::from_waker(waker)) {
+            Poll::Ready(Ok(())) => Ok(true),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl Read for Nonblocking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.tcp.try_read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.retry(tokio::net::TcpStream::poll_read_ready)? {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for Nonblocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.tcp.try_write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.retry(tokio::net::TcpStream::poll_write_ready)? {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
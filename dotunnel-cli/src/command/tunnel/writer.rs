@@ -0,0 +1,759 @@
+//! Outbound write channel and its scheduler.
+//!
+//! Worker threads queue payloads through a [`PriorityWriter`]; the IO loop
+//! drains them into a [`Scheduler`] and writes whatever it yields next.
+//!
+//! Stream data is charged against a byte [`Budget`] until the IO loop has
+//! written it, so a local server faster than the tunnel is slowed down to
+//! the tunnel's pace instead of filling memory.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use dotunnel::transport::message::{HttpMessage, Payload};
+
+/// Bytes a flow may send per round, times its weight.
+const QUANTUM: usize = 16 * 1024;
+
+/// Rough envelope overhead, so empty messages still cost something.
+const ENVELOPE_OVERHEAD: usize = 64;
+
+/// Weight of an HTTP body stream.
+const BODY_WEIGHT: u32 = 1;
+
+/// Default weight of a WebSocket stream. Interactive traffic (HMR, live
+/// reload) is light but latency-sensitive, so it gets a larger share
+/// whenever it has something to send.
+pub(super) const DEFAULT_WEBSOCKET_WEIGHT: u32 = 4;
+
+/// How long after the last WebSocket frame body producers keep their chunks
+/// small. An open but quiet socket, such as an idle HMR connection, doesn't
+/// slow downloads down.
+const INTERACTIVE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Queued-but-unwritten bytes allowed per stream.
+const STREAM_BUDGET: usize = 1024 * 1024;
+
+/// Queued-but-unwritten bytes allowed across all streams.
+const GLOBAL_BUDGET: usize = 16 * 1024 * 1024;
+
+/// How long a producer may wait for budget before its stream is given up
+/// as overloaded.
+const OVERLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a write could not be queued.
+#[derive(Debug, thiserror::Error)]
+pub(super) enum WriteError {
+    #[error("Write channel closed")]
+    Closed,
+    #[error("Outbound queue stayed full for {}s", OVERLOAD_TIMEOUT.as_secs())]
+    Overload,
+}
+
+// =============================================================================
+// Priority Write Channel
+// =============================================================================
+
+/// Traffic classes for outbound messages.
+///
+/// Control and Meta are served strictly first. WebSocket and Body streams
+/// share the remaining bandwidth fairly, per stream, by weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum WritePriority {
+    /// Control messages (ping, pong, ack) — highest priority
+    Control,
+    /// Response headers and aborts — must not be delayed by body chunks
+    Meta,
+    /// WebSocket frames, scheduled per stream
+    WebSocket,
+    /// Response body chunks and end markers, scheduled per stream
+    Body,
+}
+
+/// A message tagged with priority for the write channel.
+///
+/// Payloads are queued unencoded: `msg_seq` is assigned when the IO loop
+/// actually writes them, so the relay sees sequence numbers in order.
+pub(super) struct PrioritizedMsg {
+    pub(super) priority: WritePriority,
+    pub(super) stream_id: u32,
+    pub(super) payload: Payload,
+}
+
+impl PrioritizedMsg {
+    /// Whether the message is charged against the byte budget. Control and
+    /// Meta are small and must never wait behind bulk data.
+    fn is_budgeted(&self) -> bool {
+        matches!(
+            self.priority,
+            WritePriority::WebSocket | WritePriority::Body
+        )
+    }
+
+    /// Approximate wire size, used as the scheduling cost.
+    fn cost(&self) -> usize {
+        let data = match &self.payload {
+            Payload::Http(HttpMessage::ResponseBodyChunk(chunk)) => chunk.data.len(),
+            Payload::Ws(frame) => frame.payload.len(),
+            _ => 0,
+        };
+        data + ENVELOPE_OVERHEAD
+    }
+
+    /// The budget this message holds until it has been written.
+    pub(super) fn charge(&self) -> Option<Charge> {
+        self.is_budgeted().then(|| Charge {
+            stream_id: self.stream_id,
+            cost: self.cost(),
+        })
+    }
+}
+
+/// Budgeted bytes held by a message the IO loop has taken but not yet
+/// finished writing.
+pub(super) struct Charge {
+    stream_id: u32,
+    cost: usize,
+}
+
+/// Sender handle for priority-tagged writes.
+#[derive(Clone)]
+pub(super) struct PriorityWriter {
+    tx: mpsc::Sender<PrioritizedMsg>,
+    budget: Arc<Budget>,
+    /// Recent WebSocket traffic, which body producers keep chunks small for
+    interactive: Arc<Interactive>,
+}
+
+impl PriorityWriter {
+    pub(super) fn new(tx: mpsc::Sender<PrioritizedMsg>) -> Self {
+        Self {
+            tx,
+            budget: Arc::new(Budget::default()),
+            interactive: Arc::new(Interactive::new()),
+        }
+    }
+
+    /// Note interactive traffic, such as a WebSocket frame from the client.
+    pub(super) fn mark_interactive(&self) {
+        self.interactive.mark();
+    }
+
+    /// Whether interactive traffic passed within [`INTERACTIVE_WINDOW`].
+    pub(super) fn has_interactive(&self) -> bool {
+        self.interactive.is_recent()
+    }
+
+    /// Queue a message, blocking while its stream is over budget.
+    fn send(
+        &self,
+        priority: WritePriority,
+        stream_id: u32,
+        payload: Payload,
+    ) -> Result<(), WriteError> {
+        let msg = PrioritizedMsg {
+            priority,
+            stream_id,
+            payload,
+        };
+        if msg.is_budgeted() {
+            self.budget.reserve(stream_id, msg.cost())?;
+        }
+        self.tx.send(msg).map_err(|mpsc::SendError(msg)| {
+            self.release(msg.charge());
+            WriteError::Closed
+        })
+    }
+
+    /// Return a message's bytes to the budget once it has been written.
+    pub(super) fn release(&self, charge: Option<Charge>) {
+        if let Some(charge) = charge {
+            self.budget.release(charge.stream_id, charge.cost);
+        }
+    }
+
+    /// Fail all current and future waits for budget.
+    pub(super) fn close(&self) {
+        self.budget.close();
+    }
+
+    /// Send a control message (highest priority).
+    pub(super) fn send_control(&self, payload: Payload) -> Result<(), WriteError> {
+        self.send(WritePriority::Control, 0, payload)
+    }
+
+    /// Send a response header or abort.
+    pub(super) fn send_meta(&self, stream_id: u32, payload: Payload) -> Result<(), WriteError> {
+        self.send(WritePriority::Meta, stream_id, payload)
+    }
+
+    /// Send a WebSocket frame.
+    pub(super) fn send_ws(&self, stream_id: u32, payload: Payload) -> Result<(), WriteError> {
+        self.mark_interactive();
+        self.send(WritePriority::WebSocket, stream_id, payload)
+    }
+
+    /// Send a response body chunk or end marker.
+    pub(super) fn send_body(&self, stream_id: u32, payload: Payload) -> Result<(), WriteError> {
+        self.send(WritePriority::Body, stream_id, payload)
+    }
+}
+
+/// When interactive traffic last passed, in milliseconds since `epoch`;
+/// 0 if it never has.
+struct Interactive {
+    epoch: Instant,
+    last: AtomicU64,
+}
+
+impl Interactive {
+    fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        // Offset by one so the first millisecond isn't taken for "never"
+        self.epoch.elapsed().as_millis() as u64 + 1
+    }
+
+    fn mark(&self) {
+        self.last.store(self.now(), Ordering::Relaxed);
+    }
+
+    fn is_recent(&self) -> bool {
+        let last = self.last.load(Ordering::Relaxed);
+        last != 0 && self.now() - last < INTERACTIVE_WINDOW.as_millis() as u64
+    }
+}
+
+// =============================================================================
+// Budget
+// =============================================================================
+
+#[derive(Default)]
+struct BudgetState {
+    total: usize,
+    per_stream: HashMap<u32, usize>,
+    closed: bool,
+}
+
+/// Byte accounting for queued stream data, shared by every producer of a
+/// session and its IO loop.
+struct Budget {
+    state: Mutex<BudgetState>,
+    freed: Condvar,
+    stream_limit: usize,
+    global_limit: usize,
+    overload_timeout: Duration,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new(STREAM_BUDGET, GLOBAL_BUDGET, OVERLOAD_TIMEOUT)
+    }
+}
+
+impl Budget {
+    fn new(stream_limit: usize, global_limit: usize, overload_timeout: Duration) -> Self {
+        Self {
+            state: Mutex::default(),
+            freed: Condvar::new(),
+            stream_limit,
+            global_limit,
+            overload_timeout,
+        }
+    }
+
+    /// Wait until `cost` more bytes fit for this stream.
+    ///
+    /// A stream with nothing queued is always let through, so a single
+    /// message larger than the budget still makes progress.
+    fn reserve(&self, stream_id: u32, cost: usize) -> Result<(), WriteError> {
+        let deadline = Instant::now() + self.overload_timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return Err(WriteError::Closed);
+            }
+
+            let queued = state.per_stream.get(&stream_id).copied().unwrap_or(0);
+            let fits = queued == 0
+                || (queued + cost <= self.stream_limit && state.total + cost <= self.global_limit);
+            if fits {
+                state.total += cost;
+                *state.per_stream.entry(stream_id).or_insert(0) += cost;
+                return Ok(());
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(WriteError::Overload);
+            }
+            state = self.freed.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    fn release(&self, stream_id: u32, cost: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.total = state.total.saturating_sub(cost);
+        if let Some(queued) = state.per_stream.get_mut(&stream_id) {
+            *queued = queued.saturating_sub(cost);
+            if *queued == 0 {
+                state.per_stream.remove(&stream_id);
+            }
+        }
+        drop(state);
+        self.freed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.freed.notify_all();
+    }
+}
+
+// =============================================================================
+// Scheduler
+// =============================================================================
+
+/// One stream's queue in the deficit round-robin.
+struct Flow {
+    queue: VecDeque<PrioritizedMsg>,
+    weight: u32,
+    /// Bytes this flow may still send in its current turn
+    deficit: usize,
+}
+
+/// Orders queued messages for writing.
+///
+/// Control and Meta are FIFO and always go first. Everything else is
+/// deficit round-robin (DRR) across streams: each turn a stream may send
+/// `QUANTUM * weight` bytes before the next stream gets its go, so one
+/// large download cannot hold back the others. Messages within a stream
+/// keep their order.
+pub(super) struct Scheduler {
+    control: VecDeque<PrioritizedMsg>,
+    meta: VecDeque<PrioritizedMsg>,
+    flows: HashMap<u32, Flow>,
+    /// Streams with queued messages, in round-robin order
+    active: VecDeque<u32>,
+    /// Whether the stream at the front of `active` has had its quantum
+    turn_started: bool,
+    websocket_weight: u32,
+}
+
+impl Scheduler {
+    pub(super) fn new(websocket_weight: u32) -> Self {
+        Self {
+            control: VecDeque::new(),
+            meta: VecDeque::new(),
+            flows: HashMap::new(),
+            active: VecDeque::new(),
+            turn_started: false,
+            websocket_weight: websocket_weight.max(1),
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.meta.is_empty() && self.active.is_empty()
+    }
+
+    pub(super) fn push(&mut self, msg: PrioritizedMsg) {
+        let weight = match msg.priority {
+            WritePriority::Control => return self.control.push_back(msg),
+            WritePriority::Meta => return self.meta.push_back(msg),
+            WritePriority::WebSocket => self.websocket_weight,
+            WritePriority::Body => BODY_WEIGHT,
+        };
+
+        let stream_id = msg.stream_id;
+        let flow = self.flows.entry(stream_id).or_insert_with(|| Flow {
+            queue: VecDeque::new(),
+            weight,
+            deficit: 0,
+        });
+        if flow.queue.is_empty() {
+            self.active.push_back(stream_id);
+        }
+        flow.queue.push_back(msg);
+    }
+
+    pub(super) fn pop(&mut self) -> Option<PrioritizedMsg> {
+        if let Some(msg) = self.control.pop_front() {
+            return Some(msg);
+        }
+        if let Some(msg) = self.meta.pop_front() {
+            return Some(msg);
+        }
+
+        loop {
+            let stream_id = *self.active.front()?;
+            let flow = self
+                .flows
+                .get_mut(&stream_id)
+                .expect("active stream has a flow");
+
+            if !self.turn_started {
+                flow.deficit += QUANTUM * flow.weight as usize;
+                self.turn_started = true;
+            }
+
+            let cost = flow.queue.front().map_or(0, PrioritizedMsg::cost);
+            if flow.deficit >= cost {
+                flow.deficit -= cost;
+                let msg = flow.queue.pop_front();
+                if flow.queue.is_empty() {
+                    // An idle stream doesn't bank credit
+                    self.flows.remove(&stream_id);
+                    self.active.pop_front();
+                    self.turn_started = false;
+                }
+                return msg;
+            }
+
+            // Turn over; the leftover deficit carries to the next round
+            self.active.rotate_left(1);
+            self.turn_started = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use dotunnel::transport::message::{
+        Control, HttpBodyChunk, HttpMessage, HttpResponseEnd, Payload, Ping,
+    };
+
+    use super::{
+        Budget, Interactive, PrioritizedMsg, PriorityWriter, Scheduler, WriteError, WritePriority,
+        ENVELOPE_OVERHEAD, INTERACTIVE_WINDOW, QUANTUM,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn ping() -> Payload {
+        Payload::Control(Control::Ping(Ping {
+            timestamp_ms: 0,
+            data: Bytes::new(),
+        }))
+    }
+
+    fn end() -> Payload {
+        Payload::Http(HttpMessage::ResponseEnd(HttpResponseEnd {
+            timestamp_ms: 0,
+        }))
+    }
+
+    fn chunk(len: usize) -> Payload {
+        numbered_chunk(len, 0)
+    }
+
+    fn numbered_chunk(len: usize, seq: u32) -> Payload {
+        Payload::Http(HttpMessage::ResponseBodyChunk(HttpBodyChunk {
+            timestamp_ms: 0,
+            data: Bytes::from(vec![0; len]),
+            seq,
+            is_last: false,
+        }))
+    }
+
+    /// Queue `count` messages costing `cost` bytes each on a stream.
+    fn queue(
+        scheduler: &mut Scheduler,
+        priority: WritePriority,
+        stream_id: u32,
+        cost: usize,
+        count: u32,
+    ) {
+        for seq in 0..count {
+            scheduler.push(PrioritizedMsg {
+                priority,
+                stream_id,
+                payload: numbered_chunk(cost - ENVELOPE_OVERHEAD, seq),
+            });
+        }
+    }
+
+    /// Stream IDs in the order the scheduler yields them.
+    fn drain(scheduler: &mut Scheduler) -> Vec<u32> {
+        std::iter::from_fn(|| scheduler.pop())
+            .map(|msg| msg.stream_id)
+            .collect()
+    }
+
+    /// A writer whose budget fits `stream_limit` bytes per stream.
+    fn writer(
+        stream_limit: usize,
+        overload_timeout: Duration,
+    ) -> (PriorityWriter, mpsc::Receiver<PrioritizedMsg>) {
+        let (tx, rx) = mpsc::channel();
+        let writer = PriorityWriter {
+            tx,
+            budget: Arc::new(Budget::new(stream_limit, usize::MAX, overload_timeout)),
+            interactive: Arc::new(Interactive::new()),
+        };
+        (writer, rx)
+    }
+
+    #[test]
+    fn classes_go_out_in_order() {
+        let (writer, rx) = writer(usize::MAX, TIMEOUT);
+        writer.send_body(1, end()).unwrap();
+        writer.send_ws(2, end()).unwrap();
+        writer.send_meta(3, end()).unwrap();
+        writer.send_control(ping()).unwrap();
+        writer.send_meta(4, end()).unwrap();
+
+        let mut scheduler = Scheduler::new(1);
+        for msg in rx.try_iter() {
+            scheduler.push(msg);
+        }
+        let order: Vec<_> = std::iter::from_fn(|| scheduler.pop())
+            .map(|msg| (msg.priority, msg.stream_id))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (WritePriority::Control, 0),
+                (WritePriority::Meta, 3),
+                (WritePriority::Meta, 4),
+                (WritePriority::Body, 1),
+                (WritePriority::WebSocket, 2),
+            ]
+        );
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn only_stream_data_is_charged() {
+        let (writer, rx) = writer(usize::MAX, TIMEOUT);
+        writer.send_control(ping()).unwrap();
+        writer.send_meta(1, end()).unwrap();
+        writer.send_body(1, chunk(100)).unwrap();
+
+        let charges: Vec<_> = rx.try_iter().map(|msg| msg.charge()).collect();
+        assert!(charges[0].is_none());
+        assert!(charges[1].is_none());
+        assert_eq!(charges[2].as_ref().unwrap().cost, 100 + ENVELOPE_OVERHEAD);
+    }
+
+    #[test]
+    fn stream_limit_blocks_until_overload() {
+        let budget = Budget::new(1000, usize::MAX, TIMEOUT);
+        budget.reserve(1, 600).unwrap();
+
+        let started = Instant::now();
+        assert!(matches!(budget.reserve(1, 600), Err(WriteError::Overload)));
+        assert!(started.elapsed() >= TIMEOUT);
+
+        // Other streams are unaffected
+        budget.reserve(2, 600).unwrap();
+    }
+
+    #[test]
+    fn idle_stream_always_gets_through() {
+        let budget = Budget::new(1000, 1000, TIMEOUT);
+        budget.reserve(1, 5000).unwrap();
+        budget.reserve(2, 5000).unwrap();
+    }
+
+    #[test]
+    fn global_limit_spans_streams() {
+        let budget = Budget::new(1000, 1500, TIMEOUT);
+        budget.reserve(1, 800).unwrap();
+        budget.reserve(2, 500).unwrap();
+
+        // Within stream 2's own limit, but not the global one
+        assert!(matches!(budget.reserve(2, 300), Err(WriteError::Overload)));
+
+        budget.release(1, 800);
+        budget.reserve(2, 300).unwrap();
+    }
+
+    #[test]
+    fn release_wakes_a_waiting_producer() {
+        let (writer, rx) = writer(1000, Duration::from_secs(10));
+        writer.send_body(1, chunk(600)).unwrap();
+
+        let producer = {
+            let writer = writer.clone();
+            thread::spawn(move || writer.send_body(1, chunk(600)))
+        };
+        thread::sleep(TIMEOUT);
+        // The IO loop has written the first chunk
+        let msg = rx.try_recv().unwrap();
+        writer.release(msg.charge());
+
+        producer.join().unwrap().unwrap();
+        let state = writer.budget.state.lock().unwrap();
+        assert_eq!(state.total, 600 + ENVELOPE_OVERHEAD);
+    }
+
+    #[test]
+    fn unwritten_data_overloads_the_producer() {
+        let (writer, _rx) = writer(1000, TIMEOUT);
+        writer.send_body(1, chunk(600)).unwrap();
+        assert!(matches!(
+            writer.send_body(1, chunk(600)),
+            Err(WriteError::Overload)
+        ));
+    }
+
+    #[test]
+    fn released_streams_are_forgotten() {
+        let budget = Budget::new(1000, 1000, TIMEOUT);
+        budget.reserve(1, 300).unwrap();
+        budget.reserve(1, 300).unwrap();
+        budget.release(1, 300);
+        budget.release(1, 300);
+
+        let state = budget.state.lock().unwrap();
+        assert_eq!(state.total, 0);
+        assert!(state.per_stream.is_empty());
+    }
+
+    #[test]
+    fn close_fails_waiting_producers() {
+        let budget = Arc::new(Budget::new(1000, usize::MAX, Duration::from_secs(10)));
+        budget.reserve(1, 600).unwrap();
+
+        let producer = {
+            let budget = budget.clone();
+            thread::spawn(move || budget.reserve(1, 600))
+        };
+        thread::sleep(TIMEOUT);
+        budget.close();
+        assert!(matches!(producer.join().unwrap(), Err(WriteError::Closed)));
+    }
+
+    #[test]
+    fn streams_take_turns() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, QUANTUM, 3);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 3);
+        queue(&mut scheduler, WritePriority::Body, 3, QUANTUM, 1);
+
+        assert_eq!(drain(&mut scheduler), [1, 2, 3, 1, 2, 1, 2]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn stream_keeps_its_order() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, 1000, 20);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 2);
+
+        let seqs: Vec<_> = std::iter::from_fn(|| scheduler.pop())
+            .filter(|msg| msg.stream_id == 1)
+            .map(|msg| match msg.payload {
+                Payload::Http(HttpMessage::ResponseBodyChunk(chunk)) => chunk.seq,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(seqs, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn small_messages_share_a_quantum() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, QUANTUM / 4, 8);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 2);
+
+        assert_eq!(drain(&mut scheduler), [1, 1, 1, 1, 2, 1, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn large_message_banks_deficit() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, 3 * QUANTUM, 1);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 3);
+
+        // Stream 1 saves up over three rounds, stream 2 goes meanwhile
+        assert_eq!(drain(&mut scheduler), [2, 2, 1, 2]);
+    }
+
+    #[test]
+    fn websocket_weight_scales_the_share() {
+        let mut scheduler = Scheduler::new(4);
+        queue(&mut scheduler, WritePriority::WebSocket, 1, QUANTUM, 8);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 3);
+
+        assert_eq!(drain(&mut scheduler), [1, 1, 1, 1, 2, 1, 1, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn websocket_weight_is_at_least_one() {
+        let mut scheduler = Scheduler::new(0);
+        queue(&mut scheduler, WritePriority::WebSocket, 1, QUANTUM, 2);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 2);
+
+        assert_eq!(drain(&mut scheduler), [1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn idle_stream_starts_over() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, QUANTUM / 4, 1);
+        assert_eq!(drain(&mut scheduler), [1]);
+
+        // The three quarters left unused are not carried over
+        queue(&mut scheduler, WritePriority::Body, 1, QUANTUM / 2, 3);
+        queue(&mut scheduler, WritePriority::Body, 2, QUANTUM, 1);
+        assert_eq!(drain(&mut scheduler), [1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn control_and_meta_cut_in() {
+        let mut scheduler = Scheduler::new(1);
+        queue(&mut scheduler, WritePriority::Body, 1, QUANTUM, 2);
+        assert_eq!(scheduler.pop().unwrap().stream_id, 1);
+
+        queue(&mut scheduler, WritePriority::Meta, 2, ENVELOPE_OVERHEAD, 1);
+        queue(
+            &mut scheduler,
+            WritePriority::Control,
+            0,
+            ENVELOPE_OVERHEAD,
+            1,
+        );
+        assert_eq!(drain(&mut scheduler), [0, 2, 1]);
+    }
+
+    #[test]
+    fn websocket_frames_mark_the_window() {
+        let (writer, _rx) = writer(usize::MAX, TIMEOUT);
+        assert!(!writer.has_interactive());
+        writer.send_body(1, end()).unwrap();
+        assert!(!writer.has_interactive());
+        writer.send_ws(2, end()).unwrap();
+        assert!(writer.has_interactive());
+    }
+
+    #[test]
+    fn interactive_window_expires() {
+        let interactive = Interactive {
+            epoch: Instant::now().checked_sub(2 * INTERACTIVE_WINDOW).unwrap(),
+            last: AtomicU64::new(0),
+        };
+        assert!(!interactive.is_recent());
+
+        // Marked at the epoch, two windows ago
+        interactive.last.store(1, Ordering::Relaxed);
+        assert!(!interactive.is_recent());
+
+        interactive.mark();
+        assert!(interactive.is_recent());
+    }
+}
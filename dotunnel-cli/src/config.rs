@@ -7,6 +7,11 @@ use crate::sys;
 const CONFIG_DIR: &str = "dotunnel";
 const CONFIG_FILE: &str = "config.toml";
 const CREDENTIALS_FILE: &str = "credentials.toml";
+const TUNNELS_DIR: &str = "tunnels";
+
+/// A status file not rewritten for this long belongs to a tunnel that is no
+/// longer running (it crashed or was killed before cleaning up).
+const TUNNEL_STATUS_TTL_SECS: u64 = 120;
 
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct Config {
@@ -32,6 +37,21 @@ pub struct ProfileCredentials {
     pub token: String,
 }
 
+/// Live state of a running tunnel, written by `dotunnel tunnel` and read by
+/// `dotunnel status`. One file per process.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TunnelStatus {
+    pub pid: u32,
+    pub profile: String,
+    pub tunnel_url: String,
+    pub local_addr: String,
+    pub connected: bool,
+    /// Smoothed round-trip time to the relay
+    pub rtt_ms: Option<u64>,
+    /// Unix time of the last write, in seconds
+    pub updated_at: u64,
+}
+
 impl Config {
     /// Get the config directory path
     pub fn dir() -> Option<PathBuf> {
@@ -141,3 +161,61 @@ impl Credentials {
         self.profiles.remove(name)
     }
 }
+
+impl TunnelStatus {
+    /// Get the directory holding tunnel status files
+    pub fn dir() -> Option<PathBuf> {
+        Config::dir().map(|dir| dir.join(TUNNELS_DIR))
+    }
+
+    /// Get the status file path for a process
+    pub fn path(pid: u32) -> Option<PathBuf> {
+        Self::dir().map(|dir| dir.join(format!("{}.toml", pid)))
+    }
+
+    /// Save status to disk, stamping `updated_at`
+    pub fn save(&mut self) -> Result<()> {
+        let dir = Self::dir().context("Could not determine tunnels directory")?;
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create tunnels directory {:?}", dir))?;
+
+        self.updated_at = unix_now();
+        let path = Self::path(self.pid).context("Could not determine tunnel status path")?;
+        let content = toml::to_string_pretty(self).context("Failed to serialize tunnel status")?;
+
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write tunnel status to {:?}", path))?;
+
+        Ok(())
+    }
+
+    /// Remove the status file from disk
+    pub fn remove(&self) {
+        if let Some(path) = Self::path(self.pid) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Load the status of every running tunnel, skipping stale files
+    pub fn list() -> Vec<Self> {
+        let Some(entries) = Self::dir().and_then(|dir| fs::read_dir(dir).ok()) else {
+            return vec![];
+        };
+
+        let now = unix_now();
+        let mut tunnels: Vec<Self> = entries
+            .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+            .filter_map(|content| toml::from_str::<Self>(&content).ok())
+            .filter(|status| now.saturating_sub(status.updated_at) <= TUNNEL_STATUS_TTL_SECS)
+            .collect();
+        tunnels.sort_by_key(|status| status.pid);
+        tunnels
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
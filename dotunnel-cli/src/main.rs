@@ -69,7 +69,15 @@ fn main() -> Result<()> {
             command::setup::execute(args)?;
         }
         command::Command::Tunnel(args) => {
-            command::tunnel::execute(args, &cli.profile)?;
+            if let Err(e) = command::tunnel::execute(args, &cli.profile) {
+                // Errors a retry can't fix get a distinct exit code, so
+                // supervisors can tell them apart from crashes.
+                if let Some(fatal) = e.downcast_ref::<command::tunnel::FatalError>() {
+                    eprintln!("Error: {:#}", e);
+                    std::process::exit(fatal.exit_code());
+                }
+                return Err(e);
+            }
         }
     }
 
@@ -4,6 +4,7 @@
 
 import { env } from "cloudflare:workers";
 import { validateCliToken } from "#app/auth/device-flow.ts";
+import { getAdminUser } from "#app/functions/bootstrap.ts";
 import {
   createTunnelForUser,
   getTunnelBySubdomain,
@@ -25,6 +26,10 @@ interface ConnectResponse {
   subdomain: string;
 }
 
+interface RestartResponse {
+  restarted: number;
+}
+
 interface ErrorResponse {
   error: string;
   code?: string;
@@ -45,7 +50,107 @@ interface ErrorResponse {
  * 2. CLI connects via WebSocket to the returned DO
  */
 export async function handleTunnelConnect(request: Request): Promise<Response> {
-  // Validate CLI token
+  const userId = await authenticate(request);
+  if (userId instanceof Response) {
+    return userId;
+  }
+
+  // Handle POST - create/select tunnel
+  if (request.method === "POST") {
+    return handleTunnelSelection(request, userId);
+  }
+
+  // Handle GET with WebSocket upgrade - connect to tunnel DO
+  if (
+    request.method === "GET" &&
+    request.headers.get("Upgrade") === "websocket"
+  ) {
+    return forwardToTunnelSession(request, userId, "/_cli/connect");
+  }
+
+  return Response.json(
+    {
+      error: "Method not allowed",
+      code: "method_not_allowed",
+    } satisfies ErrorResponse,
+    { status: 405 },
+  );
+}
+
+/**
+ * Handle the HTTP streaming fallback, for networks that break WebSocket
+ * upgrades.
+ *
+ * GET /_api/tunnel/stream (downstream: relay-to-CLI frames)
+ * POST /_api/tunnel/stream (upstream: CLI-to-relay frames)
+ *
+ * The GET response names its stream in `X-Dotunnel-Stream`; the CLI's POST
+ * sends the same header so the DO can pair the two.
+ */
+export async function handleTunnelStream(request: Request): Promise<Response> {
+  const userId = await authenticate(request);
+  if (userId instanceof Response) {
+    return userId;
+  }
+
+  if (request.method === "GET" || request.method === "POST") {
+    return forwardToTunnelSession(request, userId, "/_cli/stream");
+  }
+
+  return Response.json(
+    {
+      error: "Method not allowed",
+      code: "method_not_allowed",
+    } satisfies ErrorResponse,
+    { status: 405 },
+  );
+}
+
+/**
+ * Ask every connected CLI to drain and reconnect, ahead of a deploy.
+ *
+ * POST /_api/tunnel/restart (admin only)
+ *
+ * Workers restarts Durable Objects on deploy without warning, dropping
+ * in-flight streams; a deploy calls this first so CLIs open replacement
+ * connections while the old ones finish what they carry.
+ */
+export async function handleTunnelRestart(request: Request): Promise<Response> {
+  const userId = await authenticate(request);
+  if (userId instanceof Response) {
+    return userId;
+  }
+
+  const admin = await getAdminUser();
+  if (admin?.id !== userId) {
+    return Response.json(
+      {
+        error: "Only the admin can restart tunnels",
+        code: "forbidden",
+      } satisfies ErrorResponse,
+      { status: 403 },
+    );
+  }
+
+  const { results } = await env.DB.prepare(
+    `SELECT public_id FROM tunnels WHERE status = 'online'`,
+  ).all<{ public_id: string }>();
+
+  let restarted = 0;
+  for (const { public_id } of results) {
+    const stub = env.TUNNEL_SESSION.get(
+      env.TUNNEL_SESSION.idFromName(public_id),
+    );
+    restarted += await stub.restart();
+  }
+
+  return Response.json({ restarted } satisfies RestartResponse);
+}
+
+/**
+ * Validate the CLI token, returning the user ID or an error response.
+ */
+async function authenticate(request: Request): Promise<number | Response> {
   const authHeader = request.headers.get("Authorization");
   if (!authHeader?.startsWith("Bearer ")) {
     return Response.json(
@@ -68,27 +173,7 @@ export async function handleTunnelConnect(request: Request): Promise<Response> {
       { status: 401 },
     );
   }
-
-  // Handle POST - create/select tunnel
-  if (request.method === "POST") {
-    return handleTunnelSelection(request, session.id);
-  }
-
-  // Handle GET with WebSocket upgrade - connect to tunnel DO
-  if (
-    request.method === "GET" &&
-    request.headers.get("Upgrade") === "websocket"
-  ) {
-    return handleWebSocketConnect(request, session.id);
-  }
-
-  return Response.json(
-    {
-      error: "Method not allowed",
-      code: "method_not_allowed",
-    } satisfies ErrorResponse,
-    { status: 405 },
-  );
+  return session.id;
 }
 
 /**
@@ -168,11 +253,13 @@ async function handleTunnelSelection(
 }
 
 /**
- * Handle WebSocket connection to tunnel DO (GET with Upgrade).
+ * Forward a CLI connection request to the tunnel DO at `path`, after
+ * checking the user owns the tunnel.
  */
-async function handleWebSocketConnect(
+async function forwardToTunnelSession(
   request: Request,
   userId: number,
+  path: string,
 ): Promise<Response> {
   // Get tunnel ID from query param
   const url = new URL(request.url);
@@ -229,17 +316,15 @@ async function handleWebSocketConnect(
   const stub = env.TUNNEL_SESSION.get(doId);
 
   // Forward request with tunnel metadata headers
-  const doRequest = new Request(
-    new URL("/_cli/connect", request.url).toString(),
-    {
-      method: "GET",
-      headers: new Headers([
-        ...request.headers,
-        ["X-Tunnel-Id", tunnelId],
-        ["X-Tunnel-Url", tunnelUrl],
-      ]),
-    },
-  );
+  const doRequest = new Request(new URL(path, request.url).toString(), {
+    method: request.method,
+    headers: new Headers([
+      ...request.headers,
+      ["X-Tunnel-Id", tunnelId],
+      ["X-Tunnel-Url", tunnelUrl],
+    ]),
+    body: request.method === "POST" ? request.body : null,
+  });
 
   return stub.fetch(doRequest);
 }
@@ -13,6 +13,7 @@ import {
   type HttpMessage,
   type Payload,
   type AbortReason as WireAbortReason,
+  type GoAwayCode as WireGoAwayCode,
   type HttpVersion as WireHttpVersion,
   type WebSocketFrame as WireWebSocketFrame,
   type WebSocketOpcode as WireWebSocketOpcode,
@@ -28,9 +29,25 @@ export const MAX_CONCURRENT_STREAMS = 100;
 /** Request timeout in milliseconds */
 export const REQUEST_TIMEOUT_MS = 30_000;
 
-/** Module-level TextEncoder/TextDecoder singletons to avoid per-call allocation */
-const textEncoder = new TextEncoder();
-const textDecoder = new TextDecoder();
+/** How long a dropped CLI connection may be resumed before streams fail */
+export const RESUME_GRACE_MS = 15_000;
+
+/**
+ * How long a replaced CLI connection may finish its streams after GoAway.
+ * A little longer than the CLI's own drain deadline, so it normally closes
+ * first.
+ */
+export const DRAIN_TIMEOUT_MS = 35_000;
+
+/** Unacknowledged outbound bytes kept for replay on resume */
+export const MAX_REPLAY_BYTES = 16 * 1024 * 1024;
+
+/** Relay connections one CLI may open for a tunnel */
+export const MAX_CLI_CONNECTIONS = 8;
+
+/** Acknowledge after this many inbound envelopes, or after ACK_INTERVAL_MS */
+export const ACK_EVERY = 32;
+export const ACK_INTERVAL_MS = 200;
 
 // =============================================================================
 // Enums (tag unions mirroring the Rust enums)
@@ -55,6 +72,14 @@ export const AbortReason = {
   OVERLOAD: "Overload",
 } as const satisfies Record<string, AbortReason>;
 
+export type GoAwayCode = WireGoAwayCode["tag"];
+export const GoAwayCode = {
+  /** Another connection took over the lane; the CLI must not take it back */
+  REPLACED: "Replaced",
+  /** The relay is restarting; the CLI drains and connects again right away */
+  RESTART: "Restart",
+} as const satisfies Record<string, GoAwayCode>;
+
 export type WebSocketOpcode = WireWebSocketOpcode["tag"];
 export const WebSocketOpcode = {
   CONTINUATION: "Continuation",
@@ -100,7 +125,16 @@ export type DecodedHttpMessage =
   | { type: "requestEnd"; timestampMs: bigint }
   | { type: "requestAbort"; reason: AbortReason; detail: string }
   | { type: "responseInit"; data: DecodedHttpResponseInit }
+  | {
+      type: "responseInterim";
+      status: number;
+      headers: Array<{ name: string; value: Uint8Array }>;
+    }
   | { type: "responseBodyChunk"; data: DecodedHttpBodyChunk }
+  | {
+      type: "responseTrailers";
+      headers: Array<{ name: string; value: Uint8Array }>;
+    }
   | { type: "responseEnd"; timestampMs: bigint }
   | { type: "responseAbort"; reason: AbortReason; detail: string };
 
@@ -118,6 +152,8 @@ export interface DecodedHttpResponseInit {
   status: number;
   headers: Array<{ name: string; value: Uint8Array }>;
   hasBody: boolean;
+  /** Protocol the local server answered in; H1 from CLIs that predate it */
+  version: HttpVersion;
   contentLength: bigint;
 }
 
@@ -142,7 +178,14 @@ export type DecodedControl =
   | { type: "ping"; timestampMs: bigint; data: Uint8Array }
   | { type: "pong"; timestampMs: bigint; data: Uint8Array }
   | { type: "error"; timestampMs: bigint; code: number; message: string }
-  | { type: "goAway"; timestampMs: bigint; lastMsgSeq: number; reason: string };
+  | {
+      type: "goAway";
+      timestampMs: bigint;
+      lastMsgSeq: number;
+      reason: string;
+      code: GoAwayCode;
+    }
+  | { type: "ack"; timestampMs: bigint; lastMsgSeq: number };
 
 // =============================================================================
 // Encoding Functions
@@ -164,17 +207,52 @@ function encodeEnvelope(
   });
 }
 
+/**
+ * Header values are byte strings, one character per byte, so they cross the
+ * tunnel as the exact bytes received rather than as UTF-8.
+ */
 function encodeHeaders(headers: Headers): Header[] {
   return Array.from(headers.entries()).map(([name, value]) => ({
     name,
-    value: textEncoder.encode(value),
+    value: byteStringToBytes(value),
   }));
 }
 
+function byteStringToBytes(value: string): Uint8Array {
+  const bytes = new Uint8Array(value.length);
+  for (let i = 0; i < value.length; i++) {
+    bytes[i] = value.charCodeAt(i);
+  }
+  return bytes;
+}
+
+function bytesToByteString(bytes: Uint8Array): string {
+  let value = "";
+  for (const byte of bytes) {
+    value += String.fromCharCode(byte);
+  }
+  return value;
+}
+
 function wireAbortReason(reason: AbortReason): WireAbortReason {
   return { tag: reason, value: null } as WireAbortReason;
 }
 
+function wireHttpVersion(version: HttpVersion): WireHttpVersion {
+  return { tag: version, value: null } as WireHttpVersion;
+}
+
+/**
+ * The HTTP version a visitor's request arrived over. HTTP/3 shares
+ * HTTP/2's semantics, so it is reported as H2.
+ */
+export function httpVersionOf(request: Request): HttpVersion {
+  const protocol = request.cf?.httpProtocol;
+  return protocol === "HTTP/2" || protocol === "HTTP/3"
+    ? HttpVersion.H2
+    : HttpVersion.H1;
+}
+
 /**
  * Encode an HTTP request init message.
  * Sent from DO to CLI when a new HTTP request arrives.
@@ -186,6 +264,7 @@ export function encodeHttpRequestInit(
   request: {
     method: string;
     uri: string;
+    version: HttpVersion;
     headers: Headers;
     hasBody: boolean;
   },
@@ -198,7 +277,7 @@ export function encodeHttpRequestInit(
         timestamp_ms: timestampMs,
         method: request.method,
         uri: request.uri,
-        version: { tag: "H1", value: null },
+        version: wireHttpVersion(request.version),
         headers: encodeHeaders(request.headers),
         has_body: request.hasBody,
       },
@@ -286,6 +365,7 @@ export function encodeHttpResponseInit(
     status: number;
     headers: Headers;
     hasBody: boolean;
+    version?: HttpVersion;
     contentLength?: bigint;
   },
 ): Uint8Array {
@@ -298,6 +378,7 @@ export function encodeHttpResponseInit(
         status: response.status,
         headers: encodeHeaders(response.headers),
         has_body: response.hasBody,
+        version: wireHttpVersion(response.version ?? HttpVersion.H1),
         content_length: response.contentLength ?? 0n,
       },
     },
@@ -441,6 +522,7 @@ export function encodeControlError(
 export function encodeControlGoAway(
   connectionId: bigint,
   lastMsgSeq: number,
+  code: GoAwayCode,
   reason: string,
 ): Uint8Array {
   return encodeEnvelope(connectionId, 0, 0, (timestampMs) => ({
@@ -451,6 +533,26 @@ export function encodeControlGoAway(
         timestamp_ms: timestampMs,
         last_msg_seq: lastMsgSeq,
         reason,
+        code: { tag: code, value: null } as WireGoAwayCode,
+      },
+    },
+  }));
+}
+
+/**
+ * Encode a control ack message.
+ */
+export function encodeControlAck(
+  connectionId: bigint,
+  lastMsgSeq: number,
+): Uint8Array {
+  return encodeEnvelope(connectionId, 0, 0, (timestampMs) => ({
+    tag: "Control",
+    value: {
+      tag: "Ack",
+      value: {
+        timestamp_ms: timestampMs,
+        last_msg_seq: lastMsgSeq,
       },
     },
   }));
@@ -505,9 +607,9 @@ function decodeHttpMessage(http: HttpMessage): DecodedHttpMessage {
           timestampMs: init.timestamp_ms,
           method: init.method,
           uri: init.uri,
-          version: init.version.tag,
           headers: init.headers,
           hasBody: init.has_body,
+          version: init.version.tag,
         },
       };
     }
@@ -543,10 +645,17 @@ function decodeHttpMessage(http: HttpMessage): DecodedHttpMessage {
           status: init.status,
           headers: init.headers,
           hasBody: init.has_body,
+          version: init.version.tag,
           contentLength: init.content_length,
         },
       };
     }
+    case "ResponseInterim":
+      return {
+        type: "responseInterim",
+        status: http.value.status,
+        headers: http.value.headers,
+      };
     case "ResponseBodyChunk": {
       const chunk = http.value;
       return {
@@ -559,6 +668,11 @@ function decodeHttpMessage(http: HttpMessage): DecodedHttpMessage {
         },
       };
     }
+    case "ResponseTrailers":
+      return {
+        type: "responseTrailers",
+        headers: http.value.headers,
+      };
     case "ResponseEnd":
       return {
         type: "responseEnd",
@@ -615,6 +729,13 @@ function decodeControl(control: Control): DecodedControl {
         timestampMs: control.value.timestamp_ms,
         lastMsgSeq: control.value.last_msg_seq,
         reason: control.value.reason,
+        code: control.value.code.tag,
+      };
+    case "Ack":
+      return {
+        type: "ack",
+        timestampMs: control.value.timestamp_ms,
+        lastMsgSeq: control.value.last_msg_seq,
       };
     default:
       throw new Error(`Unsupported control message type: ${control.tag}`);
@@ -626,14 +747,15 @@ function decodeControl(control: Control): DecodedControl {
 // =============================================================================
 
 /**
- * Convert decoded headers to a standard Headers object.
+ * Convert decoded headers to a standard Headers object, keeping value bytes
+ * and repeated headers.
  */
 export function headersFromDecoded(
   decoded: Array<{ name: string; value: Uint8Array }>,
 ): Headers {
   const headers = new Headers();
   for (const { name, value } of decoded) {
-    headers.append(name, textDecoder.decode(value));
+    headers.append(name, bytesToByteString(value));
   }
   return headers;
 }
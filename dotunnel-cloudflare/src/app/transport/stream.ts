@@ -0,0 +1,169 @@
+/**
+ * HTTP streaming transport for CLI connections.
+ *
+ * Some networks break WebSocket upgrades, so the CLI can carry the same
+ * messages over plain HTTP instead: a long-lived GET response streams
+ * relay-to-CLI messages, and a chunked POST body streams the other way.
+ * Both are a sequence of frames,
+ *
+ *   [opcode: u8][length: u32 BE][payload]
+ *
+ * using WebSocket opcodes (1 = text, 2 = binary), one frame per message.
+ */
+
+/** Largest frame accepted, matching the WebSocket message limit */
+const MAX_FRAME_SIZE = 32 * 1024 * 1024;
+
+const OPCODE_TEXT = 1;
+const OPCODE_BINARY = 2;
+
+const textEncoder = new TextEncoder();
+const textDecoder = new TextDecoder();
+
+/**
+ * Encode one message as a stream frame.
+ */
+export function encodeStreamFrame(
+  message: ArrayBuffer | Uint8Array | string,
+): Uint8Array {
+  const text = typeof message === "string";
+  const payload = text
+    ? textEncoder.encode(message)
+    : message instanceof Uint8Array
+      ? message
+      : new Uint8Array(message);
+  const frame = new Uint8Array(5 + payload.byteLength);
+  const view = new DataView(frame.buffer);
+  view.setUint8(0, text ? OPCODE_TEXT : OPCODE_BINARY);
+  view.setUint32(1, payload.byteLength);
+  frame.set(payload, 5);
+  return frame;
+}
+
+/**
+ * Decode the frames of a request body as they arrive.
+ *
+ * Chunks are kept as they come and each frame is copied out of them once,
+ * so a large frame arriving in many small chunks costs linear time.
+ */
+export async function* readStreamFrames(
+  body: ReadableStream<Uint8Array>,
+): AsyncGenerator<ArrayBuffer | string> {
+  const reader = body.getReader();
+  // Received bytes not yet framed, oldest first
+  const chunks: Uint8Array[] = [];
+  let buffered = 0;
+  let header: { opcode: number; length: number } | null = null;
+
+  /** Remove the next `n` buffered bytes, joined into one array. */
+  const take = (n: number): Uint8Array => {
+    const out = new Uint8Array(n);
+    let offset = 0;
+    while (offset < n) {
+      const chunk = chunks[0];
+      const used = Math.min(chunk.byteLength, n - offset);
+      out.set(chunk.subarray(0, used), offset);
+      offset += used;
+      if (used === chunk.byteLength) {
+        chunks.shift();
+      } else {
+        chunks[0] = chunk.subarray(used);
+      }
+    }
+    buffered -= n;
+    return out;
+  };
+
+  try {
+    while (true) {
+      const { done, value } = await reader.read();
+      if (done) break;
+
+      chunks.push(value);
+      buffered += value.byteLength;
+
+      while (true) {
+        if (header === null) {
+          if (buffered < 5) break;
+          const view = new DataView(take(5).buffer);
+          header = { opcode: view.getUint8(0), length: view.getUint32(1) };
+          if (header.length > MAX_FRAME_SIZE) {
+            throw new Error(
+              `Frame of ${header.length} bytes exceeds the limit`,
+            );
+          }
+        }
+        if (buffered < header.length) break;
+
+        const payload = take(header.length);
+        const { opcode } = header;
+        header = null;
+
+        if (opcode === OPCODE_TEXT) {
+          yield textDecoder.decode(payload);
+        } else if (opcode === OPCODE_BINARY) {
+          yield payload.buffer;
+        } else {
+          throw new Error(`Unknown frame opcode ${opcode}`);
+        }
+      }
+    }
+  } finally {
+    reader.releaseLock();
+  }
+
+  if (header !== null || buffered > 0) {
+    throw new Error("Stream ended mid-frame");
+  }
+}
+
+/**
+ * The relay's end of an HTTP streaming connection.
+ *
+ * Stands in for the CLI WebSocket: `send` writes to the GET response and
+ * `close` ends it. Frames from the CLI arrive on a separate POST, which is
+ * paired with this stream by `id`.
+ */
+export class HttpCliStream {
+  readonly id = crypto.randomUUID();
+  readonly readable: ReadableStream<Uint8Array>;
+  readyState: number = WebSocket.OPEN;
+
+  #writer: WritableStreamDefaultWriter<Uint8Array>;
+  #onPeerClose: (code: number, reason: string) => void;
+
+  constructor(onPeerClose: (code: number, reason: string) => void) {
+    const { readable, writable } = new TransformStream<
+      Uint8Array,
+      Uint8Array
+    >();
+    this.readable = readable;
+    this.#writer = writable.getWriter();
+    this.#onPeerClose = onPeerClose;
+
+    // The CLI hung up on the GET
+    this.#writer.closed.catch(() => this.peerClosed(1006, "Stream aborted"));
+  }
+
+  send(message: ArrayBuffer | Uint8Array | string): void {
+    if (this.readyState !== WebSocket.OPEN) return;
+    this.#writer.write(encodeStreamFrame(message)).catch(() => {});
+  }
+
+  close(_code?: number, _reason?: string): void {
+    if (this.readyState !== WebSocket.OPEN) return;
+    this.readyState = WebSocket.CLOSED;
+    this.#writer.close().catch(() => {});
+  }
+
+  /**
+   * The CLI's side ended: its POST finished (cleanly or not) or it dropped
+   * the GET.
+   */
+  peerClosed(code: number, reason: string): void {
+    if (this.readyState !== WebSocket.OPEN) return;
+    this.readyState = WebSocket.CLOSED;
+    this.#writer.abort(reason).catch(() => {});
+    this.#onPeerClose(code, reason);
+  }
+}
@@ -1,24 +1,40 @@
 /**
  * TunnelSession Durable Object
  *
- * Manages a single tunnel's WebSocket connection from the CLI and proxies
- * HTTP requests and WebSocket connections through it.
+ * Manages a single tunnel's WebSocket connections from the CLI and proxies
+ * HTTP requests and WebSocket connections through them.
  *
  * Architecture:
- * - Single CLI WebSocket connection per tunnel (hibernatable)
+ * - Up to MAX_CLI_CONNECTIONS CLI WebSocket connections ("lanes") per
+ *   tunnel (hibernatable); each new stream is assigned to the least busy one
+ * - A lane may instead use HTTP streaming (a GET down, a POST up) where
+ *   networks break WebSocket upgrades
  * - Multiple concurrent client connections (HTTP and WebSocket)
  * - Request multiplexing via streamId
  * - Streaming body support for large payloads
+ * - Protocol upgrades other than WebSocket carried as a body pipe: the
+ *   request body one way, the response body the other
+ * - Session resumption: a lane that reconnects within RESUME_GRACE_MS picks
+ *   up where it left off, with unacknowledged envelopes replayed both ways
+ * - Failover: bodiless idempotent requests on a lost lane are re-sent on
+ *   another one
+ * - Draining: a lane's connection replaced by a new one, or asked to
+ *   reconnect ahead of a deploy, gets GoAway and keeps serving its
+ *   in-flight streams for up to DRAIN_TIMEOUT_MS
  */
 
 import { DurableObject } from "cloudflare:workers";
 import {
+  ACK_EVERY,
+  ACK_INTERVAL_MS,
   AbortReason,
   type DecodedControl,
   type DecodedEnvelope,
   type DecodedHttpMessage,
   type DecodedWebSocketFrame,
+  DRAIN_TIMEOUT_MS,
   decodeEnvelope,
+  encodeControlAck,
   encodeControlGoAway,
   encodeControlPong,
   encodeHttpBodyChunk,
@@ -26,15 +42,25 @@ import {
   encodeHttpRequestEnd,
   encodeHttpRequestInit,
   encodeWebSocketFrame,
+  GoAwayCode,
+  HttpVersion,
   headersFromDecoded,
+  httpVersionOf,
+  MAX_CLI_CONNECTIONS,
   MAX_CONCURRENT_STREAMS,
+  MAX_REPLAY_BYTES,
   REQUEST_TIMEOUT_MS,
+  RESUME_GRACE_MS,
   WebSocketOpcode,
 } from "#app/transport/protocol.ts";
+import { HttpCliStream, readStreamFrames } from "#app/transport/stream.ts";
 
 const textEncoder = new TextEncoder();
 const textDecoder = new TextDecoder();
 
+/** Methods safe to send again when the lane carrying them is lost */
+const RETRYABLE_METHODS = new Set(["GET", "HEAD", "OPTIONS"]);
+
 // =============================================================================
 // Types
 // =============================================================================
@@ -44,6 +70,9 @@ interface CliAttachment {
   type: "cli";
   tunnelPublicId: string;
   tunnelUrl: string;
+  lane: number;
+  /** Set once the connection has been replaced and is draining */
+  draining?: boolean;
 }
 
 /** Attachment stored with client WebSocket for hibernation recovery */
@@ -51,13 +80,56 @@ interface ClientWsAttachment {
   type: "client-ws";
   tunnelPublicId: string;
   streamId: number;
+  lane: number;
 }
 
 type WebSocketAttachment = CliAttachment | ClientWsAttachment;
 
+/** One CLI WebSocket connection and its sequencing state */
+interface CliLane {
+  index: number;
+  socket: WebSocket | HttpCliStream | null;
+  /** Changes whenever the lane starts a new session */
+  connectionId: bigint;
+  /** Last sequence number sent to the CLI */
+  lastSentSeq: number;
+  /** Last sequence number received from the CLI */
+  lastReceivedSeq: number;
+  /** Inbound envelopes not yet acknowledged, and the timer that will */
+  unackedInbound: number;
+  ackTimer: ReturnType<typeof setTimeout> | null;
+  /** Sent but unacknowledged envelopes, oldest first */
+  replayBuffer: Array<{ msgSeq: number; data: Uint8Array }>;
+  replayBytes: number;
+  /** Cleared once the replay buffer overflows */
+  resumable: boolean;
+  /** Running while a lost connection may still be resumed */
+  resumeTimer: ReturnType<typeof setTimeout> | null;
+  /** Running while a replaced connection finishes its streams */
+  drainTimer: ReturnType<typeof setTimeout> | null;
+}
+
+/** What is needed to send a request again on another lane */
+interface RetryableRequest {
+  method: string;
+  uri: string;
+  version: HttpVersion;
+  headers: Headers;
+}
+
+/** What the client's Response is built from */
+interface ResponseStart {
+  status: number;
+  headers: Headers;
+  hasBody: boolean;
+  /** 0 when the CLI doesn't know it */
+  contentLength: bigint;
+}
+
 /** State for a pending HTTP stream */
 interface PendingHttpStream {
   streamId: number;
+  lane: CliLane;
   resolve: (response: Response) => void;
   reject: (error: Error) => void;
   writer: WritableStreamDefaultWriter<Uint8Array>;
@@ -66,19 +138,32 @@ interface PendingHttpStream {
   responseStarted: boolean;
   responseStatus?: number;
   responseHeaders?: Headers;
+  /** Link headers from 103 Early Hints, for the final response */
+  earlyHints?: string[];
+  /**
+   * A gRPC response held back until it is known whether a body follows, so
+   * trailers arriving first can go out as a Trailers-Only response
+   */
+  heldResponse?: ResponseStart;
   timeoutId: ReturnType<typeof setTimeout>;
   msgSeq: number;
-  /** If set, this stream is waiting for WebSocket upgrade confirmation */
-  pendingWsUpgrade?: {
-    clientSocket: WebSocket;
-  };
+  /** Set for requests that may fail over to another lane */
+  retry?: RetryableRequest;
+  /**
+   * Set while a WebSocket handshake waits for the local server's; a 101
+   * switches the client, anything else goes to it as a normal response
+   */
+  pendingWsUpgrade?: boolean;
 }
 
 /** State for a client WebSocket stream */
 interface ClientWsStream {
   streamId: number;
+  lane: CliLane;
   socket: WebSocket;
   msgSeq: number;
+  /** A fragmented message from the local server, until its final frame */
+  fragments?: { opcode: WebSocketOpcode; parts: Uint8Array[] };
 }
 
 // =============================================================================
@@ -86,11 +171,14 @@ interface ClientWsStream {
 // =============================================================================
 
 export class TunnelSession extends DurableObject {
-  /** The CLI WebSocket connection (only one allowed per tunnel) */
-  #cliSocket: WebSocket | null = null;
+  /** CLI connections by lane index */
+  #lanes = new Map<number, CliLane>();
 
-  /** Connection ID for this session (changes on each CLI reconnect) */
-  #connectionId: bigint = 0n;
+  /**
+   * Replaced connections finishing their streams. Each keeps the state of
+   * the session it carried; its index has a new lane in `#lanes`.
+   */
+  #drainingLanes = new Set<CliLane>();
 
   /** Tunnel metadata */
   #tunnelPublicId: string | null = null;
@@ -102,12 +190,9 @@ export class TunnelSession extends DurableObject {
   /** Client WebSocket connections being proxied */
   #clientWsStreams = new Map<number, ClientWsStream>();
 
-  /** Stream ID counter (monotonically increasing) */
+  /** Stream ID counter (monotonically increasing, shared by all lanes) */
   #nextStreamId = 1;
 
-  /** Global message sequence counter */
-  #globalMsgSeq = 0;
-
   constructor(ctx: DurableObjectState, env: Env) {
     super(ctx, env);
 
@@ -125,14 +210,20 @@ export class TunnelSession extends DurableObject {
       if (!attachment) continue;
 
       if (attachment.type === "cli") {
-        this.#cliSocket = ws;
+        // A draining connection's streams didn't survive hibernation
+        if (attachment.draining) {
+          ws.close(1000, "Replaced by new connection");
+          continue;
+        }
+        const lane = this.#lane(attachment.lane ?? 0);
+        lane.socket = ws;
         this.#tunnelPublicId = attachment.tunnelPublicId;
         this.#tunnelUrl = attachment.tunnelUrl;
-        this.#connectionId = BigInt(Date.now());
       } else if (attachment.type === "client-ws") {
         // Restore client WebSocket stream
         this.#clientWsStreams.set(attachment.streamId, {
           streamId: attachment.streamId,
+          lane: this.#lane(attachment.lane ?? 0),
           socket: ws,
           msgSeq: 0,
         });
@@ -159,7 +250,14 @@ export class TunnelSession extends DurableObject {
 
     // CLI WebSocket connection
     if (url.pathname === "/_cli/connect" && upgrade === "websocket") {
-      return this.#handleCliConnect(request);
+      return this.#handleCliConnect(request, "websocket");
+    }
+
+    // CLI HTTP streaming connection: GET downstream, POST upstream
+    if (url.pathname === "/_cli/stream") {
+      return request.method === "POST"
+        ? this.#handleCliUpstream(request)
+        : this.#handleCliConnect(request, "http");
     }
 
     // Client WebSocket upgrade - tunnel to local server
@@ -176,9 +274,13 @@ export class TunnelSession extends DurableObject {
   // ===========================================================================
 
   /**
-   * Handle CLI WebSocket connection.
+   * Handle a CLI connection, over a WebSocket or the HTTP streaming
+   * fallback.
    */
-  async #handleCliConnect(request: Request): Promise<Response> {
+  async #handleCliConnect(
+    request: Request,
+    transport: "websocket" | "http",
+  ): Promise<Response> {
     const tunnelPublicId = request.headers.get("X-Tunnel-Id");
     const tunnelUrl = request.headers.get("X-Tunnel-Url");
 
@@ -187,63 +289,174 @@ export class TunnelSession extends DurableObject {
       return new Response("Missing tunnel metadata", { status: 400 });
     }
 
+    // CLIs that predate parallel connections only ever open lane 0
+    const index = Number(request.headers.get("X-Dotunnel-Lane") ?? "0");
+    if (!Number.isInteger(index) || index < 0 || index >= MAX_CLI_CONNECTIONS) {
+      return new Response("Invalid connection lane", { status: 400 });
+    }
+    let lane = this.#lane(index);
+
+    // Resume the lane if the CLI names it and we can still replay
+    // everything it missed
+    const resumeId = request.headers.get("X-Dotunnel-Resume");
+    const resumeFrom = Number(
+      request.headers.get("X-Dotunnel-Last-Msg-Seq") ?? "0",
+    );
+    const oldestBuffered =
+      lane.replayBuffer[0]?.msgSeq ?? lane.lastSentSeq + 1;
+    const resumed =
+      resumeId !== null &&
+      lane.resumable &&
+      resumeId === lane.connectionId.toString() &&
+      Number.isInteger(resumeFrom) &&
+      resumeFrom + 1 >= oldestBuffered;
+
     console.log("[TunnelSession] handleCliConnect", {
       tunnelPublicId,
       tunnelUrl,
+      lane: index,
+      resumed,
     });
 
-    // Close existing CLI connection if any (replaced by new connection)
-    if (this.#cliSocket && this.#cliSocket.readyState === WebSocket.OPEN) {
-      // Send GoAway to old connection
-      const goAway = encodeControlGoAway(
-        this.#connectionId,
-        this.#globalMsgSeq,
-        "Replaced by new connection",
+    // A CLI moving a live connection to another transport only wants the
+    // lane if its session comes along; otherwise it keeps the one it has
+    if (!resumed && request.headers.get("X-Dotunnel-Switch") !== null) {
+      return Response.json(
+        { error: "Session cannot be resumed", code: "resume_failed" },
+        { status: 409 },
       );
-      this.#cliSocket.send(goAway);
-      this.#cliSocket.close(1000, "Replaced by new connection");
     }
 
-    // Fail all pending requests from old connection
-    this.#failAllPendingStreams("CLI reconnected");
+    if (lane.resumeTimer) {
+      clearTimeout(lane.resumeTimer);
+      lane.resumeTimer = null;
+    }
 
-    // Create WebSocket pair
-    const pair = new WebSocketPair();
-    const [client, server] = Object.values(pair);
+    if (resumed) {
+      // The old socket, if the DO hasn't noticed it die yet, is stale
+      if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+        lane.socket.close(1001, "Session resumed elsewhere");
+      }
+    } else if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+      // The old connection finishes what it has; the new one starts afresh
+      this.#drainLane(
+        lane,
+        GoAwayCode.REPLACED,
+        "Replaced by new connection",
+      );
+      lane = this.#lane(index);
+    } else {
+      // The previous session was lost and is not coming back
+      this.#closeClientWebSockets(lane);
+      this.#failPendingStreams(lane, "CLI reconnected");
+    }
 
-    // Accept with hibernation support
-    const attachment: CliAttachment = {
-      type: "cli",
-      tunnelPublicId,
-      tunnelUrl,
-    };
-    this.ctx.acceptWebSocket(server);
-    server.serializeAttachment(attachment);
+    let socket: WebSocket | HttpCliStream;
+    let client: WebSocket | null = null;
+    if (transport === "websocket") {
+      // Create WebSocket pair
+      const pair = new WebSocketPair();
+      const [clientEnd, server] = Object.values(pair);
+      client = clientEnd;
+
+      // Accept with hibernation support
+      const attachment: CliAttachment = {
+        type: "cli",
+        tunnelPublicId,
+        tunnelUrl,
+        lane: index,
+      };
+      this.ctx.acceptWebSocket(server);
+      server.serializeAttachment(attachment);
+      socket = server;
+    } else {
+      // HTTP streams hold the DO awake, so there is nothing to restore
+      const stream: HttpCliStream = new HttpCliStream((code, reason) => {
+        if (lane.socket === stream) {
+          this.#handleCliDisconnect(lane, code, reason);
+        }
+      });
+      socket = stream;
+    }
 
-    this.#cliSocket = server;
+    lane.socket = socket;
     this.#tunnelPublicId = tunnelPublicId;
     this.#tunnelUrl = tunnelUrl;
-    this.#connectionId = BigInt(Date.now());
-    this.#nextStreamId = 1;
-    this.#globalMsgSeq = 0;
+
+    if (!resumed) {
+      lane.connectionId = this.#newConnectionId(index);
+      this.#resetSequencing(lane);
+    }
 
     // Send tunnel info to CLI as JSON (initial handshake)
-    server.send(
+    socket.send(
       JSON.stringify({
         type: "tunnel_ready",
-        connectionId: this.#connectionId.toString(),
+        connectionId: lane.connectionId.toString(),
         tunnelUrl,
+        resumed,
+        lastMsgSeq: lane.lastReceivedSeq,
       }),
     );
 
+    if (resumed) {
+      // Replay whatever the CLI missed, in order
+      for (const entry of lane.replayBuffer) {
+        if (entry.msgSeq > resumeFrom) {
+          socket.send(entry.data);
+        }
+      }
+    }
+
     // Mark tunnel as online in the database (fire-and-forget, don't block 101 response)
     this.#updateTunnelStatusInDb(tunnelPublicId, "online").catch((err) =>
       console.error("Failed to update tunnel status on connect:", err),
     );
 
+    if (socket instanceof HttpCliStream) {
+      return new Response(socket.readable, {
+        headers: {
+          "Content-Type": "application/octet-stream",
+          // Proxies that buffer or compress would stall the stream
+          "Cache-Control": "no-store, no-transform",
+          "X-Dotunnel-Stream": socket.id,
+        },
+      });
+    }
     return new Response(null, { status: 101, webSocket: client });
   }
 
+  /**
+   * Handle the upstream half of an HTTP streaming connection: the CLI's
+   * frames arrive as one long POST body.
+   */
+  async #handleCliUpstream(request: Request): Promise<Response> {
+    const streamId = request.headers.get("X-Dotunnel-Stream");
+    const lane = [...this.#lanes.values(), ...this.#drainingLanes].find(
+      (candidate) =>
+        candidate.socket instanceof HttpCliStream &&
+        candidate.socket.id === streamId,
+    );
+    const stream = lane?.socket;
+    if (!lane || !(stream instanceof HttpCliStream) || !request.body) {
+      return new Response("Unknown stream", { status: 404 });
+    }
+
+    try {
+      for await (const message of readStreamFrames(request.body)) {
+        // A replaced stream may still deliver a few frames; the lane
+        // only listens to its current one
+        if (lane.socket !== stream) break;
+        this.#handleCliMessage(lane, message);
+      }
+      stream.peerClosed(1000, "Stream closed");
+    } catch (error) {
+      console.log("[TunnelSession] upstream failed", { error });
+      stream.peerClosed(1006, "Stream aborted");
+    }
+    return new Response(null, { status: 204 });
+  }
+
   /**
    * Update tunnel status in the database.
    */
@@ -271,8 +484,9 @@ export class TunnelSession extends DurableObject {
    * Proxy an HTTP request to the CLI.
    */
   async #proxyHttpRequest(request: Request): Promise<Response> {
-    // Check if CLI is connected
-    if (!this.#cliSocket || this.#cliSocket.readyState !== WebSocket.OPEN) {
+    // Check if CLI is connected (or expected back shortly)
+    const lane = this.#pickLane();
+    if (!lane) {
       return new Response("Tunnel offline", { status: 502 });
     }
 
@@ -283,6 +497,12 @@ export class TunnelSession extends DurableObject {
 
     const streamId = this.#nextStreamId++;
     const url = new URL(request.url);
+    const init = {
+      method: request.method,
+      uri: url.pathname + url.search,
+      version: httpVersionOf(request),
+      headers: request.headers,
+    };
 
     // Create streaming response infrastructure
     const { readable, writable } = new TransformStream<Uint8Array>();
@@ -296,18 +516,16 @@ export class TunnelSession extends DurableObject {
           this.#pendingHttpStreams.delete(streamId);
           stream.writer.abort(new Error("Request timeout"));
           // Send abort to CLI
-          if (
-            this.#cliSocket &&
-            this.#cliSocket.readyState === WebSocket.OPEN
-          ) {
-            const abort = encodeHttpRequestAbort(
-              this.#connectionId,
-              streamId,
-              this.#globalMsgSeq++,
-              AbortReason.TIMEOUT,
-              "Request timeout",
+          if (this.#isLaneAvailable(stream.lane)) {
+            this.#sendToCli(stream.lane, (msgSeq) =>
+              encodeHttpRequestAbort(
+                stream.lane.connectionId,
+                streamId,
+                msgSeq,
+                AbortReason.TIMEOUT,
+                "Request timeout",
+              ),
             );
-            this.#cliSocket.send(abort);
           }
         }
         reject(new Error("Request timeout"));
@@ -315,6 +533,7 @@ export class TunnelSession extends DurableObject {
 
       const stream: PendingHttpStream = {
         streamId,
+        lane,
         resolve,
         reject,
         writer,
@@ -323,36 +542,31 @@ export class TunnelSession extends DurableObject {
         responseStarted: false,
         timeoutId,
         msgSeq: 0,
+        retry:
+          request.body === null && RETRYABLE_METHODS.has(request.method)
+            ? init
+            : undefined,
       };
 
       this.#pendingHttpStreams.set(streamId, stream);
     });
 
     // Send request init to CLI
-    const initMsg = encodeHttpRequestInit(
-      this.#connectionId,
-      streamId,
-      this.#globalMsgSeq++,
-      {
-        method: request.method,
-        uri: url.pathname + url.search,
-        headers: request.headers,
+    this.#sendToCli(lane, (msgSeq) =>
+      encodeHttpRequestInit(lane.connectionId, streamId, msgSeq, {
+        ...init,
         hasBody: request.body !== null,
-      },
+      }),
     );
-    this.#cliSocket.send(initMsg);
 
     // Stream request body if present
     if (request.body) {
-      this.#streamRequestBody(streamId, request.body);
+      this.#streamRequestBody(streamId, lane, request.body);
     } else {
       // No body - send request end immediately
-      const endMsg = encodeHttpRequestEnd(
-        this.#connectionId,
-        streamId,
-        this.#globalMsgSeq++,
+      this.#sendToCli(lane, (msgSeq) =>
+        encodeHttpRequestEnd(lane.connectionId, streamId, msgSeq),
       );
-      this.#cliSocket.send(endMsg);
     }
 
     return responsePromise;
@@ -363,6 +577,7 @@ export class TunnelSession extends DurableObject {
    */
   async #streamRequestBody(
     streamId: number,
+    lane: CliLane,
     body: ReadableStream<Uint8Array>,
   ): Promise<void> {
     const reader = body.getReader();
@@ -377,49 +592,48 @@ export class TunnelSession extends DurableObject {
           break;
         }
 
-        // Check if CLI is still connected
-        if (!this.#cliSocket || this.#cliSocket.readyState !== WebSocket.OPEN) {
+        // Check if CLI is still connected (or expected back shortly)
+        if (!this.#isLaneAvailable(lane)) {
           break;
         }
 
         if (done) {
           // Send request end
-          const endMsg = encodeHttpRequestEnd(
-            this.#connectionId,
-            streamId,
-            this.#globalMsgSeq++,
+          this.#sendToCli(lane, (msgSeq) =>
+            encodeHttpRequestEnd(lane.connectionId, streamId, msgSeq),
           );
-          this.#cliSocket.send(endMsg);
           break;
         }
 
         // Send body chunk
-        const chunkMsg = encodeHttpBodyChunk(
-          this.#connectionId,
-          streamId,
-          this.#globalMsgSeq++,
-          value,
-          seq++,
-          false,
-          true, // isRequest
+        const chunkSeq = seq++;
+        this.#sendToCli(lane, (msgSeq) =>
+          encodeHttpBodyChunk(
+            lane.connectionId,
+            streamId,
+            msgSeq,
+            value,
+            chunkSeq,
+            false,
+            true, // isRequest
+          ),
         );
-        this.#cliSocket.send(chunkMsg);
       }
     } catch (error) {
       // Send abort on error
       if (
-        this.#cliSocket &&
-        this.#cliSocket.readyState === WebSocket.OPEN &&
+        this.#isLaneAvailable(lane) &&
         this.#pendingHttpStreams.has(streamId)
       ) {
-        const abortMsg = encodeHttpRequestAbort(
-          this.#connectionId,
-          streamId,
-          this.#globalMsgSeq++,
-          AbortReason.CANCELLED,
-          error instanceof Error ? error.message : "Unknown error",
+        this.#sendToCli(lane, (msgSeq) =>
+          encodeHttpRequestAbort(
+            lane.connectionId,
+            streamId,
+            msgSeq,
+            AbortReason.CANCELLED,
+            error instanceof Error ? error.message : "Unknown error",
+          ),
         );
-        this.#cliSocket.send(abortMsg);
       }
     } finally {
       reader.releaseLock();
@@ -434,21 +648,24 @@ export class TunnelSession extends DurableObject {
    * Handle client WebSocket upgrade - tunnel to local server.
    *
    * Flow:
-   * 1. Create WebSocket pair for client (must be done synchronously)
-   * 2. Send upgrade request to CLI
-   * 3. Wait for CLI to respond with 101 (handled in handleHttpMessage)
-   * 4. Once CLI confirms upgrade, the connection is ready for bidirectional frames
+   * 1. Send the upgrade request to the CLI, which shakes hands with the
+   *    local server
+   * 2. Wait for the CLI to respond (handled in handleHttpMessage)
+   * 3. On 101, switch the client with the subprotocol the server chose, and
+   *    start passing frames both ways
    *
-   * If CLI responds with non-101, we close the client WebSocket with an error.
+   * Any other answer reaches the client as a normal response.
    */
   async #handleClientWebSocket(request: Request): Promise<Response> {
+    // Check if CLI is connected (or expected back shortly)
+    const lane = this.#pickLane();
+
     console.log("[TunnelSession] handleClientWebSocket", {
-      cliConnected: !!this.#cliSocket,
-      readyState: this.#cliSocket?.readyState,
+      cliConnected: lane !== null,
+      lane: lane?.index,
     });
 
-    // Check if CLI is connected
-    if (!this.#cliSocket || this.#cliSocket.readyState !== WebSocket.OPEN) {
+    if (!lane) {
       return new Response("Tunnel offline", { status: 502 });
     }
 
@@ -463,7 +680,68 @@ export class TunnelSession extends DurableObject {
     const streamId = this.#nextStreamId++;
     const url = new URL(request.url);
 
-    // Create WebSocket pair for the client
+    // The body stream carries the local server's answer if it refuses
+    const { readable, writable } = new TransformStream<Uint8Array>();
+    const writer = writable.getWriter();
+
+    const responsePromise = new Promise<Response>((resolve, reject) => {
+      const timeoutId = setTimeout(() => {
+        const stream = this.#pendingHttpStreams.get(streamId);
+        if (stream) {
+          this.#pendingHttpStreams.delete(streamId);
+          if (this.#isLaneAvailable(stream.lane)) {
+            this.#sendToCli(stream.lane, (msgSeq) =>
+              encodeHttpRequestAbort(
+                stream.lane.connectionId,
+                streamId,
+                msgSeq,
+                AbortReason.TIMEOUT,
+                "WebSocket upgrade timeout",
+              ),
+            );
+          }
+        }
+        reject(new Error("WebSocket upgrade timeout"));
+      }, REQUEST_TIMEOUT_MS);
+
+      this.#pendingHttpStreams.set(streamId, {
+        streamId,
+        lane,
+        resolve,
+        reject,
+        writer,
+        writable,
+        readable,
+        responseStarted: false,
+        timeoutId,
+        msgSeq: 0,
+        pendingWsUpgrade: true,
+      });
+    });
+
+    // Send WebSocket upgrade request to CLI (as HTTP request init with Upgrade header)
+    this.#sendToCli(lane, (msgSeq) =>
+      encodeHttpRequestInit(lane.connectionId, streamId, msgSeq, {
+        method: request.method,
+        uri: url.pathname + url.search,
+        version: HttpVersion.H1,
+        headers: request.headers, // Includes Upgrade: websocket
+        hasBody: false,
+      }),
+    );
+
+    return responsePromise;
+  }
+
+  /**
+   * Switch the client to WebSocket now that the local server has, passing
+   * on the 101's headers, the chosen subprotocol among them.
+   */
+  #acceptClientWebSocket(
+    lane: CliLane,
+    streamId: number,
+    headers: Headers,
+  ): Response {
     const pair = new WebSocketPair();
     const [client, server] = Object.values(pair);
 
@@ -473,59 +751,28 @@ export class TunnelSession extends DurableObject {
       // biome-ignore lint/style/noNonNullAssertion: checked earlier
       tunnelPublicId: this.#tunnelPublicId!,
       streamId,
+      lane: lane.index,
     };
     this.ctx.acceptWebSocket(server);
     server.serializeAttachment(attachment);
 
-    // Create a dummy stream infrastructure for tracking the upgrade
-    // We don't use readable/writable for WebSocket, but we need the pending stream
-    // to track the upgrade handshake
-    const { readable, writable } = new TransformStream<Uint8Array>();
-    const writer = writable.getWriter();
-
-    const timeoutId = setTimeout(() => {
-      const stream = this.#pendingHttpStreams.get(streamId);
-      if (stream) {
-        this.#pendingHttpStreams.delete(streamId);
-        // Close client WebSocket with error
-        if (server.readyState === WebSocket.OPEN) {
-          server.close(1011, "WebSocket upgrade timeout");
-        }
-      }
-    }, REQUEST_TIMEOUT_MS);
-
-    // Store as pending HTTP stream until CLI confirms upgrade
-    const pendingStream: PendingHttpStream = {
+    this.#clientWsStreams.set(streamId, {
       streamId,
-      resolve: () => {},
-      reject: () => {},
-      writer,
-      writable,
-      readable,
-      responseStarted: false,
-      timeoutId,
+      lane,
+      socket: server,
       msgSeq: 0,
-      pendingWsUpgrade: {
-        clientSocket: server,
-      },
-    };
-    this.#pendingHttpStreams.set(streamId, pendingStream);
-
-    // Send WebSocket upgrade request to CLI (as HTTP request init with Upgrade header)
-    const initMsg = encodeHttpRequestInit(
-      this.#connectionId,
-      streamId,
-      this.#globalMsgSeq++,
-      {
-        method: request.method,
-        uri: url.pathname + url.search,
-        headers: request.headers, // Includes Upgrade: websocket
-        hasBody: false,
-      },
-    );
-    this.#cliSocket.send(initMsg);
+    });
 
-    return new Response(null, { status: 101, webSocket: client });
+    // The handshake itself is between the client and the edge
+    for (const name of [
+      "connection",
+      "upgrade",
+      "sec-websocket-accept",
+      "sec-websocket-extensions",
+    ]) {
+      headers.delete(name);
+    }
+    return new Response(null, { status: 101, webSocket: client, headers });
   }
 
   // ===========================================================================
@@ -540,7 +787,9 @@ export class TunnelSession extends DurableObject {
     if (!attachment) return;
 
     if (attachment.type === "cli") {
-      this.#handleCliMessage(message);
+      const lane = this.#laneOf(ws, attachment.lane ?? 0);
+      if (!lane) return;
+      this.#handleCliMessage(lane, message);
     } else if (attachment.type === "client-ws") {
       this.#handleClientWsMessage(attachment.streamId, message);
     }
@@ -549,7 +798,7 @@ export class TunnelSession extends DurableObject {
   /**
    * Handle message from CLI.
    */
-  #handleCliMessage(message: ArrayBuffer | string): void {
+  #handleCliMessage(lane: CliLane, message: ArrayBuffer | string): void {
     // JSON control messages
     if (typeof message === "string") {
       try {
@@ -566,7 +815,8 @@ export class TunnelSession extends DurableObject {
     // Binary Cap'n Proto messages
     try {
       const envelope = decodeEnvelope(message);
-      this.#handleDecodedEnvelope(envelope);
+      if (!this.#acceptInbound(lane, envelope.msgSeq)) return;
+      this.#handleDecodedEnvelope(lane, envelope);
     } catch (error) {
       console.error("Failed to decode message from CLI:", error);
     }
@@ -575,18 +825,18 @@ export class TunnelSession extends DurableObject {
   /**
    * Handle decoded envelope from CLI.
    */
-  #handleDecodedEnvelope(envelope: DecodedEnvelope): void {
+  #handleDecodedEnvelope(lane: CliLane, envelope: DecodedEnvelope): void {
     const { streamId } = envelope;
 
     switch (envelope.type) {
       case "http":
-        this.#handleHttpMessage(streamId, envelope.http);
+        this.#handleHttpMessage(lane, streamId, envelope.http);
         break;
       case "ws":
-        this.#handleWebSocketFrame(streamId, envelope.ws);
+        this.#handleWebSocketFrame(lane, streamId, envelope.ws);
         break;
       case "control":
-        this.#handleControlMessage(envelope.control);
+        this.#handleControlMessage(lane, envelope.control);
         break;
     }
   }
@@ -594,62 +844,101 @@ export class TunnelSession extends DurableObject {
   /**
    * Handle HTTP response message from CLI.
    */
-  #handleHttpMessage(streamId: number, http: DecodedHttpMessage): void {
+  #handleHttpMessage(
+    lane: CliLane,
+    streamId: number,
+    http: DecodedHttpMessage,
+  ): void {
     const stream = this.#pendingHttpStreams.get(streamId);
-    if (!stream) return;
+    // A stream that failed over only listens to its new lane
+    if (!stream || stream.lane !== lane) return;
 
     switch (http.type) {
+      case "responseInterim": {
+        // Workers cannot send 1xx responses; the edge answers the client's
+        // Expect itself. Early hints survive as Link headers on the final
+        // response, which Cloudflare can turn back into a 103.
+        if (http.status === 103) {
+          const hints = headersFromDecoded(http.headers).get("link");
+          if (hints) {
+            stream.earlyHints = [...(stream.earlyHints ?? []), hints];
+          }
+        }
+        break;
+      }
+
       case "responseInit": {
         const headers = headersFromDecoded(http.data.headers);
+        if (stream.earlyHints && !headers.has("link")) {
+          headers.set("link", stream.earlyHints.join(", "));
+        }
         stream.responseStatus = http.data.status;
         stream.responseHeaders = headers;
         stream.responseStarted = true;
 
-        // Check if this is a WebSocket upgrade response
         if (stream.pendingWsUpgrade) {
-          clearTimeout(stream.timeoutId);
-
           if (http.data.status === 101) {
-            // CLI confirmed WebSocket upgrade - move to clientWsStreams
-            this.#clientWsStreams.set(streamId, {
-              streamId,
-              socket: stream.pendingWsUpgrade.clientSocket,
-              msgSeq: 0,
-            });
+            clearTimeout(stream.timeoutId);
             this.#pendingHttpStreams.delete(streamId);
+            stream.resolve(
+              this.#acceptClientWebSocket(lane, streamId, headers),
+            );
             console.log("WebSocket upgrade confirmed", { streamId });
-          } else {
-            // CLI rejected WebSocket upgrade - close client connection
-            console.log("WebSocket upgrade rejected", {
-              streamId,
-              status: http.data.status,
-            });
-            if (
-              stream.pendingWsUpgrade.clientSocket.readyState === WebSocket.OPEN
-            ) {
-              stream.pendingWsUpgrade.clientSocket.close(
-                1002,
-                `Upstream rejected: ${http.data.status}`,
-              );
-            }
-            this.#pendingHttpStreams.delete(streamId);
+            return;
           }
-          return;
+          console.log("WebSocket upgrade rejected", {
+            streamId,
+            status: http.data.status,
+            version: http.data.version,
+          });
         }
 
-        // Regular HTTP response - create and resolve the Response with streaming body
-        const response = new Response(
-          http.data.hasBody ? stream.readable : null,
-          {
-            status: http.data.status,
+        // Workers can switch a client to WebSocket only, so any other
+        // protocol is carried as a body pipe: the request body streams the
+        // client's bytes to the local server and this response streams the
+        // server's back, for as long as either side keeps it open
+        if (http.data.status === 101) {
+          clearTimeout(stream.timeoutId);
+          headers.delete("connection");
+          headers.delete("upgrade");
+          console.log("Protocol upgrade carried as a body pipe", { streamId });
+          this.#startResponse(stream, {
+            status: 200,
             headers,
-          },
-        );
-        stream.resolve(response);
+            hasBody: true,
+            contentLength: 0n,
+          });
+          return;
+        }
+
+        const held = {
+          status: http.data.status,
+          headers,
+          hasBody: http.data.hasBody,
+          contentLength: http.data.contentLength,
+        };
+        if (grpcKind(headers) === "grpc") {
+          stream.heldResponse = held;
+        } else {
+          this.#startResponse(stream, held);
+        }
         break;
       }
 
       case "responseBodyChunk": {
+        // Workers cannot send trailers on a streamed Response, and a
+        // native gRPC response missing its grpc-status trailer reads as a
+        // failed call. Only Trailers-Only responses get through.
+        if (stream.heldResponse) {
+          if (http.data.data.byteLength === 0) break;
+          this.#refuseResponse(
+            lane,
+            stream,
+            "gRPC response bodies not supported by the relay",
+            "gRPC responses with a body are not supported through the tunnel; use grpc-web",
+          );
+          return;
+        }
         if (stream.responseStarted) {
           stream.writer.write(http.data.data).catch(() => {
             // Writer closed - ignore
@@ -658,8 +947,44 @@ export class TunnelSession extends DurableObject {
         break;
       }
 
+      case "responseTrailers": {
+        const trailers = headersFromDecoded(http.headers);
+        const kind = stream.responseHeaders
+          ? grpcKind(stream.responseHeaders)
+          : null;
+        if (stream.heldResponse) {
+          // No body yet: gRPC's Trailers-Only form carries the status in
+          // the response headers
+          const { status, headers } = stream.heldResponse;
+          for (const [name, value] of trailers) {
+            headers.append(name, value);
+          }
+          this.#startResponse(stream, {
+            status,
+            headers,
+            hasBody: false,
+            contentLength: 0n,
+          });
+        } else if (kind === "grpc-web" && stream.responseStarted) {
+          stream.writer.write(encodeGrpcWebTrailers(trailers)).catch(() => {
+            // Writer closed - ignore
+          });
+        } else {
+          // Workers cannot send trailers on a streamed Response. Only
+          // plain HTTP responses get here, where trailers are optional.
+          console.log("Dropping response trailers", {
+            streamId,
+            names: [...trailers.keys()],
+          });
+        }
+        break;
+      }
+
       case "responseEnd": {
         clearTimeout(stream.timeoutId);
+        if (stream.heldResponse) {
+          this.#startResponse(stream, stream.heldResponse);
+        }
         if (stream.responseStarted) {
           stream.writer.close().catch(() => {
             // Already closed - ignore
@@ -672,21 +997,7 @@ export class TunnelSession extends DurableObject {
       case "responseAbort": {
         clearTimeout(stream.timeoutId);
 
-        // Check if this is a WebSocket upgrade that failed
-        if (stream.pendingWsUpgrade) {
-          if (
-            stream.pendingWsUpgrade.clientSocket.readyState === WebSocket.OPEN
-          ) {
-            stream.pendingWsUpgrade.clientSocket.close(
-              1011,
-              http.detail || "WebSocket upgrade failed",
-            );
-          }
-          this.#pendingHttpStreams.delete(streamId);
-          return;
-        }
-
-        if (stream.responseStarted) {
+        if (stream.responseStarted && !stream.heldResponse) {
           stream.writer
             .abort(new Error(http.detail || "Response aborted"))
             .catch(() => {
@@ -701,33 +1012,109 @@ export class TunnelSession extends DurableObject {
     }
   }
 
+  /**
+   * Answer the client with 501 instead of the local server's response, and
+   * tell the CLI to stop sending it.
+   */
+  #refuseResponse(
+    lane: CliLane,
+    stream: PendingHttpStream,
+    detail: string,
+    message: string,
+  ): void {
+    const { streamId } = stream;
+    clearTimeout(stream.timeoutId);
+    this.#sendToCli(lane, (msgSeq) =>
+      encodeHttpRequestAbort(
+        lane.connectionId,
+        streamId,
+        msgSeq,
+        AbortReason.CANCELLED,
+        detail,
+      ),
+    );
+    this.#pendingHttpStreams.delete(streamId);
+    stream.heldResponse = undefined;
+    stream.resolve(new Response(message, { status: 501 }));
+  }
+
+  /**
+   * Resolve the client's Response, streaming the body that follows.
+   */
+  #startResponse(stream: PendingHttpStream, init: ResponseStart): void {
+    stream.heldResponse = undefined;
+    let body: ReadableStream<Uint8Array> | null = null;
+    // grpc-web trailers are appended to the body, past the declared length
+    const trailersInBody = grpcKind(init.headers) === "grpc-web";
+    if (trailersInBody) {
+      init.headers.delete("content-length");
+    }
+    if (init.hasBody && init.contentLength > 0n && !trailersInBody) {
+      // Sent with Content-Length, so clients can show progress; a body
+      // that comes up short errors instead of looking complete
+      const fixed = new FixedLengthStream(init.contentLength);
+      stream.readable.pipeTo(fixed.writable).catch(() => {
+        // Aborted upstream - the fixed stream errors with it
+      });
+      body = fixed.readable;
+    } else if (init.hasBody) {
+      body = stream.readable;
+    }
+    const response = new Response(body, {
+      status: init.status,
+      headers: init.headers,
+    });
+    stream.resolve(response);
+  }
+
   /**
    * Handle WebSocket frame from CLI (forward to client).
    */
-  #handleWebSocketFrame(streamId: number, frame: DecodedWebSocketFrame): void {
+  #handleWebSocketFrame(
+    lane: CliLane,
+    streamId: number,
+    frame: DecodedWebSocketFrame,
+  ): void {
     const clientStream = this.#clientWsStreams.get(streamId);
-    if (!clientStream) return;
+    if (!clientStream || clientStream.lane !== lane) return;
 
     const { socket } = clientStream;
     if (socket.readyState !== WebSocket.OPEN) return;
 
     switch (frame.opcode) {
       case WebSocketOpcode.TEXT:
-        socket.send(textDecoder.decode(frame.payload));
-        break;
       case WebSocketOpcode.BINARY:
-        socket.send(frame.payload);
+      case WebSocketOpcode.CONTINUATION: {
+        // Workers sends whole messages only, so fragments are put back
+        // together here
+        if (frame.opcode !== WebSocketOpcode.CONTINUATION) {
+          clientStream.fragments = { opcode: frame.opcode, parts: [] };
+        }
+        const { fragments } = clientStream;
+        if (!fragments) return;
+        fragments.parts.push(frame.payload);
+        if (!frame.fin) return;
+        clientStream.fragments = undefined;
+
+        const message = concatBytes(fragments.parts);
+        socket.send(
+          fragments.opcode === WebSocketOpcode.TEXT
+            ? textDecoder.decode(message)
+            : message,
+        );
         break;
+      }
       case WebSocketOpcode.CLOSE:
-        socket.close(frame.closeCode ?? 1000, "");
+        if (frame.closeCode === undefined) {
+          socket.close();
+        } else {
+          socket.close(frame.closeCode, textDecoder.decode(frame.payload));
+        }
         this.#clientWsStreams.delete(streamId);
         break;
       case WebSocketOpcode.PING:
-        // Auto-pong (WebSocket spec)
-        socket.send(frame.payload);
-        break;
       case WebSocketOpcode.PONG:
-        // Ignore pong
+        // The CLI answers the local server's pings itself
         break;
     }
   }
@@ -735,13 +1122,13 @@ export class TunnelSession extends DurableObject {
   /**
    * Handle control message from CLI.
    */
-  #handleControlMessage(control: DecodedControl): void {
+  #handleControlMessage(lane: CliLane, control: DecodedControl): void {
     switch (control.type) {
       case "ping": {
         // Respond with pong
-        if (this.#cliSocket && this.#cliSocket.readyState === WebSocket.OPEN) {
-          const pong = encodeControlPong(this.#connectionId, control.data);
-          this.#cliSocket.send(pong);
+        if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+          const pong = encodeControlPong(lane.connectionId, control.data);
+          lane.socket.send(pong);
         }
         break;
       }
@@ -755,9 +1142,15 @@ export class TunnelSession extends DurableObject {
         });
         break;
       case "goAway":
-        console.log("CLI going away", { reason: control.reason });
+        console.log("CLI going away", {
+          reason: control.reason,
+          code: control.code,
+        });
         // CLI is gracefully shutting down
         break;
+      case "ack":
+        this.#acknowledge(lane, control.lastMsgSeq);
+        break;
     }
   }
 
@@ -768,13 +1161,14 @@ export class TunnelSession extends DurableObject {
     streamId: number,
     message: ArrayBuffer | string,
   ): void {
-    if (!this.#cliSocket || this.#cliSocket.readyState !== WebSocket.OPEN) {
-      return;
-    }
-
     const clientStream = this.#clientWsStreams.get(streamId);
     if (!clientStream) return;
 
+    const { lane } = clientStream;
+    if (!this.#isLaneAvailable(lane)) {
+      return;
+    }
+
     // Encode and forward to CLI
     const opcode =
       typeof message === "string"
@@ -785,17 +1179,13 @@ export class TunnelSession extends DurableObject {
         ? textEncoder.encode(message)
         : new Uint8Array(message);
 
-    const frame = encodeWebSocketFrame(
-      this.#connectionId,
-      streamId,
-      this.#globalMsgSeq++,
-      {
+    this.#sendToCli(lane, (msgSeq) =>
+      encodeWebSocketFrame(lane.connectionId, streamId, msgSeq, {
         opcode,
         payload,
         fin: true,
-      },
+      }),
     );
-    this.#cliSocket.send(frame);
   }
 
   /**
@@ -811,7 +1201,9 @@ export class TunnelSession extends DurableObject {
     if (!attachment) return;
 
     if (attachment.type === "cli") {
-      this.#handleCliDisconnect(code, reason);
+      const lane = this.#laneOf(ws, attachment.lane ?? 0);
+      if (!lane) return;
+      this.#handleCliDisconnect(lane, code, reason);
     } else if (attachment.type === "client-ws") {
       this.#handleClientWsClose(attachment.streamId, code, reason);
     }
@@ -820,50 +1212,79 @@ export class TunnelSession extends DurableObject {
   /**
    * Handle CLI disconnect.
    */
-  #handleCliDisconnect(code: number, reason: string): void {
-    console.log("CLI disconnected", { code, reason });
+  #handleCliDisconnect(lane: CliLane, code: number, reason: string): void {
+    console.log("CLI disconnected", { lane: lane.index, code, reason });
+
+    lane.socket = null;
+
+    if (this.#drainingLanes.has(lane)) {
+      this.#endDrainedLane(lane, "CLI disconnected");
+      return;
+    }
+
+    // Anything but a clean close may be a network blip: hold the lane
+    // so the CLI can resume it, but don't make retryable requests wait
+    if (code !== 1000 && lane.resumable) {
+      this.#failOver(lane);
+      lane.resumeTimer = setTimeout(() => {
+        lane.resumeTimer = null;
+        this.#endLane(lane, "CLI disconnected");
+      }, RESUME_GRACE_MS);
+      return;
+    }
+
+    this.#endLane(lane, "CLI disconnected");
+  }
+
+  /**
+   * Tear down a lane that will not be resumed, and the session with it if
+   * it was the last one.
+   */
+  #endLane(lane: CliLane, reason: string): void {
+    if (lane.resumeTimer) {
+      clearTimeout(lane.resumeTimer);
+      lane.resumeTimer = null;
+    }
+    this.#lanes.delete(lane.index);
+
+    this.#failOver(lane);
+
+    // Fail the lane's pending HTTP requests
+    this.#failPendingStreams(lane, reason);
+
+    // Close the lane's client WebSockets
+    this.#closeClientWebSockets(lane);
+
+    this.#resetSequencing(lane);
 
     // Mark tunnel as offline in the database
-    if (this.#tunnelPublicId) {
+    if (this.#lanes.size === 0 && this.#tunnelPublicId) {
       this.#updateTunnelStatusInDb(this.#tunnelPublicId, "offline").catch(
         (err) =>
           console.error("Failed to update tunnel status on disconnect:", err),
       );
     }
-
-    this.#cliSocket = null;
-
-    // Fail all pending HTTP requests
-    this.#failAllPendingStreams("CLI disconnected");
-
-    // Close all client WebSockets
-    for (const [_streamId, clientStream] of this.#clientWsStreams) {
-      if (clientStream.socket.readyState === WebSocket.OPEN) {
-        clientStream.socket.close(1001, "Tunnel closed");
-      }
-    }
-    this.#clientWsStreams.clear();
   }
 
   /**
    * Handle client WebSocket close.
    */
-  #handleClientWsClose(streamId: number, code: number, _reason: string): void {
+  #handleClientWsClose(streamId: number, code: number, reason: string): void {
+    const clientStream = this.#clientWsStreams.get(streamId);
     this.#clientWsStreams.delete(streamId);
+    if (!clientStream) return;
 
     // Notify CLI that client WebSocket closed
-    if (this.#cliSocket && this.#cliSocket.readyState === WebSocket.OPEN) {
-      const closeFrame = encodeWebSocketFrame(
-        this.#connectionId,
-        streamId,
-        this.#globalMsgSeq++,
-        {
+    const { lane } = clientStream;
+    if (this.#isLaneAvailable(lane)) {
+      this.#sendToCli(lane, (msgSeq) =>
+        encodeWebSocketFrame(lane.connectionId, streamId, msgSeq, {
           opcode: WebSocketOpcode.CLOSE,
-          payload: new Uint8Array(0),
-          closeCode: code,
-        },
+          payload: textEncoder.encode(reason),
+          // 1005: the client closed without a code
+          closeCode: code === 1005 ? undefined : code,
+        }),
       );
-      this.#cliSocket.send(closeFrame);
     }
   }
 
@@ -875,22 +1296,398 @@ export class TunnelSession extends DurableObject {
     ws.close(1011, "Internal error");
   }
 
+  // ===========================================================================
+  // Lanes
+  // ===========================================================================
+
+  /**
+   * Get a lane's state, creating it on first use.
+   */
+  #lane(index: number): CliLane {
+    let lane = this.#lanes.get(index);
+    if (!lane) {
+      lane = {
+        index,
+        socket: null,
+        connectionId: this.#newConnectionId(index),
+        lastSentSeq: 0,
+        lastReceivedSeq: 0,
+        unackedInbound: 0,
+        ackTimer: null,
+        replayBuffer: [],
+        replayBytes: 0,
+        resumable: true,
+        resumeTimer: null,
+        drainTimer: null,
+      };
+      this.#lanes.set(index, lane);
+    }
+    return lane;
+  }
+
+  /**
+   * The lane a CLI socket belongs to: the current one for its index, or a
+   * replaced one still draining. A socket that is neither has nothing left
+   * to say.
+   */
+  #laneOf(socket: WebSocket, index: number): CliLane | undefined {
+    const lane = this.#lanes.get(index);
+    if (lane?.socket === socket) return lane;
+    for (const draining of this.#drainingLanes) {
+      if (draining.socket === socket) return draining;
+    }
+    return undefined;
+  }
+
+  /**
+   * Ask every connected CLI to drain and connect again, ahead of a deploy.
+   * Workers gives a Durable Object no warning before it is restarted, and
+   * a restart drops every connection with its streams in flight, so a
+   * deploy calls this first. Returns the number of connections asked.
+   */
+  async restart(): Promise<number> {
+    let drained = 0;
+    for (const lane of [...this.#lanes.values()]) {
+      if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+        this.#drainLane(lane, GoAwayCode.RESTART, "Relay restarting");
+        drained++;
+      }
+    }
+    console.log("[TunnelSession] restart", { drained });
+    return drained;
+  }
+
+  /**
+   * Send GoAway on a lane's connection and set it aside to drain: its
+   * in-flight streams run to completion, while new streams go to the
+   * lane's next connection. Whatever is left at the deadline fails.
+   */
+  #drainLane(lane: CliLane, code: GoAwayCode, reason: string): void {
+    const socket = lane.socket;
+    if (!socket) return;
+
+    this.#lanes.delete(lane.index);
+    this.#drainingLanes.add(lane);
+
+    socket.send(
+      encodeControlGoAway(lane.connectionId, lane.lastSentSeq, code, reason),
+    );
+    if (!(socket instanceof HttpCliStream)) {
+      const attachment = socket.deserializeAttachment() as CliAttachment;
+      socket.serializeAttachment({ ...attachment, draining: true });
+    }
+
+    lane.drainTimer = setTimeout(() => {
+      lane.drainTimer = null;
+      console.warn("Drain deadline reached", { lane: lane.index });
+      if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+        lane.socket.close(1000, reason);
+      }
+      lane.socket = null;
+      this.#endDrainedLane(lane, "CLI connection replaced");
+    }, DRAIN_TIMEOUT_MS);
+  }
+
+  /**
+   * Fail whatever a draining lane didn't finish.
+   */
+  #endDrainedLane(lane: CliLane, reason: string): void {
+    if (lane.drainTimer) {
+      clearTimeout(lane.drainTimer);
+      lane.drainTimer = null;
+    }
+    if (lane.ackTimer) {
+      clearTimeout(lane.ackTimer);
+      lane.ackTimer = null;
+    }
+    this.#drainingLanes.delete(lane);
+
+    this.#failOver(lane);
+    this.#failPendingStreams(lane, reason);
+    this.#closeClientWebSockets(lane);
+  }
+
+  /**
+   * A connection ID no other lane has, so the CLI can tell them apart.
+   */
+  #newConnectionId(index: number): bigint {
+    return BigInt(Date.now()) * BigInt(MAX_CLI_CONNECTIONS) + BigInt(index);
+  }
+
+  /**
+   * Choose the lane for a new stream: the connected one carrying the fewest
+   * streams, or failing that one expected back shortly.
+   */
+  #pickLane(exclude?: CliLane): CliLane | null {
+    let best: CliLane | null = null;
+    let bestScore = Number.POSITIVE_INFINITY;
+
+    for (const lane of this.#lanes.values()) {
+      if (lane === exclude || !this.#isLaneAvailable(lane)) continue;
+
+      // Any connected lane beats one that is only buffering
+      const connected = lane.socket?.readyState === WebSocket.OPEN;
+      const score =
+        this.#laneLoad(lane) + (connected ? 0 : MAX_CONCURRENT_STREAMS);
+      if (score < bestScore) {
+        best = lane;
+        bestScore = score;
+      }
+    }
+    return best;
+  }
+
+  /**
+   * Streams currently assigned to a lane.
+   */
+  #laneLoad(lane: CliLane): number {
+    let load = 0;
+    for (const stream of this.#pendingHttpStreams.values()) {
+      if (stream.lane === lane) load++;
+    }
+    for (const stream of this.#clientWsStreams.values()) {
+      if (stream.lane === lane) load++;
+    }
+    return load;
+  }
+
+  /**
+   * Re-send the lost lane's retryable requests on a connected lane.
+   *
+   * Only requests with no body and no response yet qualify. If the old
+   * lane is resumed after all, the abort queued here tells its CLI to drop
+   * the original.
+   */
+  #failOver(lane: CliLane): void {
+    for (const stream of this.#pendingHttpStreams.values()) {
+      if (stream.lane !== lane || !stream.retry || stream.responseStarted) {
+        continue;
+      }
+
+      const target = this.#pickLane(lane);
+      if (!target || target.socket?.readyState !== WebSocket.OPEN) return;
+
+      const { streamId, retry } = stream;
+      if (lane.resumeTimer || lane.socket) {
+        this.#sendToCli(lane, (msgSeq) =>
+          encodeHttpRequestAbort(
+            lane.connectionId,
+            streamId,
+            msgSeq,
+            AbortReason.CONNECTION_LOST,
+            "Failed over to another connection",
+          ),
+        );
+      }
+
+      console.log("Failing over stream", {
+        streamId,
+        from: lane.index,
+        to: target.index,
+      });
+      stream.lane = target;
+      this.#sendToCli(target, (msgSeq) =>
+        encodeHttpRequestInit(target.connectionId, streamId, msgSeq, {
+          ...retry,
+          hasBody: false,
+        }),
+      );
+      this.#sendToCli(target, (msgSeq) =>
+        encodeHttpRequestEnd(target.connectionId, streamId, msgSeq),
+      );
+    }
+  }
+
+  // ===========================================================================
+  // Session Sequencing
+  // ===========================================================================
+
+  /**
+   * Whether messages for a lane can be sent now or replayed on resume.
+   */
+  #isLaneAvailable(lane: CliLane): boolean {
+    return (
+      (lane.socket !== null && lane.socket.readyState === WebSocket.OPEN) ||
+      lane.resumeTimer !== null
+    );
+  }
+
+  /**
+   * Send a sequenced envelope on a lane, keeping it for replay until
+   * acknowledged. While the lane is away it is only buffered.
+   */
+  #sendToCli(lane: CliLane, encode: (msgSeq: number) => Uint8Array): void {
+    const msgSeq = ++lane.lastSentSeq;
+    const data = encode(msgSeq);
+
+    if (lane.resumable) {
+      lane.replayBuffer.push({ msgSeq, data });
+      lane.replayBytes += data.byteLength;
+      if (lane.replayBytes > MAX_REPLAY_BYTES) {
+        console.warn("Replay buffer exceeded, lane is no longer resumable", {
+          lane: lane.index,
+        });
+        lane.resumable = false;
+        lane.replayBuffer = [];
+        lane.replayBytes = 0;
+        if (lane.resumeTimer) {
+          // Nothing left to resume with
+          this.#endLane(lane, "CLI disconnected");
+          return;
+        }
+      }
+    }
+
+    if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+      lane.socket.send(data);
+    }
+  }
+
+  /**
+   * Record an inbound envelope. Returns false for a replayed duplicate.
+   */
+  #acceptInbound(lane: CliLane, msgSeq: number): boolean {
+    // Control messages are unsequenced
+    if (msgSeq === 0) return true;
+    if (msgSeq <= lane.lastReceivedSeq) return false;
+
+    lane.lastReceivedSeq = msgSeq;
+    lane.unackedInbound++;
+    if (lane.unackedInbound >= ACK_EVERY) {
+      this.#sendAck(lane);
+    } else if (!lane.ackTimer) {
+      lane.ackTimer = setTimeout(() => this.#sendAck(lane), ACK_INTERVAL_MS);
+    }
+    return true;
+  }
+
+  /**
+   * Acknowledge everything received on a lane so far.
+   */
+  #sendAck(lane: CliLane): void {
+    if (lane.ackTimer) {
+      clearTimeout(lane.ackTimer);
+      lane.ackTimer = null;
+    }
+    lane.unackedInbound = 0;
+    if (lane.socket && lane.socket.readyState === WebSocket.OPEN) {
+      lane.socket.send(
+        encodeControlAck(lane.connectionId, lane.lastReceivedSeq),
+      );
+    }
+  }
+
+  /**
+   * Drop everything the CLI has confirmed from a lane's replay buffer.
+   */
+  #acknowledge(lane: CliLane, lastMsgSeq: number): void {
+    let dropped = 0;
+    for (const entry of lane.replayBuffer) {
+      if (entry.msgSeq > lastMsgSeq) break;
+      lane.replayBytes -= entry.data.byteLength;
+      dropped++;
+    }
+    lane.replayBuffer.splice(0, dropped);
+  }
+
+  /**
+   * Start a lane's sequencing over for a new session.
+   */
+  #resetSequencing(lane: CliLane): void {
+    if (lane.ackTimer) {
+      clearTimeout(lane.ackTimer);
+      lane.ackTimer = null;
+    }
+    lane.lastSentSeq = 0;
+    lane.lastReceivedSeq = 0;
+    lane.unackedInbound = 0;
+    lane.replayBuffer = [];
+    lane.replayBytes = 0;
+    lane.resumable = true;
+  }
+
   // ===========================================================================
   // Utility Methods
   // ===========================================================================
 
   /**
-   * Fail all pending HTTP streams with an error.
+   * Close the client WebSockets carried by a lane.
    */
-  #failAllPendingStreams(reason: string): void {
-    for (const [, stream] of this.#pendingHttpStreams) {
+  #closeClientWebSockets(lane: CliLane): void {
+    for (const [streamId, clientStream] of this.#clientWsStreams) {
+      if (clientStream.lane !== lane) continue;
+      if (clientStream.socket.readyState === WebSocket.OPEN) {
+        clientStream.socket.close(1001, "Tunnel closed");
+      }
+      this.#clientWsStreams.delete(streamId);
+    }
+  }
+
+  /**
+   * Fail the HTTP streams carried by a lane with an error.
+   */
+  #failPendingStreams(lane: CliLane, reason: string): void {
+    for (const [streamId, stream] of this.#pendingHttpStreams) {
+      if (stream.lane !== lane) continue;
       clearTimeout(stream.timeoutId);
-      if (stream.responseStarted) {
+      if (stream.responseStarted && !stream.heldResponse) {
         stream.writer.abort(new Error(reason)).catch(() => {});
       } else {
         stream.reject(new Error(reason));
       }
+      this.#pendingHttpStreams.delete(streamId);
     }
-    this.#pendingHttpStreams.clear();
   }
 }
+
+// =============================================================================
+// gRPC Trailers
+// =============================================================================
+
+/**
+ * Which gRPC flavour a response is, going by its Content-Type.
+ */
+function grpcKind(headers: Headers): "grpc" | "grpc-web" | null {
+  const contentType = headers.get("content-type")?.toLowerCase() ?? "";
+  if (contentType.startsWith("application/grpc-web")) return "grpc-web";
+  if (contentType.startsWith("application/grpc")) return "grpc";
+  return null;
+}
+
+/**
+ * Encode trailers as a grpc-web trailer frame, which travels in the body
+ * where Workers can deliver it.
+ */
+function encodeGrpcWebTrailers(trailers: Headers): Uint8Array {
+  let block = "";
+  for (const [name, value] of trailers) {
+    block += `${name}: ${value}\r\n`;
+  }
+  const payload = textEncoder.encode(block);
+  const frame = new Uint8Array(5 + payload.byteLength);
+  const view = new DataView(frame.buffer);
+  view.setUint8(0, 0x80);
+  view.setUint32(1, payload.byteLength);
+  frame.set(payload, 5);
+  return frame;
+}
+
+// =============================================================================
+// WebSocket Fragments
+// =============================================================================
+
+/**
+ * Join a fragmented message's payloads into one.
+ */
+function concatBytes(parts: Uint8Array[]): Uint8Array {
+  if (parts.length === 1) return parts[0];
+  let length = 0;
+  for (const part of parts) length += part.byteLength;
+  const message = new Uint8Array(length);
+  let offset = 0;
+  for (const part of parts) {
+    message.set(part, offset);
+    offset += part.byteLength;
+  }
+  return message;
+}
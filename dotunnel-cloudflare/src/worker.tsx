@@ -6,7 +6,11 @@ import {
   handleDeviceTokenRequest,
   handleUserInfoRequest,
 } from "#app/api/device.ts";
-import { handleTunnelConnect } from "#app/api/tunnel.ts";
+import {
+  handleTunnelConnect,
+  handleTunnelRestart,
+  handleTunnelStream,
+} from "#app/api/tunnel.ts";
 import {
   handleCallback,
   handleLogin,
@@ -82,6 +86,17 @@ export default defineApp([
     get: ({ request }) => handleTunnelConnect(request),
   }),
 
+  // HTTP streaming fallback for networks that break WebSocket upgrades
+  route("/_api/tunnel/stream", {
+    post: ({ request }) => handleTunnelStream(request),
+    get: ({ request }) => handleTunnelStream(request),
+  }),
+
+  // Drain every CLI connection ahead of a deploy
+  route("/_api/tunnel/restart", {
+    post: ({ request }) => handleTunnelRestart(request),
+  }),
+
   // Page routes
   render(Document, [
     // Public routes
@@ -20,6 +20,13 @@ export const ArchivedAbortReason = r.taggedEnum({
 
 export type AbortReason = r.Infer<typeof ArchivedAbortReason>;
 
+export const ArchivedAck = r.struct({
+  timestamp_ms: r.u64,
+  last_msg_seq: r.u32,
+});
+
+export type Ack = r.Infer<typeof ArchivedAck>;
+
 export const ArchivedErrorReport = r.struct({
   timestamp_ms: r.u64,
   code: r.u32,
@@ -35,10 +42,18 @@ export const ArchivedFlowWindowUpdate = r.struct({
 
 export type FlowWindowUpdate = r.Infer<typeof ArchivedFlowWindowUpdate>;
 
+export const ArchivedGoAwayCode = r.taggedEnum({
+  Replaced: null,
+  Restart: null,
+});
+
+export type GoAwayCode = r.Infer<typeof ArchivedGoAwayCode>;
+
 export const ArchivedGoAway = r.struct({
   timestamp_ms: r.u64,
   last_msg_seq: r.u32,
   reason: r.string,
+  code: ArchivedGoAwayCode,
 });
 
 export type GoAway = r.Infer<typeof ArchivedGoAway>;
@@ -95,16 +110,6 @@ export const ArchivedHttpResponseEnd = r.struct({
 
 export type HttpResponseEnd = r.Infer<typeof ArchivedHttpResponseEnd>;
 
-export const ArchivedHttpResponseInit = r.struct({
-  timestamp_ms: r.u64,
-  status: r.u16,
-  headers: r.vec(ArchivedHeader),
-  has_body: r.bool,
-  content_length: r.u64,
-});
-
-export type HttpResponseInit = r.Infer<typeof ArchivedHttpResponseInit>;
-
 export const ArchivedHttpTrailers = r.struct({
   timestamp_ms: r.u64,
   headers: r.vec(ArchivedHeader),
@@ -130,6 +135,17 @@ export const ArchivedHttpRequestInit = r.struct({
 
 export type HttpRequestInit = r.Infer<typeof ArchivedHttpRequestInit>;
 
+export const ArchivedHttpResponseInit = r.struct({
+  timestamp_ms: r.u64,
+  status: r.u16,
+  headers: r.vec(ArchivedHeader),
+  has_body: r.bool,
+  version: ArchivedHttpVersion,
+  content_length: r.u64,
+});
+
+export type HttpResponseInit = r.Infer<typeof ArchivedHttpResponseInit>;
+
 export const ArchivedHttpMessage = r.taggedEnum({
   RequestInit: ArchivedHttpRequestInit,
   RequestBodyChunk: ArchivedHttpBodyChunk,
@@ -166,6 +182,7 @@ export const ArchivedControl = r.taggedEnum({
   FlowWindowUpdate: ArchivedFlowWindowUpdate,
   Error: ArchivedErrorReport,
   GoAway: ArchivedGoAway,
+  Ack: ArchivedAck,
 });
 
 export type Control = r.Infer<typeof ArchivedControl>;
@@ -5,7 +5,9 @@
 
 pub mod message;
 
+use bytes::{Bytes, BytesMut};
 use rkyv::rancor;
+use rkyv::ser::{Positional, Writer};
 use rkyv::util::AlignedVec;
 
 use message::Envelope;
@@ -27,10 +29,64 @@ impl Envelope {
     }
 }
 
+/// Envelope serializer that keeps its buffer between calls.
+///
+/// [`Envelope::encode`] grows a fresh aligned buffer for every envelope and
+/// then copies it into a `Vec`. An `Encoder` serializes straight into a
+/// shared block and hands each envelope out of it as [`Bytes`], without a
+/// copy; a block is reused once every envelope taken from it is dropped.
+pub struct Encoder {
+    buf: BytesMut,
+}
+
+/// Capacity of each block the encoder serializes into. Larger envelopes
+/// get a block of their own.
+const ENCODER_BLOCK_SIZE: usize = 64 * 1024;
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(ENCODER_BLOCK_SIZE),
+        }
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serialize an envelope into the current block and take it out as
+    /// wire bytes.
+    pub fn encode(&mut self, envelope: &Envelope) -> Result<Bytes, rancor::Error> {
+        self.buf.clear();
+        rkyv::api::high::to_bytes_in::<_, rancor::Error>(envelope, BlockWriter(&mut self.buf))?;
+        Ok(self.buf.split().freeze())
+    }
+}
+
+/// rkyv output into the encoder's block. Positions count from the start of
+/// the envelope, which is all rkyv's relative pointers need.
+struct BlockWriter<'a>(&'a mut BytesMut);
+
+impl Positional for BlockWriter<'_> {
+    fn pos(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<E> Writer<E> for BlockWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), E> {
+        self.0.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
 
+    use super::Encoder;
     use super::message::*;
 
     #[test]
@@ -97,6 +153,118 @@ mod tests {
         assert_eq!(frame.close_code, Some(1001));
     }
 
+    #[test]
+    fn encoder_matches_encode() {
+        let mut encoder = Encoder::new();
+        for seq in 0..3u32 {
+            let envelope = Envelope {
+                timestamp_ms: 1,
+                connection_id: 2,
+                stream_id: 3,
+                msg_seq: seq,
+                payload: Payload::Http(HttpMessage::ResponseBodyChunk(HttpBodyChunk {
+                    timestamp_ms: 1,
+                    data: Bytes::from(vec![seq as u8; 1024 >> seq]),
+                    seq,
+                    is_last: false,
+                })),
+            };
+
+            let bytes = encoder.encode(&envelope).unwrap();
+            assert_eq!(bytes, envelope.encode().unwrap());
+
+            let decoded = Envelope::decode(&bytes).unwrap();
+            assert_eq!(decoded.msg_seq, seq);
+            let Payload::Http(HttpMessage::ResponseBodyChunk(chunk)) = decoded.payload else {
+                panic!("unexpected payload");
+            };
+            assert_eq!(chunk.data.len(), 1024 >> seq);
+        }
+    }
+
+    #[test]
+    fn encoder_hands_out_envelopes_without_copying() {
+        let mut encoder = Encoder::new();
+        let envelope = |msg_seq| Envelope {
+            timestamp_ms: 1,
+            connection_id: 2,
+            stream_id: 3,
+            msg_seq,
+            payload: Payload::Http(HttpMessage::ResponseEnd(HttpResponseEnd {
+                timestamp_ms: 1,
+            })),
+        };
+
+        let first = encoder.encode(&envelope(1)).unwrap();
+        let second = encoder.encode(&envelope(2)).unwrap();
+
+        // Both are views of the block they were serialized into, back to
+        // back, not copies taken out of it
+        assert_eq!(second.as_ptr(), first.as_ptr().wrapping_add(first.len()));
+        assert_eq!(Envelope::decode(&first).unwrap().msg_seq, 1);
+        assert_eq!(Envelope::decode(&second).unwrap().msg_seq, 2);
+    }
+
+    #[test]
+    fn goaway_code_roundtrips_within_old_layout() {
+        // `code` sits in what used to be padding, so the archived struct
+        // must not grow
+        assert_eq!(std::mem::size_of::<ArchivedGoAway>(), 24);
+
+        let envelope = Envelope {
+            timestamp_ms: 1,
+            connection_id: 2,
+            stream_id: 0,
+            msg_seq: 0,
+            payload: Payload::Control(Control::GoAway(GoAway {
+                timestamp_ms: 1,
+                last_msg_seq: 9,
+                reason: "Relay restarting".to_string(),
+                code: GoAwayCode::Restart,
+            })),
+        };
+
+        let bytes = envelope.encode().unwrap();
+        let decoded = Envelope::decode(&bytes).unwrap();
+
+        let Payload::Control(Control::GoAway(go_away)) = decoded.payload else {
+            panic!("unexpected payload");
+        };
+        assert_eq!(go_away.code, GoAwayCode::Restart);
+        assert_eq!(go_away.last_msg_seq, 9);
+    }
+
+    #[test]
+    fn response_version_roundtrips_within_old_layout() {
+        // `version` sits in what used to be padding after `has_body`
+        assert_eq!(std::mem::size_of::<ArchivedHttpResponseInit>(), 32);
+
+        let envelope = Envelope {
+            timestamp_ms: 1,
+            connection_id: 2,
+            stream_id: 3,
+            msg_seq: 4,
+            payload: Payload::Http(HttpMessage::ResponseInit(HttpResponseInit {
+                timestamp_ms: 1,
+                status: 204,
+                headers: Vec::new(),
+                has_body: false,
+                version: HttpVersion::H2,
+                content_length: 0,
+            })),
+        };
+
+        let bytes = envelope.encode().unwrap();
+        let decoded = Envelope::decode(&bytes).unwrap();
+
+        let Payload::Http(HttpMessage::ResponseInit(init)) = decoded.payload else {
+            panic!("unexpected payload");
+        };
+        assert_eq!(init.version, HttpVersion::H2);
+        assert_eq!(init.status, 204);
+        assert!(!init.has_body);
+    }
+
     #[test]
     fn decode_rejects_garbage() {
         assert!(Envelope::decode(&[0xde, 0xad, 0xbe, 0xef]).is_err());
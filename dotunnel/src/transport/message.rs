@@ -15,6 +15,7 @@ pub struct Envelope {
     pub timestamp_ms: u64,
     pub connection_id: u64,
     pub stream_id: u32,
+    /// Per-session, per-direction sequence number; 0 = unsequenced (control)
     pub msg_seq: u32,
     pub payload: Payload,
 }
@@ -72,6 +73,9 @@ pub struct HttpResponseInit {
     pub status: u16,
     pub headers: Vec<Header>,
     pub has_body: bool,
+    /// Protocol the local server answered in. Sits in what was padding, so
+    /// peers that predate it send and read `H1`
+    pub version: HttpVersion,
     /// 0 = unknown
     pub content_length: u64,
 }
@@ -172,6 +176,7 @@ pub enum Control {
     FlowWindowUpdate(FlowWindowUpdate),
     Error(ErrorReport),
     GoAway(GoAway),
+    Ack(Ack),
 }
 
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
@@ -203,5 +208,27 @@ pub struct ErrorReport {
 pub struct GoAway {
     pub timestamp_ms: u64,
     pub last_msg_seq: u32,
+    /// For logs; `code` says what the peer should do
     pub reason: String,
+    /// Appended into what was padding, so peers that predate it send and
+    /// read `Replaced`
+    pub code: GoAwayCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub enum GoAwayCode {
+    /// Another connection took over the lane; don't take it back
+    Replaced,
+    /// The relay is restarting; drain, and connect again right away
+    Restart,
+}
+
+/// Cumulative acknowledgement of the peer's sequenced envelopes.
+///
+/// Everything up to and including `last_msg_seq` has arrived, so the peer
+/// can drop it from the buffer it replays when a session is resumed.
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+pub struct Ack {
+    pub timestamp_ms: u64,
+    pub last_msg_seq: u32,
 }